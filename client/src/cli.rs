@@ -0,0 +1,63 @@
+/*
+    Command line options of the client
+
+    The server is picked on the login view, either from the saved profiles (see `profiles.rs`)
+    or by typing its address, which `--host`, `--port` and `--tls` fill in beforehand.
+    Over TLS the server certificate is checked against `--ca`, or `--pin`, or else trusted on first use:
+    its fingerprint is remembered in `--known-servers` and must not change afterwards.
+*/
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use protocol::tls::ServerTrust;
+
+#[derive(Parser, Debug)]
+#[command(about = "Asynchronous chat client")]
+pub struct Cli {
+    /// Name or address of the server shown on the login view
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: String,
+
+    /// Port of the server shown on the login view
+    #[arg(long, default_value_t = 1632)]
+    pub port: u16,
+
+    /// Encrypt the connection with TLS by default
+    #[arg(long)]
+    pub tls: bool,
+
+    /// Only trust server certificates issued by the CAs in this PEM file
+    #[arg(long, conflicts_with = "pin")]
+    pub ca: Option<PathBuf>,
+
+    /// Only trust the server certificate with this SHA-256 fingerprint
+    #[arg(long)]
+    pub pin: Option<String>,
+
+    /// Where the certificates trusted on first use are remembered
+    #[arg(long, default_value = "known_servers")]
+    pub known_servers: PathBuf,
+
+    /// Seconds of silence after which the server is pinged; it is considered gone after 3 unanswered pings
+    #[arg(long, default_value_t = 15)]
+    pub heartbeat_interval: u64,
+
+    /// File holding the saved server profiles [default: profiles.toml in the user's config directory]
+    #[arg(long)]
+    pub profiles: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Which server certificates to trust when connecting with `tls`, or None for plain TCP
+    pub fn trust(&self, tls: bool) -> Option<ServerTrust> {
+        if !tls {
+            return None;
+        }
+        Some(match (&self.ca, &self.pin) {
+            (Some(ca), _) => ServerTrust::Ca(ca.clone()),
+            (None, Some(pin)) => ServerTrust::Pinned(pin.clone()),
+            (None, None) => ServerTrust::FirstUse(self.known_servers.clone()),
+        })
+    }
+}
@@ -0,0 +1,140 @@
+/*
+    Translates the text typed into the chat box into protocol frames
+
+    Lines starting with '/' are commands, e.g. "/join #general" or "/mute mallory 10m" (see `parse_command`),
+    anything else is a message in the 'recipient1, recipient2: message' format.
+*/
+
+use std::net::IpAddr;
+
+use protocol::{normalize_name, BanTarget, ClientFrame, Presence, Role, ROOM_PREFIX};
+
+/// Help shown when a command is not understood
+pub const COMMAND_HELP: &str = "Commands: /create #room, /join #room, /leave #room, /rooms, /members #room, \
+    /topic #room [text], /kick name [reason], /ban name [duration] [reason], /banip address [duration] [reason], \
+    /unban name|address, /mute name [duration], /unmute name, /role name user|moderator|admin, \
+    /receipts on|off, /online [status], /away [status], /busy [status], /nick newname (durations look like 30s, 10m, 2h or 7d)";
+
+/// Parses a line in the 'recipient1, recipient2: message' format into a message with the given id.
+/// Returns None if the line does not name any recipient.
+pub fn parse_message(line: &str, client_id: u64) -> Option<ClientFrame> {
+    let (dest, msg) = line.split_once(':')?;
+
+    let to: Vec<String> = dest
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if to.is_empty() {
+        return None;
+    }
+
+    Some(ClientFrame::Message {
+        client_id,
+        to,
+        msg: msg.trim().to_string(),
+    })
+}
+
+/// Parses a command line such as "/join #general" into a request for the server.
+/// Room names may be given with or without their '#'.
+/// Returns a message for the user if the command is unknown or misses its room, user or address.
+pub fn parse_command(line: &str) -> Result<ClientFrame, String> {
+    let line = line.trim().strip_prefix('/').unwrap_or(line);
+    let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let (arg, rest) = args.trim().split_once(char::is_whitespace).unwrap_or((args.trim(), ""));
+
+    let room = match arg {
+        "" => None,
+        room if room.starts_with(ROOM_PREFIX) => Some(room.to_string()),
+        room => Some(format!("{}{}", ROOM_PREFIX, room)),
+    };
+    let needs_room = || room.clone().ok_or_else(|| format!("/{} needs a room name. {}", command, COMMAND_HELP));
+    let needs_name = || match arg {
+        "" => Err(format!("/{} needs a user name. {}", command, COMMAND_HELP)),
+        name => Ok(name.to_string()),
+    };
+    let needs_ip = || {
+        arg.parse::<IpAddr>()
+            .map_err(|_| format!("/{} needs an IP address. {}", command, COMMAND_HELP))
+    };
+    // Optional text after the name, e.g. the reason of a kick
+    let text = |text: &str| Some(text.trim().to_string()).filter(|text| !text.is_empty());
+
+    match command {
+        "create" => Ok(ClientFrame::CreateRoom { room: needs_room()? }),
+        "join" => Ok(ClientFrame::JoinRoom { room: needs_room()? }),
+        "leave" => Ok(ClientFrame::LeaveRoom { room: needs_room()? }),
+        "rooms" => Ok(ClientFrame::RoomListRequest),
+        "members" => Ok(ClientFrame::RoomMembersRequest { room: needs_room()? }),
+        "topic" => {
+            // Without text the topic is cleared
+            let topic = Some(rest.trim().to_string()).filter(|topic| !topic.is_empty());
+            Ok(ClientFrame::SetTopic { room: needs_room()?, topic })
+        }
+        "kick" => Ok(ClientFrame::Kick { name: needs_name()?, reason: text(rest) }),
+        "ban" | "banip" => {
+            let target = match command {
+                "ban" => BanTarget::User(needs_name()?),
+                _ => BanTarget::Ip(needs_ip()?),
+            };
+            // The duration is optional, a reason may follow directly
+            let (first, reason) = rest.trim().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+            let (duration, reason) = match parse_duration(first) {
+                Some(duration) => (Some(duration), text(reason)),
+                None => (None, text(rest)),
+            };
+            Ok(ClientFrame::Ban { target, duration, reason })
+        }
+        "unban" => {
+            let target = match arg.parse::<IpAddr>() {
+                Ok(ip) => BanTarget::Ip(ip),
+                Err(_) => BanTarget::User(needs_name()?),
+            };
+            Ok(ClientFrame::Unban { target })
+        }
+        "mute" => {
+            let duration = match text(rest) {
+                Some(duration) => {
+                    Some(parse_duration(&duration).ok_or_else(|| format!("Invalid duration {}. {}", duration, COMMAND_HELP))?)
+                }
+                None => None,
+            };
+            Ok(ClientFrame::Mute { name: needs_name()?, duration })
+        }
+        "unmute" => Ok(ClientFrame::Unmute { name: needs_name()? }),
+        "role" => {
+            let role = match rest.trim() {
+                "user" => Role::User,
+                "moderator" => Role::Moderator,
+                "admin" => Role::Admin,
+                _ => return Err(format!("/role needs a user name and a role. {}", COMMAND_HELP)),
+            };
+            Ok(ClientFrame::SetRole { name: needs_name()?, role })
+        }
+        "receipts" => match arg {
+            "on" => Ok(ClientFrame::ReadReceipts { enabled: true }),
+            "off" => Ok(ClientFrame::ReadReceipts { enabled: false }),
+            _ => Err(format!("/receipts needs on or off. {}", COMMAND_HELP)),
+        },
+        // The status is the whole text after the command
+        "online" => Ok(ClientFrame::SetPresence { presence: Presence::Online, status: text(args) }),
+        "away" => Ok(ClientFrame::SetPresence { presence: Presence::Away, status: text(args) }),
+        "busy" => Ok(ClientFrame::SetPresence { presence: Presence::Busy, status: text(args) }),
+        "nick" => Ok(ClientFrame::Rename { name: normalize_name(&needs_name()?) }),
+        _ => Err(format!("Unknown command /{}. {}", command, COMMAND_HELP)),
+    }
+}
+
+/// Parses a duration such as "30s", "10m", "2h" or "7d" into seconds
+fn parse_duration(text: &str) -> Option<u64> {
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3_600,
+        'd' => 86_400,
+        _ => return None,
+    };
+    let count: u64 = text[..text.len() - 1].parse().ok()?;
+    count.checked_mul(unit)
+}
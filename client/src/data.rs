@@ -11,9 +11,29 @@
     Lens: This trait is used to define how to access and modify nested fields within a struct. Lenses provide a way to update nested data structures in an ergonomic and composable manner.
 */
 
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::{Duration, Instant}};
+
 use async_std::channel::Sender;
 use druid::{Data, Lens};
 use chrono::{DateTime, TimeZone, Utc};
+use protocol::{name_key, ClientFrame, Presence};
+
+use crate::profiles::ServerProfile;
+
+/// Number of older messages fetched at a time when scrolling up the chat history
+pub const HISTORY_PAGE: u32 = 50;
+
+/// The recipients are told again that the user is typing this often, while they keep typing
+pub const TYPING_RESEND: Duration = Duration::from_secs(3);
+
+/// The user stops typing after this long without a keystroke
+pub const TYPING_IDLE: Duration = Duration::from_secs(5);
+
+/// Someone else stops typing if they do not say they still are within this long
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// The user goes away after this long without touching the keyboard or the mouse
+pub const AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
 //use std::time::SystemTime;
 
 // Define a struct to represent the application state
@@ -21,36 +41,279 @@ use chrono::{DateTime, TimeZone, Utc};
 pub struct AppState {
     pub current_view: u32,                  // Unsigned integer for the view selection
 
+    pub connection: ConnectionState,        // Whether we are connected to the server, shown on the views
+    pub server_host: String,                // Address of the server typed on the login view
+    pub server_port: String,                // Port of the server typed on the login view
+    pub server_tls: bool,                   // Set to encrypt the connection to the typed server
+    pub profile_name: String,               // Name to save the typed server under
+    pub profiles: Arc<Vec<ServerProfile>>,  // Saved servers (see profiles.rs)
+
     pub logged_in: bool,                    // Bool value to check if the user is logged in or not
     pub user_alias: String,                 // Store the user's chosen username 
+    pub password: String,                   // Password typed on the login view (cleared once logged in)
+    pub register_mode: bool,                // Set to register a new account instead of logging in
+    pub login_error: String,                // Why the server refused the last login attempt (empty if none)
     pub new_user_message: String,
+    pub max_message_size: Option<usize>,    // Longest message the server accepts, in bytes (None if it did not say)
     pub new_socket_message: String,
+    pub next_client_id: u64,                // Id given to the next message this user sends
+    pub history_loading: bool,              // Set while older messages are being fetched from the server
+    pub history_complete: bool,             // Set once the server has no older messages to send
+    pub read_receipts: bool,                // Set to tell others when we read their messages, and see when they read ours
+    #[data(eq)]
+    pub presence: Presence,                 // Whether this user is around, as told to the others
+    pub status: Option<String>,             // Status text shown with our presence, e.g. "at lunch"
+    pub auto_away: bool,                    // Set if we went away on our own, after a while without input
 
     #[data(eq)]
     pub connected_users: Vec<ConnectedUsers>,    // Store a dynamic list of connected users 
 
     #[data(eq)]
     pub messages: Vec<Message>,             // Store all of the messages 
+
+    #[data(eq)]
+    pub rooms: Vec<String>,                 // Rooms this user is a member of
+
+    #[data(eq)]
+    pub typing: Vec<Typing>,                // Users writing to us, or to our rooms
     
     #[data(ignore)]
-    pub sender: Sender<String>,              // Store the channel sender to communicate between threads 
+    pub presences: HashMap<String, (Presence, Option<String>)>, // Users the server said are not simply online
+    #[data(ignore)]
+    pub read_up_to: HashMap<String, u64>,   // Newest message of each conversation we told the server we read
+    #[data(ignore)]
+    pub profiles_path: Option<PathBuf>,      // Where the saved servers are kept (None if there is no config dir)
     #[data(ignore)]
-    pub signal_sender: Sender<String>        // Store the channel signal_sender to communicate between threads 
+    pub connect_sender: Sender<ServerProfile>, // Store the channel connect_sender to start a connection
+    #[data(ignore)]
+    pub sender: Sender<ClientFrame>,         // Store the channel sender to communicate between threads 
+    #[data(ignore)]
+    pub signal_sender: Sender<ClientFrame>   // Store the channel signal_sender to communicate between threads 
 }
 
 
+/// State of the connection to the server
+#[derive(Clone, Copy, Debug, PartialEq, Data)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,                             // Connecting to the server picked on the login view
+    Connected,                              // The server accepted our handshake (and our login, after a reconnection)
+    Reconnecting(u32),                      // The connection dropped; counts the attempts to get it back
+}
+
+impl ConnectionState {
+    /// Describes the state for the connection indicator
+    pub fn describe(&self) -> String {
+        match self {
+            ConnectionState::Disconnected => "Disconnected".to_string(),
+            ConnectionState::Connecting => "Connecting...".to_string(),
+            ConnectionState::Connected => "Connected".to_string(),
+            ConnectionState::Reconnecting(attempt) => format!("Connection lost, reconnecting (attempt {})...", attempt),
+        }
+    }
+}
+
 // Define a struct to represent a chat message
 #[derive(Clone, PartialEq, Data, Lens)]
 pub struct Message {
     pub sender: String,
+    pub room: Option<String>,               // Room the message was addressed to, if any
     pub content: String,
-    pub timestamp: String
+    pub timestamp: String,
+    pub id: u64,                            // Server id of the message (0 if unknown)
+    pub server_time: Option<i64>,           // When the server routed the message, in milliseconds since the Unix epoch
+    pub client_id: u64,                     // Id of a message sent by this user (0 for other messages)
+    pub status: MessageStatus,              // How far a message sent by this user got
+    pub offline: bool,                      // Set if the server kept the message for us while we were offline
+    #[data(eq)]
+    pub recipients: Vec<String>,            // Who a message sent by this user was addressed to
+    #[data(eq)]
+    pub delivered_to: Vec<String>,          // Recipients the server wrote the message to
+    #[data(eq)]
+    pub read_by: Vec<String>,               // Recipients who have seen the message
+    #[data(eq)]
+    pub queued_for: Vec<String>,            // Offline recipients the server is keeping the message for
+    #[data(eq)]
+    pub undelivered: Vec<String>            // Why the message did not reach some of its recipients
+}
+
+impl Message {
+    /// Creates a message that is not tracked for delivery, e.g. one received from the server
+    pub fn new(sender: impl Into<String>, content: impl Into<String>, timestamp: impl Into<String>) -> Message {
+        Message {
+            sender: sender.into(),
+            room: None,
+            content: content.into(),
+            timestamp: timestamp.into(),
+            id: 0,
+            server_time: None,
+            client_id: 0,
+            status: MessageStatus::Received,
+            offline: false,
+            recipients: Vec::new(),
+            delivered_to: Vec::new(),
+            read_by: Vec::new(),
+            queued_for: Vec::new(),
+            undelivered: Vec::new(),
+        }
+    }
+}
+
+impl AppState {
+    /// Tells the server we have seen the direct messages shown in the chat, up to the newest of each conversation,
+    /// unless we keep our read receipts private
+    pub fn report_read(&mut self) {
+        // The chat is not on screen
+        if !self.read_receipts || !self.logged_in || self.current_view == 2 {
+            return;
+        }
+        let mut newest: HashMap<String, u64> = HashMap::new();
+        for message in self.messages.iter().filter(|m| m.room.is_none() && m.id != 0 && m.client_id == 0) {
+            let up_to = newest.entry(message.sender.clone()).or_default();
+            *up_to = (*up_to).max(message.id);
+        }
+        for (with, up_to) in newest {
+            if with == self.user_alias || self.read_up_to.get(&with).is_some_and(|read| *read >= up_to) {
+                continue;
+            }
+            if self.signal_sender.try_send(ClientFrame::MarkRead { with: with.clone(), up_to }).is_ok() {
+                self.read_up_to.insert(with, up_to);
+            }
+        }
+    }
+
+    /// Marks the messages we sent `by` as read, up to the message `up_to`
+    pub fn mark_read_by(&mut self, by: &str, up_to: u64) {
+        let key = name_key(by);
+        for message in self.messages.iter_mut().filter(|m| m.client_id != 0 && m.id != 0 && m.id <= up_to) {
+            let to_them = message.recipients.iter().any(|recipient| name_key(recipient) == key);
+            if to_them && !message.read_by.iter().any(|reader| reader == by) {
+                message.read_by.push(by.to_string());
+            }
+        }
+    }
+
+    /// Flags the messages the server did not acknowledge before the connection dropped: they may not have reached it
+    pub fn mark_unacknowledged(&mut self, client_ids: &[u64]) {
+        let unacknowledged = |m: &&mut Message| m.status == MessageStatus::Sending && client_ids.contains(&m.client_id);
+        for message in self.messages.iter_mut().filter(unacknowledged) {
+            message.undelivered.push("the connection was lost before the server acknowledged it".to_string());
+        }
+    }
+
+    /// Remembers that someone started or stopped writing
+    pub fn set_typing(&mut self, name: String, room: Option<String>, typing: bool) {
+        self.typing.retain(|other| other.name != name || other.room != room);
+        if typing {
+            self.typing.push(Typing { name, room, until: Instant::now() + TYPING_TIMEOUT });
+        }
+    }
+
+    /// Remembers the presence of another user, keeping the connected user list up to date
+    pub fn set_presence(&mut self, name: String, presence: Presence, status: Option<String>) {
+        if presence == Presence::Offline {
+            self.presences.remove(&name);
+            self.connected_users.retain(|user| user.user != name);
+            return;
+        }
+        match self.connected_users.iter_mut().find(|user| user.user == name) {
+            Some(user) => {
+                user.presence = presence;
+                user.status = status.clone();
+            }
+            None => self.connected_users.push(ConnectedUsers { user: name.clone(), selected: false, presence, status: status.clone() }),
+        }
+        if presence == Presence::Online && status.is_none() {
+            self.presences.remove(&name);
+        } else {
+            self.presences.insert(name, (presence, status));
+        }
+    }
+
+    /// Follows another user who changed their name
+    pub fn rename_user(&mut self, from: &str, to: &str) {
+        for user in self.connected_users.iter_mut().filter(|user| user.user == from) {
+            user.user = to.to_string();
+        }
+        if let Some(presence) = self.presences.remove(from) {
+            self.presences.insert(to.to_string(), presence);
+        }
+        for typing in self.typing.iter_mut().filter(|typing| typing.name == from) {
+            typing.name = to.to_string();
+        }
+        if let Some(up_to) = self.read_up_to.remove(from) {
+            self.read_up_to.insert(to.to_string(), up_to);
+        }
+    }
+
+    /// Forgets the presence of the others, e.g. after a reconnection: the server tells us again who is not simply online
+    pub fn forget_presences(&mut self) {
+        self.presences.clear();
+        for user in self.connected_users.iter_mut() {
+            user.presence = Presence::Online;
+            user.status = None;
+        }
+    }
+
+    /// Describes who is writing, e.g. "alice is typing…" or "alice, bob (#rust) are typing…"
+    pub fn describe_typing(&self) -> String {
+        let names: Vec<String> = self
+            .typing
+            .iter()
+            .map(|typing| match &typing.room {
+                Some(room) => format!("{} ({})", typing.name, room),
+                None => typing.name.clone(),
+            })
+            .collect();
+        match names.len() {
+            0 => String::new(),
+            1 => format!("{} is typing…", names[0]),
+            _ => format!("{} are typing…", names.join(", ")),
+        }
+    }
+}
+
+/// Someone writing a message to this user, or to one of their rooms
+#[derive(Clone, Debug, PartialEq)]
+pub struct Typing {
+    pub name: String,
+    pub room: Option<String>,
+    pub until: Instant,                     // Forgotten after this, unless they say they are still typing
+}
+
+/// Delivery state of a message, as acknowledged by the server
+#[derive(Clone, Copy, Debug, PartialEq, Data)]
+pub enum MessageStatus {
+    Received,                               // Written by someone else, or by the server
+    Sending,                                // Written by this user, not acknowledged yet
+    Sent,                                   // Recorded by the server
+    Delivered,                              // Written to at least one of its recipients
 }
 
 #[derive(Clone, PartialEq, Data, Lens)]
 pub struct ConnectedUsers {
     pub user: String, 
-    pub selected: bool               // Store if the user is selected in the dm pane
+    pub selected: bool,              // Store if the user is selected in the dm pane
+    #[data(eq)]
+    pub presence: Presence,          // Whether the user is around
+    pub status: Option<String>       // What they said they are up to, if anything
+}
+
+impl ConnectedUsers {
+    /// The name of the user with a badge for their presence, e.g. "◐ alice (away: at lunch)"
+    pub fn describe(&self) -> String {
+        let badge = match self.presence {
+            Presence::Online => "●",
+            Presence::Away => "◐",
+            Presence::Busy => "⊘",
+            Presence::Offline => "○",
+        };
+        match (&self.presence, &self.status) {
+            (Presence::Online, None) => format!("{} {}", badge, self.user),
+            (presence, None) => format!("{} {} ({})", badge, self.user, presence),
+            (presence, Some(status)) => format!("{} {} ({}: {})", badge, self.user, presence, status),
+        }
+    }
 }
 
 
@@ -69,8 +332,8 @@ impl SystemClock<Utc> {
     }
 }
 
-/// Dead code
-/// TODO: Implement Local Time
+// Dead code
+// TODO: Implement Local Time
 // impl<Tz: TimeZone> SystemClock<Tz> {
 //     pub fn new_with_time_zone(tz: Tz) -> SystemClock<Tz> {
 //         SystemClock { time_zone: tz }
@@ -83,6 +346,14 @@ impl<Tz: TimeZone> Clock<Tz> for SystemClock<Tz> {
     }
 }
 
+/// Formats a server timestamp (milliseconds since the Unix epoch) like the chat timestamps
+pub fn format_timestamp(millis: i64) -> String {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .map(|time| time.format("%H:%M %Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
 /* Example usage
 fn main() {
     println!("{:?}", SystemClock::new_utc().now());
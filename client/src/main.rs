@@ -1,6 +1,10 @@
 /*
     The main function is called here. Depends on data.rs and view.rs 
-    Call a task handle the server connection
+    Call a task handle the server connection, once the user picked a server on the login view
+    The server is pinged when it goes quiet, and considered gone if it stops answering.
+    If the connection drops after logging in, the task reconnects with an exponential backoff and resumes
+    the session (see `Session`), then sends the messages written in the meantime.
+    Messages the server never acknowledged before the connection dropped are flagged rather than sent twice.
     New messages from the user's UI will be sent to the server and messages 
     recieved will be sent to the UI to be displayed in chat history.
     
@@ -12,17 +16,37 @@
 use druid::{AppLauncher, WindowDesc};
 
 mod data;
-use data::{AppState, Message, SystemClock};
+use data::{AppState, Message};
 use crate::data::*;
 
 mod view;
 use view::build_ui;
 
-use futures::{select, FutureExt};
+mod cli;
+use cli::Cli;
+
+mod commands;
+
+mod profiles;
+use profiles::ServerProfile;
+
+use protocol::{
+    features, is_room, name_key, tls::{self, ServerTrust}, ClientFrame, ErrorCode, Password, Presence, ResumeToken, ServerFrame,
+    PROTOCOL_VERSION,
+};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use futures::{select, AsyncRead, AsyncReadExt, AsyncWrite, FutureExt, Stream};
 
 use async_std::{
-    io::BufReader,
-    net::{TcpStream, ToSocketAddrs},
+    future,
+    net::TcpStream,
     prelude::*,
     task,
     channel::{unbounded,  Sender, Receiver}
@@ -30,13 +54,88 @@ use async_std::{
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+/// Wait before the first attempt to reconnect, doubled after every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between two attempts to reconnect
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Attempts to reconnect before going back to the login view
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+/// Heartbeats the server may miss before we consider it gone
+const HEARTBEAT_MISSES: u32 = 3;
+
+/// What the connection task remembers from one connection to the next, to log in again after a reconnection
+#[derive(Default)]
+struct Session {
+    /// Name and password of the last successful login
+    login: Option<(String, Option<Password>)>,
+    /// Login sent by the user that the server has not answered yet
+    pending_login: Option<(String, Option<Password>)>,
+    /// Token given by the server to resume the session
+    resume_token: Option<ResumeToken>,
+    /// Messages written by the user that did not reach the server yet
+    unsent: VecDeque<ClientFrame>,
+    /// Client ids of the messages written on the current connection that the server has not acknowledged yet
+    unacknowledged: Vec<u64>,
+    /// Set once the server accepted the login on the current connection
+    logged_in: bool,
+    /// Set if the user turned read receipts off, which the server forgets with every login
+    receipts_off: bool,
+    /// Presence set by the user, unless they are simply online, which the server forgets with every login too
+    presence: Option<ClientFrame>,
+}
+
+impl Session {
+    /// Remembers a message written to the server until it acknowledges it, if it does
+    fn written(&mut self, frame: &ClientFrame, server_acks: bool) {
+        if let ClientFrame::Message { client_id, .. } = frame {
+            if server_acks {
+                self.unacknowledged.push(*client_id);
+            }
+        }
+    }
+
+    /// Keeps a frame that could not be written for the next connection, if it is a message: the other frames are
+    /// either stale by then, e.g. typing notices, or sent again after the login, e.g. the presence
+    fn unsent(&mut self, frame: ClientFrame) {
+        if let ClientFrame::Message { .. } = frame {
+            self.unsent.push_back(frame);
+        }
+    }
+}
+
+/// How logging in again after a reconnection went
+enum Relogin {
+    /// The server resumed the session: nothing was missed
+    Resumed,
+    /// The session had expired, so we logged in again with the same name
+    LoggedIn,
+}
+
 pub(crate) fn main() -> Result<()> {
+    let cli = Cli::parse();
 
     // Create an unbounded channel to send messages from build_ui to main
-    let (sender, receiver) = unbounded::<String>(); // Specify type <T> as String
-
-    // Create an unbounded channel to recieve a list of users from the server
-    let (signal_sender, signal_reciever) = unbounded::<String>();
+    let (sender, receiver) = unbounded::<ClientFrame>(); // Specify type <T> as ClientFrame
+
+    // Create an unbounded channel to send requests (such as the user list) to the server
+    let (signal_sender, signal_reciever) = unbounded::<ClientFrame>();
+
+    // Create an unbounded channel to send the server picked on the login view
+    let (connect_sender, connect_receiver) = unbounded::<ServerProfile>();
+
+    // Saved servers, and the one given on the command line to start with
+    let profiles_path = cli.profiles.clone().or_else(profiles::default_path);
+    let saved_profiles = match &profiles_path {
+        Some(path) => profiles::load(path).unwrap_or_else(|e| {
+            eprintln!("Cannot read the saved servers: {}", e);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    let server = ServerProfile { name: String::new(), host: cli.host.clone(), port: cli.port, tls: cli.tls };
 
     // Setup UI
     let main_window = WindowDesc::new(build_ui())
@@ -50,112 +149,463 @@ pub(crate) fn main() -> Result<()> {
     // `ctx.submit_command`
     let event_sink = launcher.get_external_handle();
 
-    // Run the try_run task
-    task::spawn(connection("127.0.0.1:1632", receiver, signal_reciever, event_sink));
+    // Connect to each server the user picks, one at a time
+    task::spawn(async move {
+        while let Ok(profile) = connect_receiver.recv().await {
+            // Forget what was typed while we were not connected
+            while receiver.try_recv().is_ok() {}
+            while signal_reciever.try_recv().is_ok() {}
+
+            let trust = cli.trust(profile.tls);
+            let heartbeat = Duration::from_secs(cli.heartbeat_interval.max(1));
+            let mut session = Session::default();
+            let mut attempt = 0;
+            let reason = loop {
+                session.logged_in = false;
+                let result = connection(
+                    &profile,
+                    trust.clone(),
+                    heartbeat,
+                    &mut session,
+                    receiver.clone(),
+                    signal_reciever.clone(),
+                    event_sink.clone(),
+                )
+                .await;
+
+                // What the server did not acknowledge may or may not have reached it
+                let lost = std::mem::take(&mut session.unacknowledged);
+                if result.is_err() && !lost.is_empty() {
+                    event_sink.add_idle_callback(move |data: &mut AppState| data.mark_unacknowledged(&lost));
+                }
+                let e = match result {
+                    Ok(()) => break format!("Disconnected from {}", profile.address()),
+                    Err(e) => e,
+                };
+
+                // Only a session we logged in to is worth getting back
+                if session.logged_in {
+                    attempt = 0;
+                }
+                if session.login.is_none() || attempt == MAX_RECONNECT_ATTEMPTS {
+                    break format!("Connection to {} failed: {}", profile.address(), e);
+                }
+                attempt += 1;
+                let delay = reconnect_delay(attempt);
+                eprintln!("Connection to {} lost: {}. Reconnecting in {:?}", profile.address(), e, delay);
+                event_sink.add_idle_callback(move |data: &mut AppState| data.connection = ConnectionState::Reconnecting(attempt));
+                task::sleep(delay).await;
+            };
+
+            // Go back to the login view, showing why we are not connected anymore
+            eprintln!("{}", reason);
+            event_sink.add_idle_callback(move |data: &mut AppState| {
+                data.connection = ConnectionState::Disconnected;
+                data.logged_in = false;
+                data.rooms.clear();
+                data.presence = Presence::Online;
+                data.status = None;
+                data.auto_away = false;
+                data.forget_presences();
+                data.login_error = reason;
+            });
+        }
+    });
 
     // Run the UI in the main thread
-    user_interface(launcher, sender, signal_sender);
+    user_interface(launcher, sender, signal_sender, connect_sender, server, saved_profiles, profiles_path);
 
     Ok(())
 }
 
 
-async fn connection(addr: impl ToSocketAddrs, receiver: Receiver<String>, signal_reciever: Receiver::<String>, event_sink: druid::ExtEventSink) -> Result<()> {
+/// Returns how long to wait before the given attempt to reconnect: 1s, 2s, 4s... up to `MAX_RECONNECT_DELAY`
+fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_DELAY.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(MAX_RECONNECT_DELAY)
+}
+
+/// Talks to the server of `profile`, over TLS if `trust` says which certificates to accept.
+/// If `session` was logged in on a previous connection, logs in again on its own first.
+/// The server is pinged after `heartbeat` of silence.
+/// Returns Ok if the user or the UI ended the conversation, and an error if the connection was lost.
+async fn connection(
+    profile: &ServerProfile,
+    trust: Option<ServerTrust>,
+    heartbeat: Duration,
+    session: &mut Session,
+    receiver: Receiver<ClientFrame>,
+    signal_reciever: Receiver<ClientFrame>,
+    event_sink: druid::ExtEventSink,
+) -> Result<()> {
     
     // Connect to the server
     // Hold the code here; 'await' until a connection is made
     println!("Connecting to server...\n");
-    let stream = TcpStream::connect(addr).await?;
-    let (reader, mut writer) = (&stream, &stream);
+    let host = profile.host.as_str();
+    let stream = TcpStream::connect((host, profile.port)).await?;
+
+    // Over TLS the stream is split into halves that can be used at the same time
+    let (reader, mut writer): (Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWrite + Send + Unpin>) = match trust {
+        Some(trust) => {
+            let stream = tls::connector(&trust)?.connect(tls::server_name(host)?, stream).await?;
+            let (reader, writer) = stream.split();
+            (Box::new(reader), Box::new(writer))
+        }
+        None => (Box::new(stream.clone()), Box::new(stream)),
+    };
     println!("Connected to server!");
 
-    // Set up a buffered reader to reit worksad lines from the server
-    let reader = BufReader::new(reader);
-    let mut lines_from_server = futures::StreamExt::fuse(reader.lines());
+    // Decode the frames sent by the server
+    let mut frames_from_server = futures::StreamExt::fuse(protocol::frames::<_, ServerFrame>(reader));
+
+    // Announce our protocol version and wait for the server to accept it
+    let hello = ClientFrame::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: [
+            features::PEER_LIST,
+            features::HISTORY,
+            features::ROOMS,
+            features::ACCOUNTS,
+            features::RESUME,
+            features::HEARTBEAT,
+            features::MODERATION,
+            features::DELIVERY,
+            features::READ_RECEIPTS,
+            features::TYPING,
+            features::PRESENCE,
+            features::RENAME,
+        ]
+        .iter()
+        .map(|feature| feature.to_string())
+        .collect(),
+    };
+    protocol::write_frame(&mut writer, &hello).await?;
+    // Only servers that know about heartbeats answer our pings, and about delivery acknowledge our messages
+    let (server_heartbeat, server_acks) = match frames_from_server.next().await {
+        Some(Ok(ServerFrame::Welcome { features, max_message_size, .. })) => {
+            event_sink.add_idle_callback(move |data: &mut AppState| data.max_message_size = max_message_size);
+
+            // The login view can now ask for a name, unless we were logged in before
+            if session.login.is_none() {
+                event_sink.add_idle_callback(|data: &mut AppState| data.connection = ConnectionState::Connected);
+            }
+            let has = |wanted: &str| features.iter().any(|feature| feature == wanted);
+            (has(features::HEARTBEAT), has(features::DELIVERY))
+        }
+        Some(Ok(ServerFrame::Error { code, msg })) => {
+            // Let the user know why the server turned us away, and do not come back if we are banned
+            if code == ErrorCode::Banned {
+                session.login = None;
+            }
+            let reason = msg.clone();
+            event_sink.add_idle_callback(move |data: &mut AppState| {
+                data.messages.push(Message::new("Server", reason, ""));
+            });
+            return Err(msg.into());
+        }
+        Some(Ok(frame)) => return Err(format!("unexpected handshake frame: {:?}", frame).into()),
+        Some(Err(e)) => return Err(e.into()),
+        None => return Err("server closed the connection during the handshake".into()),
+    };
 
+    // Get the session back after a reconnection
+    if let Some((name, password)) = session.login.clone() {
+        let token = session.resume_token.clone();
+        // A server that does not answer in time is as good as gone
+        let relogin = future::timeout(heartbeat * HEARTBEAT_MISSES, relogin(&mut writer, &mut frames_from_server, &name, password, token))
+            .await
+            .map_err(|_| "the server did not answer while logging in again")?;
+        let relogin = match relogin? {
+            Ok((relogin, token)) => {
+                session.resume_token = Some(token);
+                relogin
+            }
+            Err(refusal) => {
+                // Nothing more we can do on our own, the user has to log in again
+                session.login = None;
+                return Err(refusal.into());
+            }
+        };
+        session.logged_in = true;
+        if session.receipts_off {
+            protocol::write_frame(&mut writer, &ClientFrame::ReadReceipts { enabled: false }).await?;
+        }
+        if let Some(presence) = &session.presence {
+            protocol::write_frame(&mut writer, presence).await?;
+        }
+        event_sink.add_idle_callback(move |data: &mut AppState| {
+            data.connection = ConnectionState::Connected;
+            // The server tells us which rooms we are back in, and who is not simply online
+            data.rooms.clear();
+            data.forget_presences();
+            let notice = match relogin {
+                Relogin::Resumed => "Reconnected".to_string(),
+                Relogin::LoggedIn => "Reconnected, but the session had expired: messages and rooms may have been missed".to_string(),
+            };
+            data.messages.push(Message::new("Client", notice, ""));
+        });
+
+        // Send what the user wrote while we were away
+        while let Some(frame) = session.unsent.pop_front() {
+            if let Err(e) = protocol::write_frame(&mut writer, &frame).await {
+                session.unsent.push_front(frame);
+                return Err(e.into());
+            }
+            session.written(&frame, server_acks);
+        }
+    }
+
+
+    // When we last heard from the server, and last pinged it
+    let mut last_heard = Instant::now();
+    let mut last_ping = Instant::now();
 
     // Start an event loop to handle incoming messages from the server and user input
     loop {
+        if server_heartbeat {
+            // A silent server is pinged, and given up on (to reconnect) if it stays silent
+            if last_heard.elapsed() >= heartbeat * HEARTBEAT_MISSES {
+                return Err(format!("the server did not answer for {:?}", last_heard.elapsed()).into());
+            }
+            if last_heard.elapsed() >= heartbeat && last_ping.elapsed() >= heartbeat {
+                protocol::write_frame(&mut writer, &ClientFrame::Ping).await?;
+                last_ping = Instant::now();
+            }
+        }
+
         select! {
-            // Read lines from the server socket
+            // Read frames from the server socket
             // Receive messages from the server and send to UI
-            server_message = lines_from_server.next().fuse() => match server_message {
+            server_message = frames_from_server.next().fuse() => match server_message {
                 Some(server_message) => {
                     let server_message = server_message?;
+                    last_heard = Instant::now();
+
+                    // Set if a moderator (or the flood protection) sent us away, in which case reconnecting would be of no use
+                    let mut sent_away = None;
+                    match &server_message {
+                        ServerFrame::LoggedIn { resume_token, .. } => {
+                            // Remember how to log in again if the connection drops
+                            session.login = session.pending_login.take();
+                            session.resume_token = Some(resume_token.clone());
+                            session.logged_in = true;
+                            if session.receipts_off {
+                                protocol::write_frame(&mut writer, &ClientFrame::ReadReceipts { enabled: false }).await?;
+                            }
+                            if let Some(presence) = &session.presence {
+                                protocol::write_frame(&mut writer, presence).await?;
+                            }
 
-                    let message_check = server_message.clone();
-
-                    if message_check == "**Client_list"     // Dead
-                    {   
-
-                        // Recieve client list until the end
-                        let sig_fin: bool = false;
-                        while !sig_fin 
-                        {
-                            // TODO: Read lines from server and fill a vector
+                            // Show the latest messages as soon as we are logged in
+                            let request = ClientFrame::HistoryRequest { with: None, before: None, limit: HISTORY_PAGE };
+                            protocol::write_frame(&mut writer, &request).await?;
                         }
-
-
-                    } else {
-                        // schedule idle callback to change the data
-                        event_sink.add_idle_callback(move |data: &mut AppState| {
-                            let message = server_message.clone();
-                                
-                            // Split the string by ": " to separate the components
-                            let parts: Vec<&str> = message.split(": ").collect();
-
-                            let username = parts[0].trim();
-                            let message = parts[1..].join(": ");
-
-                            // If the message is from the server indicating a new user, add it to the connected user list
-                            if message.starts_with("**New User Connected:") {
-                                let new_connected_user = ConnectedUsers {
-                                    user: String::from(username),
-                                    selected: false
-                                };
-                                data.connected_users.push(new_connected_user);
+                        ServerFrame::LoginRejected { .. } => session.pending_login = None,
+                        ServerFrame::Error { code: ErrorCode::Kicked | ErrorCode::Banned | ErrorCode::Flooding, msg } => {
+                            session.login = None;
+                            sent_away = Some(msg.clone());
+                        }
+                        // Log in again under the new name after a reconnection, the account follows it with its password
+                        ServerFrame::Renamed { from, to } => {
+                            if let Some((name, _)) = session.login.as_mut().filter(|(name, _)| name_key(name) == name_key(from)) {
+                                *name = to.clone();
                             }
+                        }
+                        // The server got our message, whatever became of it
+                        ServerFrame::Sent { client_id, .. } | ServerFrame::Undeliverable { client_id, .. } => {
+                            session.unacknowledged.retain(|id| id != client_id)
+                        }
+                        // The server checks that we are still there
+                        ServerFrame::Ping => protocol::write_frame(&mut writer, &ClientFrame::Pong).await?,
+                        _ => (),
+                    }
 
-                            // terminal logging
-                            println!("username {}", username);  
-                            println!("message {},", message);
-
-                            // Temp code to make client listing prettier 
-                            if username == "**Server" || username == "**FIN" {
-                                let server_message = Message {
-                                    sender: String::from(username),
-                                    content: String::from(message),
-                                    timestamp: String::from(""),
+                    // schedule idle callback to change the data
+                    event_sink.add_idle_callback(move |data: &mut AppState| {
+                        match server_message {
+                            ServerFrame::Message { id, timestamp, from, room, msg, offline } => {
+                                // They are done writing this one
+                                data.set_typing(from.clone(), room.clone(), false);
+
+                                // Create a new message, stamped with the server's time
+                                let mut new_message = Message::new(from, msg, format_timestamp(timestamp));
+                                new_message.id = id;
+                                new_message.server_time = Some(timestamp);
+                                new_message.room = room;
+                                new_message.offline = offline;
+                                data.messages.push(new_message);
+                                data.report_read();
+                            }
+                            ServerFrame::Notice { msg } | ServerFrame::Error { msg, .. } => {
+                                let server_message = Message::new("Server", msg, "");
+                                data.messages.push(server_message);
+                            }
+                            ServerFrame::PeerList { names } => {
+                                // Refresh the connected user list, with what we know of their presence
+                                data.connected_users = names
+                                    .iter()
+                                    .map(|name| {
+                                        let (presence, status) = data.presences.get(name).cloned().unwrap_or_default();
+                                        ConnectedUsers {
+                                            user: name.clone(),
+                                            selected: false,
+                                            presence,
+                                            status
+                                        }
+                                    })
+                                    .collect();
+
+                                // Also print the list in the chat history
+                                let users: Vec<String> = data.connected_users.iter().map(ConnectedUsers::describe).collect();
+                                let server_message = Message::new("Server", format!("Clients Connected: {}", users.join(", ")), "");
+                                data.messages.push(server_message);
+                            }
+                            ServerFrame::Presence { name, presence, status } => data.set_presence(name, presence, status),
+                            ServerFrame::Renamed { from, to } => {
+                                let notice = if from == data.user_alias {
+                                    data.user_alias = to.clone();
+                                    format!("You are now known as {}", to)
+                                } else {
+                                    data.rename_user(&from, &to);
+                                    format!("{} is now known as {}", from, to)
                                 };
+                                data.messages.push(Message::new("Server", notice, ""));
+                            }
+                            ServerFrame::Sent { client_id, id, timestamp } => {
+                                // The server recorded our message: it now has an id and the server's time
+                                if let Some(message) = data.messages.iter_mut().rev().find(|m| m.client_id == client_id) {
+                                    message.id = id;
+                                    message.server_time = Some(timestamp);
+                                    message.timestamp = format_timestamp(timestamp);
+                                    message.status = MessageStatus::Sent;
+                                }
+                            }
+                            ServerFrame::Delivered { id, recipient } => {
+                                // One more recipient got our message
+                                if let Some(message) = data.messages.iter_mut().rev().find(|m| m.id == id && m.client_id != 0) {
+                                    message.delivered_to.push(recipient);
+                                    message.status = MessageStatus::Delivered;
+                                }
+                            }
+                            ServerFrame::Read { by, up_to } => data.mark_read_by(&by, up_to),
+                            ServerFrame::Typing { from, room, typing } => data.set_typing(from, room, typing),
+                            ServerFrame::Undeliverable { client_id, reason, .. } => {
+                                // Mark our message as failed for this recipient
+                                if let Some(message) = data.messages.iter_mut().rev().find(|m| m.client_id == client_id) {
+                                    message.undelivered.push(reason);
+                                }
+                            }
+                            ServerFrame::Queued { client_id, recipient } => {
+                                // The recipient will get our message when they log in again
+                                if let Some(message) = data.messages.iter_mut().rev().find(|m| m.client_id == client_id) {
+                                    message.queued_for.push(recipient);
+                                }
+                            }
+                            ServerFrame::History { messages, has_more, .. } => {
+                                // Put the older messages in front of the ones we already show
+                                let older: Vec<Message> = messages
+                                    .into_iter()
+                                    .filter(|old| !data.messages.iter().any(|known| known.id == old.id))
+                                    .map(|old| {
+                                        let mut message = Message::new(old.from, old.msg, format_timestamp(old.timestamp));
+                                        message.id = old.id;
+                                        message.server_time = Some(old.timestamp);
+                                        message.room = old.to.into_iter().find(|to| is_room(to));
+                                        message
+                                    })
+                                    .collect();
+                                data.messages.splice(0..0, older);
+                                data.history_loading = false;
+                                data.history_complete = !has_more;
+                                data.report_read();
+                            }
+                            ServerFrame::RoomJoined { room, topic, members } => {
+                                let mut notice = format!("You joined {} ({})", room, members.join(", "));
+                                if let Some(topic) = topic {
+                                    notice.push_str(&format!(". Topic: {}", topic));
+                                }
+                                data.messages.push(Message::new("Server", notice, ""));
+                                data.rooms.push(room);
+                            }
+                            ServerFrame::RoomLeft { room } => {
+                                data.messages.push(Message::new("Server", format!("You left {}", room), ""));
+                                data.rooms.retain(|joined| *joined != room);
+                            }
+                            ServerFrame::RoomList { rooms } => {
+                                let rooms: Vec<String> = rooms
+                                    .into_iter()
+                                    .map(|room| match room.topic {
+                                        Some(topic) => format!("{} ({} members, {})", room.name, room.members, topic),
+                                        None => format!("{} ({} members)", room.name, room.members),
+                                    })
+                                    .collect();
+                                let server_message = Message::new("Server", format!("Rooms: {}", rooms.join(", ")), "");
                                 data.messages.push(server_message);
-
-                            } else {
-                                // Create a new message
-                                let new_message = Message {
-                                    sender: String::from(username),
-                                    content: String::from(message),
-                                    timestamp: SystemClock::new_utc().now().format("%H:%M %Y-%m-%d").to_string(),
+                            }
+                            ServerFrame::RoomMembers { room, names } => {
+                                let server_message = Message::new("Server", format!("Members of {}: {}", room, names.join(", ")), "");
+                                data.messages.push(server_message);
+                            }
+                            ServerFrame::RoomTopic { room, topic, by } => {
+                                let notice = match topic {
+                                    Some(topic) => format!("{} set the topic of {} to: {}", by, room, topic),
+                                    None => format!("{} cleared the topic of {}", by, room),
                                 };
-                                data.messages.push(new_message);
+                                data.messages.push(Message::new("Server", notice, ""));
+                            }
+                            ServerFrame::LoggedIn { name, .. } => {
+                                // The server accepted our name, the latest history is on its way
+                                data.logged_in = true;
+                                data.history_loading = true;
+                                data.user_alias = name;
+                                // Tell the server again what we have read, it may have been a while
+                                data.read_up_to.clear();
+                                data.forget_presences();
+                                data.password.clear();
+                                data.login_error.clear();
                             }
-                        });
+                            ServerFrame::LoginRejected { msg, suggestion, .. } => {
+                                // Stay on the login view and offer the suggested name
+                                data.login_error = match &suggestion {
+                                    Some(suggestion) => format!("{}. Try {}?", msg, suggestion),
+                                    None => msg,
+                                };
+                                if let Some(suggestion) = suggestion {
+                                    data.user_alias = suggestion;
+                                }
+                            }
+                            // Only expected during the handshake
+                            ServerFrame::Welcome { .. } => (),
+                            // Heartbeats are handled by the connection task
+                            ServerFrame::Ping | ServerFrame::Pong => (),
+                        }
+                    });
+                    if let Some(reason) = sent_away {
+                        return Err(reason.into());
                     }
-
-                }
-                None => {
-                    println!("Channel closed, exiting event loop");
-                    break; // Break if the channel is closed
                 }
+                // The server went away: reconnect
+                None => return Err("the server closed the connection".into()),
             },
 
             // Receive messages from the UI
             ui_message = receiver.recv().fuse() => match ui_message {
-                Ok(user_text) => {
-                    // Write the user message to the server
-                    writer.write_all(user_text.as_bytes()).await?;
-                    writer.write_all(b"\n").await?;
-                    println!("recieved from UI: {}", user_text);
-            
+                Ok(frame) => {
+                    // Remember the credentials until the server answers
+                    match &frame {
+                        ClientFrame::Login { name, password } => session.pending_login = Some((name.clone(), password.clone())),
+                        ClientFrame::Register { name, password } => {
+                            session.pending_login = Some((name.clone(), Some(password.clone())))
+                        }
+                        _ => (),
+                    }
+
+                    // Write the user message to the server, keeping a message for the next connection if that fails
+                    if let Err(e) = protocol::write_frame(&mut writer, &frame).await {
+                        session.unsent(frame);
+                        return Err(e.into());
+                    }
+                    session.written(&frame, server_acks);
                 }
                 Err(_) => {
                     println!("Channel closed, exiting event loop.");
@@ -164,44 +614,135 @@ async fn connection(addr: impl ToSocketAddrs, receiver: Receiver<String>, signal
             },
             // Receive signals from the UI to the connection thread to send requests to the server
             signal = signal_reciever.recv().fuse() => match signal {
+                Ok(ClientFrame::Disconnect) => {
+                    // The user is leaving this server, e.g. to pick another one
+                    protocol::write_frame(&mut writer, &ClientFrame::Disconnect).await?;
+                    return Ok(());
+                }
                 Ok(signal) => {
-                    // Write the user message to the server
-                    writer.write_all(signal.as_bytes()).await?;
-                    writer.write_all(b"\n").await?;
-                    println!("recieved from UI: {}", signal);
+                    match &signal {
+                        ClientFrame::ReadReceipts { enabled } => session.receipts_off = !enabled,
+                        ClientFrame::SetPresence { presence: Presence::Online, status: None } => session.presence = None,
+                        ClientFrame::SetPresence { .. } => session.presence = Some(signal.clone()),
+                        _ => (),
+                    }
+                    // Write the request to the server
+                    if let Err(e) = protocol::write_frame(&mut writer, &signal).await {
+                        session.unsent(signal);
+                        return Err(e.into());
+                    }
                 }
                 Err(_) => {
                     println!("Signal channel closed, exiting event loop.");
                     break; // Break if the signal channel is closed
                 }
-            }
+            },
+            // Wake up regularly to check the heartbeat
+            _ = task::sleep(heartbeat).fuse() => (),
         }
     }
     
     // Write the disconnect message to the server
-    let disconnect_msg = "Client_Disconnect";
-    writer.write_all(disconnect_msg.as_bytes()).await?;
-    writer.write_all(b"\n").await?;
+    protocol::write_frame(&mut writer, &ClientFrame::Disconnect).await?;
     
     Ok(())
 }
 
-/// Function to launch the application 
-fn user_interface(launcher: AppLauncher<AppState>, sender: Sender<String>, signal_sender: Sender<String>) {
+/// Logs in again after a reconnection: resumes the session with `token`, or else logs in with the same name.
+/// Returns the refusal to show to the user if the server does not let us back in.
+async fn relogin<W, S>(
+    writer: &mut W,
+    frames_from_server: &mut S,
+    name: &str,
+    password: Option<Password>,
+    token: Option<ResumeToken>,
+) -> Result<std::result::Result<(Relogin, ResumeToken), String>>
+where
+    W: AsyncWrite + Unpin,
+    S: Stream<Item = protocol::Result<ServerFrame>> + Unpin,
+{
+    let mut relogin = match token {
+        Some(token) => {
+            protocol::write_frame(writer, &ClientFrame::Resume { name: name.to_string(), token }).await?;
+            Relogin::Resumed
+        }
+        None => {
+            protocol::write_frame(writer, &ClientFrame::Login { name: name.to_string(), password: password.clone() }).await?;
+            Relogin::LoggedIn
+        }
+    };
+
+    loop {
+        match frames_from_server.next().await {
+            Some(Ok(ServerFrame::LoggedIn { resume_token, .. })) => return Ok(Ok((relogin, resume_token))),
+            // We were banned while away
+            Some(Ok(ServerFrame::Error { code: ErrorCode::Banned, msg })) => return Ok(Err(msg)),
+            Some(Ok(ServerFrame::LoginRejected { code, msg, .. })) => match relogin {
+                // The server has not noticed yet that our previous connection dropped: try again later
+                Relogin::Resumed if code == ErrorCode::NameTaken => return Err(msg.into()),
+                // The session expired
+                Relogin::Resumed => {
+                    let login = ClientFrame::Login { name: name.to_string(), password: password.clone() };
+                    protocol::write_frame(writer, &login).await?;
+                    relogin = Relogin::LoggedIn;
+                }
+                Relogin::LoggedIn => return Ok(Err(msg)),
+            },
+            Some(Ok(frame)) => return Err(format!("unexpected frame while logging in again: {:?}", frame).into()),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err("server closed the connection while logging in again".into()),
+        }
+    }
+}
+
+/// Function to launch the application, showing `server` and the `profiles` saved in `profiles_path` on the login view
+fn user_interface(
+    launcher: AppLauncher<AppState>,
+    sender: Sender<ClientFrame>,
+    signal_sender: Sender<ClientFrame>,
+    connect_sender: Sender<ServerProfile>,
+    server: ServerProfile,
+    profiles: Vec<ServerProfile>,
+    profiles_path: Option<std::path::PathBuf>,
+) {
 
     // Initialize the app state
     let initial_state = AppState {
         current_view: 0,
 
+        connection: ConnectionState::Disconnected,
+        server_host: server.host,
+        server_port: server.port.to_string(),
+        server_tls: server.tls,
+        profile_name: String::new(),
+        profiles: Arc::new(profiles),
+
         logged_in: false,
         user_alias: String::new(),
+        password: String::new(),
+        register_mode: false,
+        login_error: String::new(),
         new_user_message: String::new(),
+        max_message_size: None,
         new_socket_message: String::new(),
+        next_client_id: 1,
+        history_loading: false,
+        history_complete: false,
+        read_receipts: true,
+        presence: Presence::Online,
+        status: None,
+        auto_away: false,
         messages: Vec::new(),   
+        rooms: Vec::new(),
+        typing: Vec::new(),
         connected_users: Vec::new(),
         
-        sender: sender, 
-        signal_sender: signal_sender
+        presences: HashMap::new(),
+        read_up_to: HashMap::new(),
+        profiles_path,
+        connect_sender,
+        sender, 
+        signal_sender
     };
 
 
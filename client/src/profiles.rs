@@ -0,0 +1,79 @@
+/*
+    Saved server profiles
+
+    The servers a user connects to can be saved under a name, so that they can be picked
+    from the login view next time. They are kept in a TOML file, by default
+    `rusty-chat/profiles.toml` in the user's config directory (e.g. `~/.config` on Linux):
+
+        [[profile]]
+        name = "home"
+        host = "chat.example.org"
+        port = 1632
+        tls = true
+*/
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use druid::{Data, Lens};
+use serde::{Deserialize, Serialize};
+
+/// A server the user can connect to
+#[derive(Debug, Clone, PartialEq, Data, Lens, Serialize, Deserialize)]
+pub struct ServerProfile {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub tls: bool,                          // Encrypt the connection (see cli.rs for the trusted certificates)
+}
+
+impl ServerProfile {
+    /// Describes where the profile connects to, e.g. "chat.example.org:1632 (TLS)"
+    pub fn address(&self) -> String {
+        if self.tls {
+            format!("{}:{} (TLS)", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Layout of the profiles file: one `[[profile]]` table per server
+#[derive(Default, Serialize, Deserialize)]
+struct ProfilesFile {
+    #[serde(default)]
+    profile: Vec<ServerProfile>,
+}
+
+/// Where the profiles are kept unless another file is given on the command line
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("rusty-chat").join("profiles.toml"))
+}
+
+/// Reads the saved profiles; there are none until the first one is saved
+pub fn load(path: &Path) -> io::Result<Vec<ServerProfile>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let file: ProfilesFile =
+        toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    Ok(file.profile)
+}
+
+/// Replaces the saved profiles, creating the config directory if needed
+pub fn save(path: &Path, profiles: &[ServerProfile]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let text = toml::to_string(&ProfilesFile { profile: profiles.to_vec() }).map_err(io::Error::other)?;
+
+    // Write a temporary file first, so that a crash never leaves the profiles half written
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
@@ -5,13 +5,26 @@
     Date:   3/21/2024
 */
 
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use crate::commands::{parse_command, parse_message};
 use crate::data::*;
+use crate::profiles::{self, ServerProfile};
+use protocol::{normalize_name, validate_name, ClientFrame, NameError, Password, Presence};
 
 use druid::{ 
-    widget::{Button, CrossAxisAlignment, Flex,
-            Label, Scroll, SizedBox, TextBox, ViewSwitcher}, Widget, WidgetExt 
+    text::Selection,
+    widget::{Button, Checkbox, Controller, CrossAxisAlignment, Either, Flex,
+            Label, List, Scroll, SizedBox, TextBox, ViewSwitcher}, BoxConstraints, Color, Env, Event, EventCtx,
+            LayoutCtx, LifeCycle, LifeCycleCtx, PaintCtx, Selector, Size, TimerToken, UpdateCtx, Widget, WidgetExt 
 };
 
+/// Sent by the rows of the saved server list to connect to their server
+const CONNECT_PROFILE: Selector<ServerProfile> = Selector::new("rusty-chat.connect-profile");
+
+/// Sent by the rows of the saved server list to delete the profile with this name
+const DELETE_PROFILE: Selector<String> = Selector::new("rusty-chat.delete-profile");
+
 pub fn build_ui() -> impl Widget<AppState> {
 
     let view_switcher = ViewSwitcher::new(
@@ -37,10 +50,205 @@ pub fn build_ui() -> impl Widget<AppState> {
         .with_flex_child(view_switcher,1.0)
 }
 
-/// Returns a user interface layout for setting the user's alias 
-/// TODO: Deny user from entering any special characters such as '**' (** denotes server messages)
+/// Returns a user interface layout for picking a server, then setting the user's alias once connected
 pub fn login_ui() -> impl Widget<AppState> {
 
+    // Shows why we are not connected or why the server refused the name, e.g. because it is taken
+    let error_label = Label::dynamic(|data: &AppState, _env| data.login_error.clone())
+        .with_text_color(Color::rgb8(0xE0, 0x40, 0x40))
+        .padding(3.0);
+
+    Flex::column()
+        .with_child(Either::new(|data: &AppState, _env| data.connection == ConnectionState::Connected, account_ui(), server_ui()))
+        .with_child(error_label)
+}
+
+/// Returns a layout for picking the server to connect to, typed in or from the saved profiles
+fn server_ui() -> impl Widget<AppState> {
+
+    // Server address and connect button ===============================================
+    let host_box = TextBox::new()
+        .with_placeholder("Server")
+        .expand_width()
+        .lens(AppState::server_host)
+        .padding(3.0);
+
+    let port_box = TextBox::new()
+        .with_placeholder("Port")
+        .fix_width(64.0)
+        .lens(AppState::server_port)
+        .padding(3.0);
+
+    let tls_toggle = Checkbox::new("TLS")
+        .lens(AppState::server_tls)
+        .padding(3.0);
+
+    let connect_button = Button::dynamic(|data: &AppState, _env| {
+            if data.connection == ConnectionState::Connecting { "Connecting...".to_string() } else { "Connect".to_string() }
+        })
+        .on_click(|_ctx, data: &mut AppState, _env| match typed_server(data) {
+            Ok(server) => connect(data, server),
+            Err(e) => data.login_error = e,
+        })
+        .padding(3.0);
+
+    let address_row = Flex::row()
+        .with_flex_child(host_box, 1.0)
+        .with_child(port_box)
+        .with_child(tls_toggle)
+        .with_child(connect_button);
+// End server address and connect button ===========================================
+
+    // Saves the typed server for next time
+    let profile_name_box = TextBox::new()
+        .with_placeholder("Profile name (optional)")
+        .expand_width()
+        .lens(AppState::profile_name)
+        .padding(3.0);
+
+    let save_button = Button::new("Save server")
+        .on_click(|_ctx, data: &mut AppState, _env| save_profile(data))
+        .padding(3.0);
+
+    let save_row = Flex::row()
+        .with_flex_child(profile_name_box, 1.0)
+        .with_spacer(8.0)
+        .with_child(save_button);
+
+    // One row per saved server; the buttons are handled by `ProfileCommands`
+    let profile_list = List::new(|| {
+        Flex::row()
+            .with_flex_child(
+                Label::dynamic(|profile: &ServerProfile, _env| format!("{}: {}", profile.name, profile.address()))
+                    .padding(3.0)
+                    .expand_width(),
+                1.0,
+            )
+            .with_child(
+                Button::new("Connect")
+                    .on_click(|ctx, profile: &mut ServerProfile, _env| ctx.submit_command(CONNECT_PROFILE.with(profile.clone())))
+                    .padding(3.0),
+            )
+            .with_child(
+                Button::new("Delete")
+                    .on_click(|ctx, profile: &mut ServerProfile, _env| ctx.submit_command(DELETE_PROFILE.with(profile.name.clone())))
+                    .padding(3.0),
+            )
+    })
+    .lens(AppState::profiles);
+
+    Flex::column()
+        .with_child(address_row)
+        .with_child(save_row)
+        .with_child(
+            Label::dynamic(|data: &AppState, _env| {
+                if data.profiles.is_empty() { "No saved servers".to_string() } else { "Saved servers".to_string() }
+            })
+            .padding(3.0),
+        )
+        .with_child(Scroll::new(profile_list).vertical())
+        .controller(ProfileCommands)
+}
+
+/// Reads the server typed on the login view
+fn typed_server(data: &AppState) -> Result<ServerProfile, String> {
+    let host = data.server_host.trim();
+    if host.is_empty() {
+        return Err("Enter the address of the server".to_string());
+    }
+    let port = data
+        .server_port
+        .trim()
+        .parse::<u16>()
+        .ok()
+        .filter(|port| *port != 0)
+        .ok_or_else(|| "The port must be a number between 1 and 65535".to_string())?;
+    Ok(ServerProfile {
+        name: data.profile_name.trim().to_string(),
+        host: host.to_string(),
+        port,
+        tls: data.server_tls,
+    })
+}
+
+/// Asks the connection task in main.rs to connect to a server.
+/// The login view asks for a name once the server accepted the handshake.
+fn connect(data: &mut AppState, server: ServerProfile) {
+    if data.connection != ConnectionState::Disconnected {
+        return;
+    }
+
+    // Show the server we are connecting to, so that it can be edited after a failure
+    data.server_host = server.host.clone();
+    data.server_port = server.port.to_string();
+    data.server_tls = server.tls;
+    data.profile_name = server.name.clone();
+
+    if let Err(err) = data.connect_sender.try_send(server) {
+        eprintln!("Error starting the connection: {:?}", err);
+    } else {
+        data.connection = ConnectionState::Connecting;
+        data.login_error.clear();
+    }
+}
+
+/// Saves the typed server under its profile name (or its address), replacing a profile with the same name
+fn save_profile(data: &mut AppState) {
+    let mut server = match typed_server(data) {
+        Ok(server) => server,
+        Err(e) => {
+            data.login_error = e;
+            return;
+        }
+    };
+    if server.name.is_empty() {
+        server.name = format!("{}:{}", server.host, server.port);
+    }
+
+    let mut saved = data.profiles.to_vec();
+    match saved.iter_mut().find(|profile| profile.name == server.name) {
+        Some(profile) => *profile = server,
+        None => saved.push(server),
+    }
+    store_profiles(data, saved);
+}
+
+/// Replaces the saved profiles, shown on the login view and kept in the profiles file
+fn store_profiles(data: &mut AppState, saved: Vec<ServerProfile>) {
+    data.login_error = match &data.profiles_path {
+        Some(path) => match profiles::save(path, &saved) {
+            Ok(()) => String::new(),
+            Err(e) => format!("Cannot save the servers: {}", e),
+        },
+        None => "There is no config directory to save the servers in, use --profiles".to_string(),
+    };
+    data.profiles = Arc::new(saved);
+}
+
+/// Handles the buttons of the saved server list, which only see their own profile
+struct ProfileCommands;
+
+impl<W: Widget<AppState>> Controller<AppState, W> for ProfileCommands {
+    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        match event {
+            Event::Command(command) if command.is(CONNECT_PROFILE) => {
+                connect(data, command.get_unchecked(CONNECT_PROFILE).clone());
+                ctx.set_handled();
+            }
+            Event::Command(command) if command.is(DELETE_PROFILE) => {
+                let name = command.get_unchecked(DELETE_PROFILE);
+                let saved = data.profiles.iter().filter(|profile| profile.name != *name).cloned().collect();
+                store_profiles(data, saved);
+                ctx.set_handled();
+            }
+            _ => child.event(ctx, event, data, env),
+        }
+    }
+}
+
+/// Returns a layout for setting the user's alias on the server we are connected to
+fn account_ui() -> impl Widget<AppState> {
+
     // Texbox and send button ==========================================================
     let text_box = TextBox::new()
         .with_placeholder("Username")
@@ -48,19 +256,36 @@ pub fn login_ui() -> impl Widget<AppState> {
         .lens(AppState::user_alias)
         .padding(3.0);
 
-    let send_button = Button::new("Send")
+    let password_box = PasswordBox::new("Password (optional for guests)")
+        .expand_width()
+        .lens(AppState::password)
+        .padding(3.0);
+
+    let send_button = Button::dynamic(|data: &AppState, _env| {
+            if data.register_mode { "Register".to_string() } else { "Log in".to_string() }
+        })
         .on_click(move |_ctx, data: &mut AppState, _env| {
+            // The server would refuse the name anyway
+            if name_error(&data.user_alias).is_some() {
+                return;
+            }
 
             // Get text from the text box and add it to new_user_message
-            let message = data.user_alias.clone(); 
+            let message = normalize_name(&data.user_alias);
+
+            // Registering needs a password, logging in only does for registered names
+            let frame = if data.register_mode {
+                ClientFrame::Register { name: message, password: Password(data.password.clone()) }
+            } else {
+                let password = Some(data.password.clone()).filter(|password| !password.is_empty()).map(Password);
+                ClientFrame::Login { name: message, password }
+            };
 
-            if let Err(err) = data.sender.try_send(message.clone()) {
+            // The user is marked logged in once the server accepts the name (see main.rs)
+            if let Err(err) = data.sender.try_send(frame) {
                 eprintln!("Error sending username: {:?}", err);
             } else {
-                println!("Username set to: {}", message);
-                // Set the user to logged in with the given user alias
-                data.logged_in = true;
-                data.user_alias = message;
+                data.login_error.clear();
             }
 
         })
@@ -70,10 +295,130 @@ pub fn login_ui() -> impl Widget<AppState> {
     let input_row = Flex::row()
     .with_flex_child(text_box, 1.0)
     .with_spacer(8.0) // Add spacing between text box and button
-    .with_child(send_button);
+    .with_child(send_button.disabled_if(|data: &AppState, _env| name_error(&data.user_alias).is_some()));
 // End Textbox and send button =======================================================
-    
-    input_row //.debug_paint_layout()
+
+    // Says what is wrong with the name while it is typed, e.g. a ':' or a "**" in front
+    let name_error_label = Label::dynamic(|data: &AppState, _env| match name_error(&data.user_alias) {
+            Some(NameError::Empty) | None => String::new(),
+            Some(e) => e.to_string(),
+        })
+        .with_text_color(Color::rgb8(0xE0, 0x40, 0x40))
+        .padding(3.0);
+
+    // Switches between logging in and registering a new account
+    let register_toggle = Checkbox::new("Register a new account")
+        .lens(AppState::register_mode)
+        .padding(3.0);
+
+    // Leaves this server to pick another one
+    let server_row = Flex::row()
+        .with_flex_child(
+            Label::dynamic(|data: &AppState, _env| format!("Connected to {}:{}", data.server_host, data.server_port))
+                .padding(3.0)
+                .expand_width(),
+            1.0,
+        )
+        .with_child(
+            Button::new("Change server")
+                .on_click(|_ctx, data: &mut AppState, _env| {
+                    if let Err(err) = data.signal_sender.try_send(ClientFrame::Disconnect) {
+                        eprintln!("Error disconnecting: {:?}", err);
+                    }
+                })
+                .padding(3.0),
+        );
+
+    Flex::column()
+        .with_child(server_row)
+        .with_child(input_row)
+        .with_child(name_error_label)
+        .with_child(password_box)
+        .with_child(register_toggle) //.debug_paint_layout()
+}
+
+/// A text box showing a bullet for every character of the password typed in it
+///
+/// The box itself only ever holds the bullets, so copying from it does not give the password away.
+/// Edits are replayed on the password from where the caret was before and after them
+struct PasswordBox {
+    inner: TextBox<String>,
+    /// What the box shows
+    masked: String,
+}
+
+impl PasswordBox {
+    const BULLET: char = '\u{2022}';
+
+    fn new(placeholder: &str) -> PasswordBox {
+        PasswordBox { inner: TextBox::new().with_placeholder(placeholder.to_string()), masked: String::new() }
+    }
+
+    fn mask(password: &str) -> String {
+        PasswordBox::BULLET.to_string().repeat(password.chars().count())
+    }
+
+    /// The selection of the box, in characters, if the text input is not busy with it
+    fn selection(&self, text: &str) -> Option<(usize, usize)> {
+        let component = self.inner.text();
+        component.can_read().then(|| {
+            let range = component.borrow().selection().range();
+            let chars = |offset: usize| text.get(..offset).map_or(0, |before| before.chars().count());
+            (chars(range.start), chars(range.end))
+        })
+    }
+}
+
+impl Widget<String> for PasswordBox {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut String, env: &Env) {
+        let before = self.selection(&self.masked);
+        let mut edited = self.masked.clone();
+        self.inner.event(ctx, event, &mut edited, env);
+        if edited == self.masked {
+            return;
+        }
+
+        // What the edit left in front of the selection and after the caret comes from the password,
+        // the characters in between were typed or pasted
+        let Some(((start, _), (_, caret))) = before.zip(self.selection(&edited)) else { return };
+        let password: Vec<char> = data.chars().collect();
+        let kept = start.min(caret).min(password.len());
+        let kept_after = (edited.chars().count() - caret).min(password.len() - kept);
+        let typed = edited.chars().skip(kept).take(caret - kept);
+        let after = &password[password.len() - kept_after..];
+        *data = password[..kept].iter().copied().chain(typed).chain(after.iter().copied()).collect();
+
+        // The box holds the edited text until the update masks it again, even if the password did not change,
+        // which keeps the caret after the same bullet
+        self.masked = edited;
+        ctx.request_update();
+        if self.inner.text().can_write() {
+            let caret = Selection::caret(caret * PasswordBox::BULLET.len_utf8());
+            if let Some(invalidation) = self.inner.text_mut().borrow_mut().set_selection(caret) {
+                ctx.invalidate_text_input(invalidation);
+            }
+        }
+    }
+
+    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &String, env: &Env) {
+        if let LifeCycle::WidgetAdded = event {
+            self.masked = PasswordBox::mask(data);
+        }
+        self.inner.lifecycle(ctx, event, &self.masked, env)
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx, _old_data: &String, data: &String, env: &Env) {
+        let old = std::mem::replace(&mut self.masked, PasswordBox::mask(data));
+        self.inner.update(ctx, &old, &self.masked, env)
+    }
+
+    fn layout(&mut self, ctx: &mut LayoutCtx, bc: &BoxConstraints, _data: &String, env: &Env) -> Size {
+        self.inner.layout(ctx, bc, &self.masked, env)
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, _data: &String, env: &Env) {
+        self.inner.paint(ctx, &self.masked, env)
+    }
 }
 
 /// A user interface that returns a layout for sending and receiving messages
@@ -87,7 +432,35 @@ pub fn chat_ui() -> impl Widget<AppState> {
                     let messages = data
                         .messages
                         .iter()
-                        .map(|msg| format!("{}: {} ({})", msg.sender, msg.content, msg.timestamp))
+                        .map(|msg| {
+                            let mut line = match &msg.room {
+                                Some(room) => format!("{} @ {}: {} ({})", msg.sender, room, msg.content, msg.timestamp),
+                                None => format!("{}: {} ({})", msg.sender, msg.content, msg.timestamp),
+                            };
+                            if msg.offline {
+                                line.push_str(" [delivered while offline]");
+                            }
+                            // How far our own messages got
+                            match msg.status {
+                                MessageStatus::Received => (),
+                                MessageStatus::Sending => line.push_str(" [sending]"),
+                                MessageStatus::Sent => line.push_str(" [sent]"),
+                                MessageStatus::Delivered => {
+                                    line.push_str(&format!(" [delivered to: {}]", msg.delivered_to.join(", ")))
+                                }
+                            }
+                            if !msg.read_by.is_empty() {
+                                line.push_str(&format!(" [read by: {}]", msg.read_by.join(", ")));
+                            }
+                            if !msg.queued_for.is_empty() {
+                                line.push_str(&format!(" [queued for: {}]", msg.queued_for.join(", ")));
+                            }
+                            // Flag messages that did not reach everyone
+                            for reason in &msg.undelivered {
+                                line.push_str(&format!(" [failed: {}]", reason));
+                            }
+                            line
+                        })
                         .collect::<Vec<String>>()
                         .join("\n");
                     messages
@@ -97,6 +470,7 @@ pub fn chat_ui() -> impl Widget<AppState> {
             1.0)
     )
     .vertical()
+    .controller(LoadOlderMessages)
     .expand_width();
 
 
@@ -105,18 +479,68 @@ pub fn chat_ui() -> impl Widget<AppState> {
         .with_placeholder("Send message")
         .expand_width()
         .lens(AppState::new_user_message)
+        .controller(TypingNotifier::new())
+        .padding(3.0);
+
+    // Who is writing to us, e.g. "alice is typing…"
+    let typing_label = Label::dynamic(|data: &AppState, _env| data.describe_typing())
+        .with_text_color(Color::rgb8(0xA0, 0xA0, 0xA0))
+        .controller(ExpireTyping { timer: TimerToken::INVALID })
         .padding(3.0);
 
 
+    // Messages the server would refuse are not sent
+    let size_error = Label::dynamic(|data: &AppState, _env| oversize_error(data).unwrap_or_default())
+        .with_text_color(Color::rgb8(0xE0, 0x40, 0x40))
+        .padding(3.0);
+
     let send_button = Button::new("Send")
         .on_click(move |_ctx, data: &mut AppState, _env| {
+            if oversize_error(data).is_some() {
+                return;
+            }
 
             // Get text from the text box and add it to new_user_message
             let message = data.new_user_message.clone(); // Clone the text to avoid borrowing issues
 
-            // Send the string to the connection Task in main.rs
+            // Commands such as "/join #general" are requests for the server, not messages
+            if message.starts_with('/') {
+                match parse_command(&message) {
+                    Ok(frame) => {
+                        match &frame {
+                            ClientFrame::ReadReceipts { enabled } => data.read_receipts = *enabled,
+                            ClientFrame::SetPresence { presence, status } => {
+                                data.presence = *presence;
+                                data.status = status.clone();
+                                data.auto_away = false;
+                            }
+                            _ => (),
+                        }
+                        if let Err(err) = data.signal_sender.try_send(frame) {
+                            eprintln!("Error sending command: {:?}", err);
+                        }
+                        data.new_user_message.clear();
+                    }
+                    Err(hint) => data.messages.push(Message::new("Client", hint, "")),
+                }
+                return;
+            }
+
+            // The text must be in the 'recipient: message' format
+            let client_id = data.next_client_id;
+            let Some(frame) = parse_message(&message, client_id) else {
+                data.messages.push(Message::new("Client", "Messages must be formatted as 'recipient: message'", ""));
+                return;
+            };
+            data.next_client_id += 1;
+            let recipients = match &frame {
+                ClientFrame::Message { to, .. } => to.clone(),
+                _ => Vec::new(),
+            };
+
+            // Send the frame to the connection Task in main.rs
             // try_send requires error handling
-            if let Err(err) = data.sender.try_send(message.clone()) {
+            if let Err(err) = data.sender.try_send(frame) {
                 eprintln!("Error sending message: {:?}", err);
             } else {
                 println!("Button has been clicked! - Message sent from: {}", message);
@@ -126,14 +550,20 @@ pub fn chat_ui() -> impl Widget<AppState> {
             let username: String = data.user_alias.clone();
 
             // Create a new message
-            let new_message = Message {
-                sender: String::from(username),
-                content: String::from(message),
-                timestamp: SystemClock::new_utc().now().format("%Y-%m-%d %H:%M").to_string(),
-            };
+            let mut new_message = Message::new(
+                username,
+                message,
+                SystemClock::new_utc().now().format("%Y-%m-%d %H:%M").to_string(),
+            );
+            new_message.client_id = client_id;
+            new_message.status = MessageStatus::Sending;
+            new_message.recipients = recipients;
 
             // Append the new message to the messages vector
             data.messages.push(new_message);
+
+            // Emptying the text box tells the recipients the user stopped typing, see `TypingNotifier`
+            data.new_user_message.clear();
         })
         .padding(3.0);
 
@@ -141,7 +571,7 @@ pub fn chat_ui() -> impl Widget<AppState> {
     let input_row = Flex::row()
         .with_flex_child(text_box, 1.0)
         .with_spacer(8.0) // Add spacing between text box and button
-        .with_child(send_button);
+        .with_child(send_button.disabled_if(|data: &AppState, _env| oversize_error(data).is_some()));
 // End Textbox and send button =======================================================
     
     // Button to switch views to the user list
@@ -151,9 +581,9 @@ pub fn chat_ui() -> impl Widget<AppState> {
             data.current_view = 2;
 
             // Signal the server for a request for a list of users
-            let signal_msg = "Client_PeerList_Request";
+            let signal_msg = ClientFrame::PeerListRequest;
             
-            if let Err(err) = data.signal_sender.try_send(signal_msg.to_string()) {
+            if let Err(err) = data.signal_sender.try_send(signal_msg) {
                 eprintln!("Error sending username: {:?}", err);
             } else {
                 println!("Sent server signal");
@@ -168,9 +598,9 @@ pub fn chat_ui() -> impl Widget<AppState> {
         .on_click(move |_ctx, data: &mut AppState, _env| {
 
             // Signal the server for a request for a list of users
-            let signal_msg = "Client_PeerList_Request";
+            let signal_msg = ClientFrame::PeerListRequest;
             
-            if let Err(err) = data.signal_sender.try_send(signal_msg.to_string()) {
+            if let Err(err) = data.signal_sender.try_send(signal_msg) {
                 eprintln!("Error sending username: {:?}", err);
             } else {
                 println!("Sent server signal");
@@ -186,30 +616,275 @@ pub fn chat_ui() -> impl Widget<AppState> {
     //     .with_child(list_clients_button)
     //     .with_child(new_recipient_button);
             
-    let layout = Flex::column()
+    Flex::column()
         .with_child(list_clients_button)
         .with_child(new_recipient_button)
+        .with_child(
+            // Tells whether messages are going through, e.g. while the client reconnects
+            Label::dynamic(|data: &AppState, _env| {
+                format!("{} ({}:{})", data.connection.describe(), data.server_host, data.server_port)
+            })
+            .with_text_color(Color::rgb8(0xA0, 0xA0, 0xA0))
+            .padding(3.0)
+            .center(),
+        )
         .with_child(Label::new("Chat Messages").padding(8.0).center())
+        .with_child(
+            // The rooms this user receives messages from
+            Label::dynamic(|data: &AppState, _env| {
+                if data.rooms.is_empty() {
+                    "Rooms: none (try /rooms and /join #room)".to_string()
+                } else {
+                    format!("Rooms: {}", data.rooms.join(", "))
+                }
+            })
+            .padding(3.0)
+            .center(),
+        )
         .with_flex_child(message_list, 1.0)
+        .with_child(typing_label)
         .with_child(input_row)
-        .cross_axis_alignment(CrossAxisAlignment::End);
-    
-    layout //.debug_paint_layout()
+        .with_child(size_error)
+        .cross_axis_alignment(CrossAxisAlignment::End) //.debug_paint_layout()
+        .controller(AutoAway::new())
+}
+
+/// Says why the server would refuse the typed name, if it would
+fn name_error(name: &str) -> Option<NameError> {
+    validate_name(&normalize_name(name)).err()
+}
+
+/// Says why the typed message cannot be sent, if it is longer than the server accepts
+fn oversize_error(data: &AppState) -> Option<String> {
+    let max = data.max_message_size?;
+    if data.new_user_message.starts_with('/') {
+        return None;
+    }
+    // Only the text of a message counts, not its recipients
+    let Some(ClientFrame::Message { msg, .. }) = parse_message(&data.new_user_message, 0) else {
+        return None;
+    };
+    (msg.len() > max).then(|| format!("Message too long: {} bytes, the server accepts {}", msg.len(), max))
+}
+
+
+/// Requests older messages from the server when the message list is scrolled to the top
+struct LoadOlderMessages;
+
+impl<W: Widget<AppState>> Controller<AppState, Scroll<AppState, W>> for LoadOlderMessages {
+    fn event(&mut self, child: &mut Scroll<AppState, W>, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        child.event(ctx, event, data, env);
+
+        if let Event::Wheel(_) = event {
+            if child.offset().y <= 0.0 && !data.history_loading && !data.history_complete {
+                // Ask for the page right before the oldest message we know of
+                let before = data.messages.iter().map(|msg| msg.id).filter(|id| *id != 0).min();
+                let request = ClientFrame::HistoryRequest { with: None, before, limit: HISTORY_PAGE };
+
+                if let Err(err) = data.signal_sender.try_send(request) {
+                    eprintln!("Error requesting history: {:?}", err);
+                } else {
+                    data.history_loading = true;
+                }
+            }
+        }
+    }
+}
+
+
+/// Tells the recipients of the message being written that the user is typing: when they start,
+/// again every `TYPING_RESEND` while they keep at it, and when they stop, after `TYPING_IDLE` without
+/// a keystroke or as soon as the text box no longer holds a message for them, e.g. once the Send button emptied it
+struct TypingNotifier {
+    /// The recipients last told that the user is typing, and when
+    notified: Option<(Vec<String>, Instant)>,
+    /// When the text last changed
+    last_edit: Instant,
+    /// Fires to check whether the user went idle
+    timer: TimerToken,
+}
+
+impl TypingNotifier {
+    fn new() -> TypingNotifier {
+        TypingNotifier { notified: None, last_edit: Instant::now(), timer: TimerToken::INVALID }
+    }
+
+    /// Follows the text being written
+    fn typed(&mut self, ctx: &mut UpdateCtx, data: &AppState) {
+        // Only messages have recipients, and only once they are named
+        let to = match parse_message(&data.new_user_message, 0) {
+            Some(ClientFrame::Message { to, .. }) if !data.new_user_message.starts_with('/') => to,
+            _ => return self.stop(data),
+        };
+        self.last_edit = Instant::now();
+        match &self.notified {
+            Some((told, at)) if *told == to && at.elapsed() < TYPING_RESEND => (),
+            _ => {
+                if self.notified.as_ref().is_some_and(|(told, _)| *told != to) {
+                    self.stop(data);
+                }
+                send_typing(data, to.clone(), true);
+                self.notified = Some((to, self.last_edit));
+                self.timer = ctx.request_timer(TYPING_IDLE);
+            }
+        }
+    }
+
+    /// Tells the recipients that the user stopped typing, if they were told they started
+    fn stop(&mut self, data: &AppState) {
+        if let Some((to, _)) = self.notified.take() {
+            send_typing(data, to, false);
+        }
+    }
+}
+
+impl<W: Widget<AppState>> Controller<AppState, W> for TypingNotifier {
+    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        match event {
+            Event::Timer(token) if *token == self.timer => {
+                let idle = self.last_edit.elapsed();
+                if idle >= TYPING_IDLE {
+                    self.stop(data);
+                } else {
+                    self.timer = ctx.request_timer(TYPING_IDLE - idle);
+                }
+                ctx.set_handled();
+            }
+            _ => child.event(ctx, event, data, env),
+        }
+    }
+
+    fn update(&mut self, child: &mut W, ctx: &mut UpdateCtx, old_data: &AppState, data: &AppState, env: &Env) {
+        if data.new_user_message != old_data.new_user_message {
+            self.typed(ctx, data);
+        }
+        child.update(ctx, old_data, data, env)
+    }
+}
+
+fn send_typing(data: &AppState, to: Vec<String>, typing: bool) {
+    if let Err(err) = data.signal_sender.try_send(ClientFrame::Typing { to, typing }) {
+        eprintln!("Error sending typing notice: {:?}", err);
+    }
+}
+
+/// Forgets the users who did not say in time that they are still typing, checking every second
+struct ExpireTyping {
+    timer: TimerToken,
+}
+
+impl ExpireTyping {
+    const INTERVAL: Duration = Duration::from_secs(1);
+}
+
+impl<W: Widget<AppState>> Controller<AppState, W> for ExpireTyping {
+    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        match event {
+            Event::Timer(token) if *token == self.timer => {
+                let now = Instant::now();
+                if data.typing.iter().any(|typing| typing.until <= now) {
+                    data.typing.retain(|typing| typing.until > now);
+                }
+                self.timer = ctx.request_timer(ExpireTyping::INTERVAL);
+                ctx.set_handled();
+            }
+            _ => child.event(ctx, event, data, env),
+        }
+    }
+
+    fn lifecycle(&mut self, child: &mut W, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &AppState, env: &Env) {
+        if let LifeCycle::WidgetAdded = event {
+            self.timer = ctx.request_timer(ExpireTyping::INTERVAL);
+        }
+        child.lifecycle(ctx, event, data, env)
+    }
+}
+
+/// Sets the user away after `AWAY_AFTER` without input, as long as they are simply online,
+/// and back online as soon as they touch the keyboard or the mouse again
+struct AutoAway {
+    /// When the user last did something
+    last_input: Instant,
+    /// Fires to check whether the user went idle
+    timer: TimerToken,
+}
+
+impl AutoAway {
+    fn new() -> AutoAway {
+        AutoAway { last_input: Instant::now(), timer: TimerToken::INVALID }
+    }
+
+    /// Tells the server the user's presence, keeping their status text
+    fn set(data: &mut AppState, presence: Presence) {
+        data.presence = presence;
+        let frame = ClientFrame::SetPresence { presence, status: data.status.clone() };
+        if let Err(err) = data.signal_sender.try_send(frame) {
+            eprintln!("Error sending presence: {:?}", err);
+        }
+    }
 }
 
+impl<W: Widget<AppState>> Controller<AppState, W> for AutoAway {
+    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        match event {
+            Event::Timer(token) if *token == self.timer => {
+                let idle = self.last_input.elapsed();
+                if idle < AWAY_AFTER {
+                    self.timer = ctx.request_timer(AWAY_AFTER - idle);
+                } else {
+                    if data.presence == Presence::Online && data.logged_in {
+                        AutoAway::set(data, Presence::Away);
+                        data.auto_away = true;
+                    }
+                    self.timer = ctx.request_timer(AWAY_AFTER);
+                }
+                ctx.set_handled();
+                return;
+            }
+            Event::KeyDown(_) | Event::MouseDown(_) | Event::MouseMove(_) | Event::Wheel(_) => {
+                self.last_input = Instant::now();
+                if data.auto_away {
+                    data.auto_away = false;
+                    AutoAway::set(data, Presence::Online);
+                }
+            }
+            _ => (),
+        }
+        child.event(ctx, event, data, env)
+    }
+
+    fn lifecycle(&mut self, child: &mut W, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &AppState, env: &Env) {
+        if let LifeCycle::WidgetAdded = event {
+            self.timer = ctx.request_timer(AWAY_AFTER);
+        }
+        child.lifecycle(ctx, event, data, env)
+    }
+}
 
 /// A user interface that returns a layout of users currently connected to the server
 /// TODO: Make it work
 pub fn user_list_ui() -> impl Widget<AppState> {
 
-    // TODO: Populate form with a list of users connected to the server
+    // TODO: Let the user select recipients in the list
+
+    // Every user with a badge for their presence, e.g. "◐ alice (away: at lunch)"
+    let users = Label::dynamic(|data: &AppState, _env| {
+        if data.connected_users.is_empty() {
+            return "Nobody is connected".to_string();
+        }
+        data.connected_users.iter().map(ConnectedUsers::describe).collect::<Vec<String>>().join("\n")
+    })
+    .padding(8.0);
 
-    let col = Flex::column();
-    // let mut row = Flex::row();
+    let back_button = Button::new("Back to chat")
+        .on_click(|_ctx, data: &mut AppState, _env| data.current_view = 1)
+        .padding(3.0);
 
     // let check_box = LensWrap::new(Checkbox::new(""), AppState::connected_users);
-    
-    // row.add_child(Label::new(connected_user.user));
-    // row.add_child(Padding::new(5.0, check_box));
-    col.center()
-}
\ No newline at end of file
+
+    Flex::column()
+        .with_child(Label::new("Connected Users").padding(8.0))
+        .with_child(users)
+        .with_child(back_button)
+        .center()
+}
@@ -0,0 +1,392 @@
+/*
+    Encoder and decoder for length-prefixed JSON frames over async streams
+*/
+
+use std::fmt;
+
+use futures::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    stream::{self, BoxStream, StreamExt},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Largest frame body (in bytes) that will be encoded or accepted
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Size of the big-endian length prefix in front of every frame
+const LEN_PREFIX: usize = 4;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors that can occur while reading or writing frames
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying stream failed, or closed in the middle of a frame
+    Io(std::io::Error),
+    /// The frame body was not a valid frame
+    Json(serde_json::Error),
+    /// The frame body is longer than `MAX_FRAME_LEN`, or the limit given to the reader
+    FrameTooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Json(e) => write!(f, "malformed frame: {}", e),
+            Error::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the {} byte limit", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::FrameTooLarge { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+/// Encodes a frame into its length-prefixed wire representation
+pub fn encode<T: Serialize>(frame: &T) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(frame)?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err(Error::FrameTooLarge { len: body.len(), max: MAX_FRAME_LEN });
+    }
+
+    let mut buf = Vec::with_capacity(LEN_PREFIX + body.len());
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body);
+    Ok(buf)
+}
+
+/// Writes a single frame to the stream and flushes it
+pub async fn write_frame<W, T>(writer: &mut W, frame: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let buf = encode(frame)?;
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a single frame from the stream.
+/// Returns `Ok(None)` if the stream ended cleanly before the start of a frame.
+pub async fn read_frame<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    read_frame_with_limit(reader, MAX_FRAME_LEN).await
+}
+
+/// Reads a single frame from the stream, refusing bodies longer than `max_len` (or `MAX_FRAME_LEN`)
+/// before reading them: the body of a refused frame is left in the stream.
+pub async fn read_frame_with_limit<R, T>(reader: &mut R, max_len: usize) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let max_len = max_len.min(MAX_FRAME_LEN);
+    let mut len_buf = [0u8; LEN_PREFIX];
+
+    // An EOF before the first byte of a frame is a normal disconnect
+    if reader.read(&mut len_buf[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len_buf[1..]).await?;
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_len {
+        return Err(Error::FrameTooLarge { len, max: max_len });
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Turns a reader into a stream of decoded frames.
+/// The stream ends when the reader reaches EOF, or after an error the framing cannot survive
+/// (frames that are too large are skipped, see `frames_with_limit`).
+/// Unlike calling `read_frame` in a `select!`, polling this stream is cancel-safe:
+/// a partially read frame is kept until the next poll.
+pub fn frames<'a, R, T>(reader: R) -> BoxStream<'a, Result<T>>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+    T: DeserializeOwned + Send + 'a,
+{
+    frames_with_limit(reader, MAX_FRAME_LEN)
+}
+
+/// Like `frames`, refusing frames longer than `max_len` (or `MAX_FRAME_LEN`).
+/// A refused frame is skipped without being kept in memory: the stream yields `Error::FrameTooLarge`
+/// for it and goes on with the next frame.
+pub fn frames_with_limit<'a, R, T>(reader: R, max_len: usize) -> BoxStream<'a, Result<T>>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+    T: DeserializeOwned + Send + 'a,
+{
+    stream::unfold(Some(reader), move |reader| async move {
+        let mut reader = reader?;
+        match read_frame_with_limit(&mut reader, max_len).await {
+            Ok(Some(frame)) => Some((Ok(frame), Some(reader))),
+            Ok(None) => None,
+            Err(Error::FrameTooLarge { len, max }) => {
+                match io::copy((&mut reader).take(len as u64), &mut io::sink()).await {
+                    Ok(skipped) if skipped == len as u64 => Some((Err(Error::FrameTooLarge { len, max }), Some(reader))),
+                    Ok(_) => Some((Err(Error::Io(io::ErrorKind::UnexpectedEof.into())), None)),
+                    Err(e) => Some((Err(e.into()), None)),
+                }
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        features, BanTarget, ClientFrame, ErrorCode, HistoryMessage, Password, Presence, ResumeToken, Role, RoomInfo,
+        ServerFrame, BROADCAST, PROTOCOL_VERSION,
+    };
+    use futures::{executor::block_on, io::Cursor};
+
+    fn round_trip<T>(sent: Vec<T>) -> Vec<T>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        block_on(async {
+            let mut wire = Cursor::new(Vec::new());
+            for frame in &sent {
+                write_frame(&mut wire, frame).await.unwrap();
+            }
+            wire.set_position(0);
+            frames(wire).map(|frame| frame.unwrap()).collect().await
+        })
+    }
+
+    #[test]
+    fn client_frames_round_trip() {
+        let sent = vec![
+            ClientFrame::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![features::PEER_LIST.to_string()],
+            },
+            ClientFrame::Login { name: "**Server: admin".to_string(), password: None },
+            ClientFrame::Login { name: "alice".to_string(), password: Some(Password("p:w\"d".to_string())) },
+            ClientFrame::Register { name: "bob".to_string(), password: Password("hunter22".to_string()) },
+            ClientFrame::Resume { name: "bob".to_string(), token: ResumeToken("0123abcd".to_string()) },
+            ClientFrame::Message {
+                client_id: 1,
+                to: vec!["bob, the builder".to_string(), "carol:".to_string()],
+                msg: "hello: world\n**FIN".to_string(),
+            },
+            ClientFrame::Message { client_id: 2, to: vec![BROADCAST.to_string()], msg: String::new() },
+            ClientFrame::PeerListRequest,
+            ClientFrame::HistoryRequest { with: Some("bob".to_string()), before: Some(42), limit: 20 },
+            ClientFrame::HistoryRequest { with: None, before: None, limit: 20 },
+            ClientFrame::CreateRoom { room: "#rust".to_string() },
+            ClientFrame::JoinRoom { room: "#rust".to_string() },
+            ClientFrame::LeaveRoom { room: "#rust".to_string() },
+            ClientFrame::RoomListRequest,
+            ClientFrame::RoomMembersRequest { room: "#rust".to_string() },
+            ClientFrame::SetTopic { room: "#rust".to_string(), topic: Some("async: all the way".to_string()) },
+            ClientFrame::SetTopic { room: "#rust".to_string(), topic: None },
+            ClientFrame::Kick { name: "mallory".to_string(), reason: Some("spam".to_string()) },
+            ClientFrame::Ban { target: BanTarget::User("mallory".to_string()), duration: Some(3600), reason: None },
+            ClientFrame::Ban { target: BanTarget::Ip("::1".parse().unwrap()), duration: None, reason: None },
+            ClientFrame::Unban { target: BanTarget::Ip("10.0.0.1".parse().unwrap()) },
+            ClientFrame::Mute { name: "mallory".to_string(), duration: None },
+            ClientFrame::Unmute { name: "mallory".to_string() },
+            ClientFrame::SetRole { name: "bob".to_string(), role: Role::Moderator },
+            ClientFrame::MarkRead { with: "al:ice".to_string(), up_to: 42 },
+            ClientFrame::ReadReceipts { enabled: false },
+            ClientFrame::Typing { to: vec!["bob".to_string(), "#rust".to_string()], typing: true },
+            ClientFrame::SetPresence { presence: Presence::Busy, status: Some("in a meeting: back at 3".to_string()) },
+            ClientFrame::SetPresence { presence: Presence::Online, status: None },
+            ClientFrame::Rename { name: "alicia".to_string() },
+            ClientFrame::Ping,
+            ClientFrame::Pong,
+            ClientFrame::Disconnect,
+        ];
+        assert_eq!(round_trip(sent.clone()), sent);
+    }
+
+    #[test]
+    fn server_frames_round_trip() {
+        let sent = vec![
+            ServerFrame::Welcome { version: PROTOCOL_VERSION, features: Vec::new(), session_id: 7, max_message_size: None },
+            ServerFrame::Welcome {
+                version: PROTOCOL_VERSION,
+                features: vec![features::HEARTBEAT.to_string()],
+                session_id: 8,
+                max_message_size: Some(16384),
+            },
+            ServerFrame::Error { code: ErrorCode::IncompatibleVersion, msg: "too new".to_string() },
+            ServerFrame::Error { code: ErrorCode::MessageTooLarge, msg: "too long".to_string() },
+            ServerFrame::Error { code: ErrorCode::Kicked, msg: "bye".to_string() },
+            ServerFrame::Error { code: ErrorCode::RateLimited, msg: "slow down".to_string() },
+            ServerFrame::LoginRejected {
+                code: ErrorCode::NameTaken,
+                msg: "taken".to_string(),
+                suggestion: Some("al:ice2".to_string()),
+            },
+            ServerFrame::LoggedIn { name: "al:ice2".to_string(), resume_token: ResumeToken("0123abcd".to_string()) },
+            ServerFrame::Message {
+                id: 1,
+                timestamp: 1_711_000_000_000,
+                from: "al:ice".to_string(),
+                room: None,
+                msg: "**FIN".to_string(),
+                offline: true,
+            },
+            ServerFrame::Message {
+                id: 2,
+                timestamp: 1_711_000_000_001,
+                from: "bob".to_string(),
+                room: Some("#rust".to_string()),
+                msg: "hi room".to_string(),
+                offline: false,
+            },
+            ServerFrame::Sent { client_id: 3, id: 2, timestamp: 1_711_000_000_001 },
+            ServerFrame::Delivered { id: 2, recipient: "bob".to_string() },
+            ServerFrame::Read { by: "bob".to_string(), up_to: 2 },
+            ServerFrame::Typing { from: "al:ice".to_string(), room: Some("#rust".to_string()), typing: false },
+            ServerFrame::Presence { name: "al:ice".to_string(), presence: Presence::Away, status: Some("**".to_string()) },
+            ServerFrame::Presence { name: "bob".to_string(), presence: Presence::Offline, status: None },
+            ServerFrame::Renamed { from: "al:ice".to_string(), to: "alicia".to_string() },
+            ServerFrame::Queued { client_id: 3, recipient: "bob".to_string() },
+            ServerFrame::Undeliverable {
+                client_id: 1,
+                recipient: "carol:".to_string(),
+                reason: "carol: is not online".to_string(),
+            },
+            ServerFrame::Notice { msg: "New client joined: **".to_string() },
+            ServerFrame::PeerList { names: vec!["a:b".to_string(), "ünïcödé".to_string()] },
+            ServerFrame::PeerList { names: Vec::new() },
+            ServerFrame::History {
+                with: None,
+                messages: vec![HistoryMessage {
+                    id: 1,
+                    timestamp: 1_711_000_000_000,
+                    from: "al:ice".to_string(),
+                    to: vec!["bob".to_string()],
+                    msg: "**FIN".to_string(),
+                }],
+                has_more: true,
+            },
+            ServerFrame::RoomJoined {
+                room: "#rust".to_string(),
+                topic: None,
+                members: vec!["al:ice".to_string()],
+            },
+            ServerFrame::RoomLeft { room: "#rust".to_string() },
+            ServerFrame::RoomList {
+                rooms: vec![RoomInfo { name: "#rust".to_string(), topic: Some("**".to_string()), members: 2 }],
+            },
+            ServerFrame::RoomMembers { room: "#rust".to_string(), names: Vec::new() },
+            ServerFrame::RoomTopic { room: "#rust".to_string(), topic: None, by: "bob".to_string() },
+            ServerFrame::Ping,
+            ServerFrame::Pong,
+        ];
+        assert_eq!(round_trip(sent.clone()), sent);
+    }
+
+    #[test]
+    fn secrets_are_not_logged() {
+        let frame = ClientFrame::Register { name: "bob".to_string(), password: Password("hunter22".to_string()) };
+        assert!(!format!("{:?}", frame).contains("hunter22"));
+        assert!(String::from_utf8_lossy(&encode(&frame).unwrap()).contains(r#""password":"hunter22""#));
+
+        let frame = ServerFrame::LoggedIn { name: "bob".to_string(), resume_token: ResumeToken("0123abcd".to_string()) };
+        assert!(!format!("{:?}", frame).contains("0123abcd"));
+    }
+
+    #[test]
+    fn encoding_is_length_prefixed_json() {
+        let buf = encode(&ClientFrame::PeerListRequest).unwrap();
+        let body = br#"{"type":"peer_list_request"}"#;
+        assert_eq!(&buf[..LEN_PREFIX], &(body.len() as u32).to_be_bytes());
+        assert_eq!(&buf[LEN_PREFIX..], body);
+    }
+
+    #[test]
+    fn version_1_welcomes_are_unchanged() {
+        let frame = ServerFrame::Welcome { version: 1, features: Vec::new(), session_id: 7, max_message_size: None };
+        let body = br#"{"type":"welcome","version":1,"features":[],"session_id":7}"#;
+        assert_eq!(&encode(&frame).unwrap()[LEN_PREFIX..], body);
+        assert_eq!(round_trip(vec![frame.clone()]), vec![frame]);
+    }
+
+    #[test]
+    fn clean_eof_ends_the_stream() {
+        let frame: Option<ClientFrame> =
+            block_on(read_frame(&mut Cursor::new(Vec::new()))).unwrap();
+        assert!(frame.is_none());
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let mut buf = encode(&ClientFrame::Disconnect).unwrap();
+        buf.pop();
+        let result = block_on(read_frame::<_, ClientFrame>(&mut Cursor::new(buf)));
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected_before_reading_the_body() {
+        let buf = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes().to_vec();
+        let result = block_on(read_frame::<_, ClientFrame>(&mut Cursor::new(buf)));
+        assert!(matches!(result, Err(Error::FrameTooLarge { .. })));
+    }
+
+    #[test]
+    fn oversized_frames_are_skipped() {
+        let mut buf = encode(&ClientFrame::Message { client_id: 1, to: Vec::new(), msg: "x".repeat(100) }).unwrap();
+        buf.extend(encode(&ClientFrame::Disconnect).unwrap());
+
+        let results: Vec<Result<ClientFrame>> = block_on(frames_with_limit(Cursor::new(buf), 64).collect());
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0], Err(Error::FrameTooLarge { max: 64, .. })));
+        assert!(matches!(results[1], Ok(ClientFrame::Disconnect)));
+
+        // Unless the body is cut short
+        let mut buf = (1000u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(b"{}");
+        let results: Vec<Result<ClientFrame>> = block_on(frames_with_limit(Cursor::new(buf), 64).collect());
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn stream_stops_after_an_error() {
+        let mut buf = (5u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(b"nope!");
+        buf.extend(encode(&ClientFrame::Disconnect).unwrap());
+
+        let results: Vec<Result<ClientFrame>> = block_on(frames(Cursor::new(buf)).collect());
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], Err(Error::Json(_))));
+    }
+}
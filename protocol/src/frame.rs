@@ -0,0 +1,327 @@
+/*
+    The frames that make up a conversation between a client and the server
+*/
+
+use std::{fmt, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
+
+/// Recipient name that addresses every connected client
+pub const BROADCAST: &str = "*";
+
+/// First character of every room name, e.g. `#general`
+pub const ROOM_PREFIX: char = '#';
+
+/// Longest status text a user can set with their presence, in characters
+pub const MAX_STATUS_LEN: usize = 100;
+
+/// Returns true if the recipient name designates a room rather than a user
+pub fn is_room(name: &str) -> bool {
+    name.starts_with(ROOM_PREFIX)
+}
+
+/// Frames sent from a client to the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// Opens the handshake. Must be the first frame sent.
+    Hello { version: u32, capabilities: Vec<String> },
+    /// Sets the username of the client. Must be sent right after the handshake,
+    /// and again (e.g. with another name) if the server answers `LoginRejected`.
+    /// Registered names require their password; other names can be used as guests, without one.
+    Login { name: String, password: Option<Password> },
+    /// Registers a new account and logs in with it. Allowed instead of `Login`.
+    Register { name: String, password: Password },
+    /// Resumes the session of `name` after the connection dropped, without its password. Allowed instead of `Login`.
+    /// `token` is the one given in the last `LoggedIn`; the server then replays what was missed in the meantime.
+    Resume { name: String, token: ResumeToken },
+    /// A message for one or more recipients: users, rooms the client is a member of,
+    /// or `BROADCAST` for everyone. `client_id` is chosen by the client so that the server's answers can refer to the message.
+    Message { client_id: u64, to: Vec<String>, msg: String },
+    /// Asks the server for the names of every connected client
+    PeerListRequest,
+    /// Asks for up to `limit` of the most recent messages with ids lower than `before`
+    /// (or the very latest if `before` is None). With `with` set to a user name, only the
+    /// conversation with that user is replayed; set to `BROADCAST` or a room, only the messages addressed to it are.
+    HistoryRequest { with: Option<String>, before: Option<u64>, limit: u32 },
+    /// Creates a room and joins it
+    CreateRoom { room: String },
+    /// Joins an existing room, to receive the messages addressed to it
+    JoinRoom { room: String },
+    /// Leaves a room the client is a member of
+    LeaveRoom { room: String },
+    /// Asks the server for every room
+    RoomListRequest,
+    /// Asks the server for the members of a room
+    RoomMembersRequest { room: String },
+    /// Sets (or clears, with None) the topic of a room the client is a member of
+    SetTopic { room: String, topic: Option<String> },
+    /// Disconnects a connected user. Moderators and admins only, like the commands below.
+    Kick { name: String, reason: Option<String> },
+    /// Bans a user or an address, for `duration` seconds or for good, disconnecting them if they are connected
+    Ban { target: BanTarget, duration: Option<u64>, reason: Option<String> },
+    /// Lifts a ban
+    Unban { target: BanTarget },
+    /// Drops the messages of a user, for `duration` seconds or until `Unmute`
+    Mute { name: String, duration: Option<u64> },
+    /// Lets a muted user write again
+    Unmute { name: String },
+    /// Gives a role to a registered user. Admins only.
+    SetRole { name: String, role: Role },
+    /// The user has seen the direct messages `with` sent them, up to the message `up_to`.
+    /// The server tells `with` with `ServerFrame::Read`, if both of them share read receipts.
+    MarkRead { with: String, up_to: u64 },
+    /// Turns read receipts on or off for the rest of the session: users who do not share theirs
+    /// do not see those of others either. They are on by default for clients announcing `features::READ_RECEIPTS`.
+    ReadReceipts { enabled: bool },
+    /// The user started (`typing` set) or stopped writing a message to `to`, users or rooms.
+    /// Clients repeat the start every few seconds while the user keeps typing; the server forwards it without keeping it.
+    Typing { to: Vec<String>, typing: bool },
+    /// Sets the presence of the user, with an optional status text of up to `MAX_STATUS_LEN` characters, e.g. "at lunch".
+    /// `Presence::Offline` cannot be set, the server does that when the user leaves. Clients go `Away` on their own
+    /// after a while without any input from the user, and back `Online` when it comes back.
+    SetPresence { presence: Presence, status: Option<String> },
+    /// Changes the name of the user for the rest of the session, without reconnecting.
+    /// The new name follows the rules of `validate_name`, and must not be used by another connected user, nor registered.
+    /// The server answers `Renamed`, or an error if the name cannot be had.
+    Rename { name: String },
+    /// Checks that the server is still there; it answers `ServerFrame::Pong`
+    Ping,
+    /// Answers `ServerFrame::Ping`
+    Pong,
+    /// The client is about to close the connection
+    Disconnect,
+}
+
+/// Frames sent from the server to a client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// Accepts the handshake, answering `ClientFrame::Hello`.
+    /// `max_message_size` is the longest message text the server accepts, in bytes (None if it did not say).
+    /// It is only sent from protocol version 2 on.
+    Welcome {
+        version: u32,
+        features: Vec<String>,
+        session_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_message_size: Option<usize>,
+    },
+    /// The login succeeded and the client is now known as `name`.
+    /// `resume_token` lets the client resume this session with `ClientFrame::Resume` if the connection drops.
+    LoggedIn { name: String, resume_token: ResumeToken },
+    /// The login was refused; the client may send another `Login` on the same connection
+    LoginRejected { code: ErrorCode, msg: String, suggestion: Option<String> },
+    /// A request could not be served. Fatal errors are followed by the server closing the connection.
+    Error { code: ErrorCode, msg: String },
+    /// A message written by another user. `id` is the server's id for the message, and `timestamp` the server time
+    /// it was sent at, in milliseconds since the Unix epoch.
+    /// `room` is set if the message was addressed to a room rather than to the recipient.
+    /// `offline` is set if the message was kept by the server while the recipient was offline.
+    Message { id: u64, timestamp: i64, from: String, room: Option<String>, msg: String, offline: bool },
+    /// The server recorded the message the client sent as `client_id`, under `id`, at `timestamp`.
+    /// Ids increase with every message routed. Only sent to clients announcing `features::DELIVERY`.
+    Sent { client_id: u64, id: u64, timestamp: i64 },
+    /// The message `id` of the client was written to `recipient`.
+    /// Only sent to clients announcing `features::DELIVERY`.
+    Delivered { id: u64, recipient: String },
+    /// `by` has seen the direct messages the client sent them, up to the message `up_to`
+    Read { by: String, up_to: u64 },
+    /// `from` started or stopped writing to the client, or to `room` if set.
+    /// Only sent to clients announcing `features::TYPING`, which should forget a start after a few seconds without another.
+    Typing { from: String, room: Option<String>, typing: bool },
+    /// `name` logged in, changed their presence, or left (`Presence::Offline`).
+    /// Only sent to clients announcing `features::PRESENCE`, which are also told right after logging in
+    /// about every connected user who is not simply online.
+    Presence { name: String, presence: Presence, status: Option<String> },
+    /// `from` is now known as `to`: the client itself, answering `Rename`, or a user it shares a room or a conversation with.
+    /// Only sent to clients announcing `features::RENAME`; the others are sent a notice.
+    Renamed { from: String, to: String },
+    /// The message the client sent as `client_id` could not be delivered to `recipient`
+    Undeliverable { client_id: u64, recipient: String, reason: String },
+    /// `recipient` is offline; the message sent as `client_id` will be delivered when they log in again
+    Queued { client_id: u64, recipient: String },
+    /// A message generated by the server itself, e.g. "New client joined: alice"
+    Notice { msg: String },
+    /// Answer to `ClientFrame::PeerListRequest`
+    PeerList { names: Vec<String> },
+    /// Answer to `ClientFrame::HistoryRequest`, oldest message first.
+    /// `has_more` is set if older messages can be fetched with another request.
+    History { with: Option<String>, messages: Vec<HistoryMessage>, has_more: bool },
+    /// The client is now a member of `room`, answering `CreateRoom` or `JoinRoom`
+    RoomJoined { room: String, topic: Option<String>, members: Vec<String> },
+    /// The client is no longer a member of `room`, answering `LeaveRoom`
+    RoomLeft { room: String },
+    /// Answer to `ClientFrame::RoomListRequest`
+    RoomList { rooms: Vec<RoomInfo> },
+    /// Answer to `ClientFrame::RoomMembersRequest`
+    RoomMembers { room: String, names: Vec<String> },
+    /// `by` changed the topic of a room the client is a member of
+    RoomTopic { room: String, topic: Option<String>, by: String },
+    /// Checks that an idle client is still there; it must answer `ClientFrame::Pong`
+    Ping,
+    /// Answers `ClientFrame::Ping`
+    Pong,
+}
+
+/// A message replayed from the server's history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryMessage {
+    pub id: u64,
+    /// Server time the message was sent at, in milliseconds since the Unix epoch
+    pub timestamp: i64,
+    pub from: String,
+    pub to: Vec<String>,
+    pub msg: String,
+}
+
+/// A password sent during login. It is never printed by `Debug`, so frames can be logged safely.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Password(pub String);
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(***)")
+    }
+}
+
+/// A secret handed out by the server to resume a session. Like `Password`, it is never printed by `Debug`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ResumeToken(pub String);
+
+impl fmt::Debug for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResumeToken(***)")
+    }
+}
+
+/// What a user may do on the server. Each role may do everything the ones before it may.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    /// May kick, ban and mute users
+    Moderator,
+    /// May also give roles
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+/// Whether a user is around, as shown next to their name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    #[default]
+    Online,
+    /// Set by the user, or by their client after a while without input
+    Away,
+    /// Do not disturb
+    Busy,
+    /// Not connected
+    Offline,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Presence::Online => "online",
+            Presence::Away => "away",
+            Presence::Busy => "busy",
+            Presence::Offline => "offline",
+        })
+    }
+}
+
+/// Who a ban applies to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    /// Logging in with this name
+    User(String),
+    /// Connecting from this address
+    Ip(IpAddr),
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::User(name) => f.write_str(name),
+            BanTarget::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// A room as listed in `ServerFrame::RoomList`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub topic: Option<String>,
+    /// Number of members currently in the room
+    pub members: usize,
+}
+
+/// Machine readable reason carried by `ServerFrame::Error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The client speaks a protocol version the server does not support (fatal)
+    IncompatibleVersion,
+    /// The client sent a frame that is not allowed at this point of the conversation (fatal)
+    UnexpectedFrame,
+    /// Another connected client already uses the requested name, possibly spelled differently
+    NameTaken,
+    /// The requested name breaks the rules of `validate_name`
+    InvalidName,
+    /// The name is registered and the password is missing or wrong
+    AuthenticationFailed,
+    /// An account with the requested name already exists
+    AccountExists,
+    /// The password given to `Register` is too short
+    WeakPassword,
+    /// The server failed to serve the request, e.g. its storage is unavailable
+    Internal,
+    /// The named room does not exist
+    NoSuchRoom,
+    /// A room with the requested name already exists
+    RoomExists,
+    /// Room names must start with `ROOM_PREFIX`, followed by letters, digits, '-' or '_'
+    InvalidRoomName,
+    /// The request is only allowed to members of the room
+    NotInRoom,
+    /// The client is already a member of the room it tried to join
+    AlreadyInRoom,
+    /// The server already serves as many clients as it is configured to (fatal)
+    ServerFull,
+    /// The message is longer than the server accepts; it was not delivered
+    MessageTooLarge,
+    /// The session cannot be resumed, e.g. because it expired; the client must log in again
+    ResumeFailed,
+    /// The request needs a role the client does not have, or targets a user with a role as high as its own
+    PermissionDenied,
+    /// The named user is not connected, or not registered
+    NoSuchUser,
+    /// A moderator disconnected the client (fatal)
+    Kicked,
+    /// The client's name or address is banned (fatal)
+    Banned,
+    /// The client is muted; its message was not delivered
+    Muted,
+    /// The client sends faster than the server allows; its frame was dropped
+    RateLimited,
+    /// The client kept sending too fast and was disconnected (fatal)
+    Flooding,
+    /// The presence cannot be set by the client, or its status text is longer than `MAX_STATUS_LEN`
+    InvalidPresence,
+}
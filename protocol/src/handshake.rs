@@ -0,0 +1,69 @@
+/*
+    Protocol versioning and the features negotiated during the hello/welcome handshake
+
+    The handshake is the first exchange on every connection:
+        client -> server   ClientFrame::Hello { version, capabilities }
+        server -> client   ServerFrame::Welcome { version, features, session_id }
+                      or   ServerFrame::Error { code: IncompatibleVersion, .. } and the connection is closed
+    The fields these frames have in version 1 must never change between protocol versions,
+    so that a client and a server of any version can always tell each other apart.
+    Later versions may only add optional fields, which the server leaves out for older clients.
+
+    Versions:
+        1   the first one
+        2   Welcome tells the longest message the server accepts, in `max_message_size`
+*/
+
+/// Version of the protocol implemented by this crate
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version this crate can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Returns true if a peer announcing `version` can be served
+pub fn is_supported(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// Names of the optional features a peer can announce during the handshake
+pub mod features {
+    /// Listing the connected users with `PeerListRequest`
+    pub const PEER_LIST: &str = "peer_list";
+    /// Replaying past messages with `HistoryRequest`
+    pub const HISTORY: &str = "history";
+    /// Named rooms with `CreateRoom`, `JoinRoom` and friends
+    pub const ROOMS: &str = "rooms";
+    /// Registered accounts protected by a password, with `Register`
+    pub const ACCOUNTS: &str = "accounts";
+    /// Resuming a dropped session with `Resume`, without missing any message
+    pub const RESUME: &str = "resume";
+    /// `Ping` and `Pong` heartbeats. The server pings idle clients announcing it,
+    /// and disconnects them if they stay silent for several heartbeats.
+    pub const HEARTBEAT: &str = "heartbeat";
+    /// Roles, and the `Kick`, `Ban`, `Mute` commands of moderators
+    pub const MODERATION: &str = "moderation";
+    /// Acknowledgements of the messages a client sends: `Sent` once the server recorded them,
+    /// then `Delivered` as they are written to each recipient
+    pub const DELIVERY: &str = "delivery";
+    /// Read receipts of direct messages, with `MarkRead`, `ReadReceipts` and `Read`
+    pub const READ_RECEIPTS: &str = "read_receipts";
+    /// "alice is typing" notices, with `Typing`
+    pub const TYPING: &str = "typing";
+    /// Online, away and busy users, with `SetPresence` and `Presence`
+    pub const PRESENCE: &str = "presence";
+    /// Changing names without reconnecting, with `Rename` and `Renamed`
+    pub const RENAME: &str = "rename";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_known_versions_are_supported() {
+        assert!(is_supported(PROTOCOL_VERSION));
+        assert!(is_supported(MIN_PROTOCOL_VERSION));
+        assert!(!is_supported(MIN_PROTOCOL_VERSION - 1));
+        assert!(!is_supported(PROTOCOL_VERSION + 1));
+    }
+}
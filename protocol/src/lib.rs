@@ -0,0 +1,39 @@
+/*
+    The wire protocol shared by the server and the client is defined here
+
+    Every message exchanged over the TCP stream is a frame. A frame is a
+    4-byte big-endian length followed by that many bytes of UTF-8 JSON:
+
+        +----------------+---------------------------------------+
+        | length: u32 BE | body: JSON object, `length` bytes long |
+        +----------------+---------------------------------------+
+
+    The JSON body is an object tagged with a "type" field naming the variant,
+    e.g. {"type":"message","to":["bob"],"msg":"hi: there **"}.
+    Clients only ever send `ClientFrame`s and servers only ever send `ServerFrame`s.
+    Because the payload is length-prefixed and JSON-escaped, usernames and messages
+    may contain any character (including ':', ',', '**' and newlines) without
+    corrupting the conversation. Usernames do follow rules of their own though, see `names.rs`.
+
+    Frames larger than `MAX_FRAME_LEN` are rejected by both the encoder and the decoder.
+    Readers can set a lower limit (see `frames_with_limit`); the server announces the longest message it accepts.
+
+    Every connection starts with a version handshake, described in `handshake.rs`.
+    The stream can optionally be encrypted with TLS, see `tls.rs`.
+*/
+
+mod codec;
+mod frame;
+mod handshake;
+mod names;
+pub mod tls;
+
+pub use codec::{
+    encode, frames, frames_with_limit, read_frame, read_frame_with_limit, write_frame, Error, Result, MAX_FRAME_LEN,
+};
+pub use frame::{
+    is_room, BanTarget, ClientFrame, ErrorCode, HistoryMessage, Password, Presence, ResumeToken, Role, RoomInfo,
+    ServerFrame, BROADCAST, MAX_STATUS_LEN, ROOM_PREFIX,
+};
+pub use handshake::{features, is_supported, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+pub use names::{name_key, normalize_name, validate_name, NameError, MAX_NAME_LEN, RESERVED_PREFIXES};
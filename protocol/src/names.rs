@@ -0,0 +1,122 @@
+/*
+    The rules usernames follow, shared by the server, which enforces them at login,
+    and the client, which checks names before sending them.
+
+    A name is 1 to `MAX_NAME_LEN` characters long, made of letters, digits, marks, '-', '_' and '.'.
+    That rules out ':', ',' and whitespace, which would be ambiguous when names are typed in front of
+    a message, and the prefixes the clients reserve for themselves, like "**" for server notices.
+    Names are compared without regard to case or Unicode form: "Alice" and "ALICE" are the same user,
+    and so are "é" written as one code point or as an 'e' followed by an accent.
+*/
+
+use std::fmt;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::frame::BROADCAST;
+
+/// Longest name allowed, in characters
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Names may not start with these: "**" marks server notices, '#' (`ROOM_PREFIX`) rooms and '/' commands
+pub const RESERVED_PREFIXES: &[&str] = &["**", "#", "/"];
+
+/// Why a name is refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    TooLong,
+    /// The name starts with one of `RESERVED_PREFIXES`
+    ReservedPrefix(&'static str),
+    /// The name designates everyone
+    Reserved,
+    Whitespace,
+    /// The name contains a character that is not allowed
+    Forbidden(char),
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "The name is empty"),
+            NameError::TooLong => write!(f, "The name is longer than {} characters", MAX_NAME_LEN),
+            NameError::ReservedPrefix(prefix) => write!(f, "Names may not start with '{}'", prefix),
+            NameError::Reserved => write!(f, "The name {} is reserved", BROADCAST),
+            NameError::Whitespace => write!(f, "Names may not contain spaces"),
+            NameError::Forbidden(c) => write!(f, "Names may not contain '{}'", c),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// Puts a name in its canonical Unicode form (NFC); names are checked and stored in that form
+pub fn normalize_name(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Checks a name against the rules above. The name should be normalized first.
+pub fn validate_name(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(NameError::TooLong);
+    }
+    if let Some(prefix) = RESERVED_PREFIXES.iter().find(|prefix| name.starts_with(*prefix)) {
+        return Err(NameError::ReservedPrefix(prefix));
+    }
+    if name == BROADCAST {
+        return Err(NameError::Reserved);
+    }
+    if name.contains(char::is_whitespace) {
+        return Err(NameError::Whitespace);
+    }
+    match name.chars().find(|c| !is_allowed(*c)) {
+        Some(c) => Err(NameError::Forbidden(c)),
+        None => Ok(()),
+    }
+}
+
+/// The key names are compared by: two names with the same key belong to the same user
+pub fn name_key(name: &str) -> String {
+    name.nfkc().flat_map(char::to_lowercase).nfkc().collect()
+}
+
+/// Letters, digits and the marks that combine with them, plus a few separators
+fn is_allowed(c: char) -> bool {
+    c.is_alphanumeric() || unicode_normalization::char::is_combining_mark(c) || matches!(c, '-' | '_' | '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_follow_the_rules() {
+        for name in ["alice", "Bob_2", "jean-luc.picard", "Zoë", "李小龙", "e\u{301}"] {
+            assert_eq!(validate_name(name), Ok(()), "{}", name);
+        }
+        assert_eq!(validate_name(""), Err(NameError::Empty));
+        assert_eq!(validate_name(&"a".repeat(MAX_NAME_LEN)), Ok(()));
+        assert_eq!(validate_name(&"é".repeat(MAX_NAME_LEN + 1)), Err(NameError::TooLong));
+        assert_eq!(validate_name("**Server"), Err(NameError::ReservedPrefix("**")));
+        assert_eq!(validate_name("#general"), Err(NameError::ReservedPrefix("#")));
+        assert_eq!(validate_name("*"), Err(NameError::Reserved));
+        assert_eq!(validate_name("al ice"), Err(NameError::Whitespace));
+        assert_eq!(validate_name("al\u{a0}ice"), Err(NameError::Whitespace));
+        assert_eq!(validate_name("al:ice"), Err(NameError::Forbidden(':')));
+        assert_eq!(validate_name("alice,bob"), Err(NameError::Forbidden(',')));
+        assert_eq!(validate_name("bob*"), Err(NameError::Forbidden('*')));
+    }
+
+    #[test]
+    fn names_are_compared_regardless_of_case_and_form() {
+        assert_eq!(normalize_name("Zoe\u{308}"), "Zoë");
+        assert_eq!(name_key("Alice"), name_key("aLICE"));
+        assert_eq!(name_key("Zoe\u{308}"), name_key("ZOË"));
+        // Compatibility forms, e.g. full width letters, are the same too
+        assert_eq!(name_key("ａｌｉｃｅ"), name_key("alice"));
+        assert_ne!(name_key("alice"), name_key("alice2"));
+    }
+}
@@ -0,0 +1,263 @@
+/*
+    Optional TLS transport shared by the server and the client
+
+    The frames are exchanged exactly the same way over TLS as over plain TCP: TLS only wraps the stream.
+    The server loads its certificate chain and private key from PEM files (see `acceptor`).
+    The client decides which server certificates it trusts (see `ServerTrust`):
+      - `Ca`: certificates issued for the server's name by one of the CAs of a PEM file
+      - `Pinned`: the one certificate with a known SHA-256 fingerprint, e.g. a self-signed one
+      - `FirstUse`: the certificate seen on the first connection to each server (trust on first use),
+        which is remembered in a file and must not change afterwards
+*/
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use futures_rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    },
+};
+
+pub use futures_rustls::{
+    client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor, TlsConnector,
+};
+
+/// Which server certificates a client accepts
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerTrust {
+    /// Certificates issued for the server's name by one of the CAs in this PEM file
+    Ca(PathBuf),
+    /// Only the certificate with this SHA-256 fingerprint (hex, colons optional)
+    Pinned(String),
+    /// The certificate seen on the first connection to a server, remembered in this file
+    FirstUse(PathBuf),
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Wraps an error with the file it is about
+fn file_error(path: &Path, error: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), error))
+}
+
+/// Builds the server side of TLS from a PEM certificate chain and a PEM private key
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| file_error(cert_path, e))?;
+    if certs.is_empty() {
+        return Err(file_error(cert_path, "no certificate found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| file_error(key_path, e))?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| file_error(cert_path, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds the client side of TLS, accepting the server certificates described by `trust`
+pub fn connector(trust: &ServerTrust) -> io::Result<TlsConnector> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+
+    let config = match trust {
+        ServerTrust::Ca(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path).map_err(|e| file_error(path, e))? {
+                let cert = cert.map_err(|e| file_error(path, e))?;
+                roots.add(cert).map_err(|e| file_error(path, e))?;
+            }
+            if roots.is_empty() {
+                return Err(file_error(path, "no certificate found"));
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        ServerTrust::Pinned(fingerprint) => {
+            let verifier = FingerprintVerifier::new(KnownServers::Pinned(normalize(fingerprint)));
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth()
+        }
+        ServerTrust::FirstUse(path) => {
+            let known = KnownServers::load(path)?;
+            let verifier = FingerprintVerifier::new(known);
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth()
+        }
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Parses the host a client connects to into the name its certificate must be issued for
+pub fn server_name(host: &str) -> io::Result<ServerName<'static>> {
+    ServerName::try_from(host.to_string()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Returns the SHA-256 fingerprint of a certificate, as colon separated uppercase hex
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = ::ring::digest::digest(&::ring::digest::SHA256, cert.as_ref());
+    digest.as_ref().iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
+
+/// Puts a fingerprint typed by a user in the format returned by `fingerprint`
+fn normalize(fingerprint: &str) -> String {
+    let hex: Vec<char> = fingerprint.chars().filter(|c| c.is_ascii_hexdigit()).map(|c| c.to_ascii_uppercase()).collect();
+    hex.chunks(2).map(|pair| pair.iter().collect::<String>()).collect::<Vec<_>>().join(":")
+}
+
+/// The certificates a `FingerprintVerifier` accepts
+#[derive(Debug)]
+enum KnownServers {
+    /// The same certificate for every server
+    Pinned(String),
+    /// One certificate per server name, learnt on first use and saved in `path`
+    FirstUse { path: PathBuf, fingerprints: Mutex<HashMap<String, String>> },
+}
+
+impl KnownServers {
+    /// Reads the known servers file, one "name fingerprint" pair per line
+    fn load(path: &Path) -> io::Result<KnownServers> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(file_error(path, e)),
+        };
+        let mut fingerprints = HashMap::new();
+        for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let (name, fingerprint) = line
+                .trim()
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| file_error(path, format!("line {}: expected 'name fingerprint'", number + 1)))?;
+            fingerprints.insert(name.to_string(), normalize(fingerprint));
+        }
+        Ok(KnownServers::FirstUse { path: path.to_path_buf(), fingerprints: Mutex::new(fingerprints) })
+    }
+
+    /// Checks the fingerprint of the certificate presented by `name`,
+    /// remembering it if this is the first connection to that server
+    fn check(&self, name: &str, fingerprint: String) -> Result<(), rustls::Error> {
+        match self {
+            KnownServers::Pinned(pinned) if *pinned == fingerprint => Ok(()),
+            KnownServers::Pinned(pinned) => Err(rustls::Error::General(format!(
+                "the certificate of {} (fingerprint {}) does not match the pinned fingerprint {}",
+                name, fingerprint, pinned
+            ))),
+            KnownServers::FirstUse { path, fingerprints } => {
+                let mut fingerprints = fingerprints.lock().unwrap();
+                match fingerprints.get(name) {
+                    Some(known) if *known == fingerprint => Ok(()),
+                    Some(known) => Err(rustls::Error::General(format!(
+                        "the certificate of {} changed from {} to {}; remove it from {} if this is expected",
+                        name,
+                        known,
+                        fingerprint,
+                        path.display()
+                    ))),
+                    None => {
+                        let remembered = OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)
+                            .and_then(|mut file| writeln!(file, "{} {}", name, fingerprint));
+                        if let Err(e) = remembered {
+                            return Err(rustls::Error::General(format!("cannot remember {}: {}", name, file_error(path, e))));
+                        }
+                        fingerprints.insert(name.to_string(), fingerprint);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Accepts server certificates by fingerprint instead of by CA, for self-signed setups.
+/// The handshake signatures are still checked against the accepted certificate.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    known: KnownServers,
+    provider: Arc<CryptoProvider>,
+}
+
+impl FingerprintVerifier {
+    fn new(known: KnownServers) -> FingerprintVerifier {
+        FingerprintVerifier { known, provider: provider() }
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.known.check(&server_name.to_str(), fingerprint(end_entity))?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_are_normalized() {
+        assert_eq!(normalize("ab:cd:0f"), "AB:CD:0F");
+        assert_eq!(normalize("abcd0F"), "AB:CD:0F");
+        assert_eq!(normalize(" AB CD 0f "), "AB:CD:0F");
+    }
+
+    #[test]
+    fn first_use_remembers_each_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("known_servers");
+
+        let known = KnownServers::load(&path).unwrap();
+        assert!(known.check("chat.example", "AA:BB".to_string()).is_ok());
+        assert!(known.check("chat.example", "AA:BB".to_string()).is_ok());
+        assert!(known.check("chat.example", "CC:DD".to_string()).is_err());
+
+        // The fingerprint is kept for the next run
+        let known = KnownServers::load(&path).unwrap();
+        assert!(known.check("chat.example", "CC:DD".to_string()).is_err());
+        assert!(known.check("other.example", "CC:DD".to_string()).is_ok());
+    }
+}
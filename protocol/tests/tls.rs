@@ -0,0 +1,131 @@
+/*
+    End to end TLS test: a local CA issues the server certificate, and clients
+    exchange frames with the server using each of the trust modes.
+*/
+
+use std::{fs, path::Path};
+
+use async_std::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::{AsyncReadExt, StreamExt};
+use protocol::{
+    tls::{self, ServerTrust, TlsAcceptor},
+    ClientFrame, ResumeToken, ServerFrame,
+};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+/// Files written by `generate_pki`
+struct Pki {
+    ca_cert: std::path::PathBuf,
+    server_cert: std::path::PathBuf,
+    server_key: std::path::PathBuf,
+    /// Fingerprint of the server certificate
+    fingerprint: String,
+}
+
+/// Creates a CA and a certificate it issues for "localhost"
+fn generate_pki(dir: &Path) -> Pki {
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let server = CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .signed_by(&server_key, &ca, &ca_key)
+        .unwrap();
+
+    let pki = Pki {
+        ca_cert: dir.join("ca.pem"),
+        server_cert: dir.join("server.pem"),
+        server_key: dir.join("server.key"),
+        fingerprint: tls::fingerprint(server.der()),
+    };
+    fs::write(&pki.ca_cert, ca.pem()).unwrap();
+    fs::write(&pki.server_cert, server.pem()).unwrap();
+    fs::write(&pki.server_key, server_key.serialize_pem()).unwrap();
+    pki
+}
+
+/// Accepts TLS connections, answering every login with `LoggedIn`
+async fn serve(listener: TcpListener, acceptor: TlsAcceptor) {
+    let mut incoming = listener.incoming();
+    while let Some(Ok(stream)) = incoming.next().await {
+        let acceptor = acceptor.clone();
+        task::spawn(async move {
+            // Failed handshakes are expected, some clients do not trust the server
+            let Ok(stream) = acceptor.accept(stream).await else { return };
+            let (reader, mut writer) = stream.split();
+            let mut frames = protocol::frames::<_, ClientFrame>(reader);
+            while let Some(Ok(ClientFrame::Login { name, .. })) = frames.next().await {
+                protocol::write_frame(&mut writer, &ServerFrame::LoggedIn { name, resume_token: ResumeToken("token".to_string()) }).await.unwrap();
+            }
+        });
+    }
+}
+
+/// Connects to the server with the given trust and logs in
+async fn login(port: u16, trust: &ServerTrust) -> std::io::Result<ServerFrame> {
+    let connector = tls::connector(trust)?;
+    let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let stream = connector.connect(tls::server_name("localhost")?, stream).await?;
+    let (reader, mut writer) = stream.split();
+
+    let login = ClientFrame::Login { name: "alice".to_string(), password: None };
+    protocol::write_frame(&mut writer, &login).await.unwrap();
+    let answer = protocol::frames::<_, ServerFrame>(reader).next().await;
+    Ok(answer.expect("the server closed the connection").unwrap())
+}
+
+#[test]
+fn frames_go_through_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    let pki = generate_pki(dir.path());
+    let logged_in = ServerFrame::LoggedIn { name: "alice".to_string(), resume_token: ResumeToken("token".to_string()) };
+
+    task::block_on(async {
+        let acceptor = tls::acceptor(&pki.server_cert, &pki.server_key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        task::spawn(serve(listener, acceptor));
+
+        // Certificates issued by the local CA are trusted
+        assert_eq!(login(port, &ServerTrust::Ca(pki.ca_cert.clone())).await.unwrap(), logged_in);
+
+        // ...but not by another CA
+        let other = tempfile::tempdir().unwrap();
+        let other_pki = generate_pki(other.path());
+        assert!(login(port, &ServerTrust::Ca(other_pki.ca_cert)).await.is_err());
+
+        // Pinning accepts exactly the pinned certificate, whatever the format of the fingerprint
+        let pin = pki.fingerprint.replace(':', "").to_lowercase();
+        assert_eq!(login(port, &ServerTrust::Pinned(pin)).await.unwrap(), logged_in);
+        assert!(login(port, &ServerTrust::Pinned(other_pki.fingerprint.clone())).await.is_err());
+
+        // Trust on first use remembers the certificate
+        let known = dir.path().join("known_servers");
+        let first_use = ServerTrust::FirstUse(known.clone());
+        assert_eq!(login(port, &first_use).await.unwrap(), logged_in);
+        assert_eq!(login(port, &first_use).await.unwrap(), logged_in);
+        assert_eq!(fs::read_to_string(&known).unwrap(), format!("localhost {}\n", pki.fingerprint));
+
+        // ...and refuses another one for the same server
+        fs::write(&known, format!("localhost {}\n", other_pki.fingerprint)).unwrap();
+        assert!(login(port, &first_use).await.is_err());
+    });
+}
+
+#[test]
+fn bad_server_files_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let pki = generate_pki(dir.path());
+    let missing = dir.path().join("missing.pem");
+
+    let error = tls::acceptor(&missing, &pki.server_key).err().unwrap();
+    assert!(error.to_string().contains("missing.pem"));
+    // A certificate is not a key
+    assert!(tls::acceptor(&pki.server_cert, &pki.server_cert).is_err());
+}
@@ -0,0 +1,304 @@
+/*
+    Registered accounts
+
+    Users may register their name with a password, after which nobody can log in
+    with that name without the password. Names that are not registered can still be used by guests.
+    Passwords are only kept as salted argon2 hashes (in the PHC string format), in a JSON file
+    mapping each name to its account. Accounts also hold the role of their user: guests are plain users,
+    and registered users can be made moderators or admins (see `moderation.rs`). The file is rewritten through a temporary file, so that a
+    crash never leaves it half written. Names are registered regardless of case: once "alice" is taken, so is "Alice".
+
+    Hashing is slow on purpose, so it runs on the blocking thread pool instead of the executor.
+*/
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use async_std::task;
+use chrono::Utc;
+use protocol::{name_key, ErrorCode, Role};
+use serde::{Deserialize, Serialize};
+
+/// Shortest password accepted when registering
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// A registered account as saved in the accounts file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Account {
+    /// Salted argon2 hash of the password, in the PHC string format
+    hash: String,
+    /// When the account was registered, in milliseconds since the Unix epoch
+    created: i64,
+    #[serde(default)]
+    role: Role,
+}
+
+/// Why a login or a registration was refused
+#[derive(Debug)]
+pub enum AccountError {
+    /// The name is registered and the password is missing or wrong
+    AuthenticationFailed(String),
+    AlreadyExists(String),
+    WeakPassword,
+    NoSuchAccount(String),
+    /// The accounts could not be read, saved or hashed
+    Internal(String),
+}
+
+impl AccountError {
+    /// The code reported to the client in `ServerFrame::LoginRejected`
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AccountError::AuthenticationFailed(_) => ErrorCode::AuthenticationFailed,
+            AccountError::AlreadyExists(_) => ErrorCode::AccountExists,
+            AccountError::WeakPassword => ErrorCode::WeakPassword,
+            AccountError::NoSuchAccount(_) => ErrorCode::NoSuchUser,
+            AccountError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::AuthenticationFailed(name) => write!(f, "Wrong or missing password for {}", name),
+            AccountError::AlreadyExists(name) => write!(f, "The name {} is already registered", name),
+            AccountError::WeakPassword => write!(f, "Passwords must be at least {} characters long", MIN_PASSWORD_LEN),
+            AccountError::NoSuchAccount(name) => write!(f, "{} is not a registered user", name),
+            AccountError::Internal(_) => write!(f, "Accounts are unavailable, try again later"),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+type Result<T> = std::result::Result<T, AccountError>;
+
+/// Handle on the registered accounts, shared by every connection
+#[derive(Clone)]
+pub struct Accounts {
+    path: PathBuf,
+    accounts: Arc<Mutex<HashMap<String, Account>>>,
+}
+
+impl Accounts {
+    /// Loads the accounts file, which is created on the first registration
+    pub fn open(path: &Path) -> std::result::Result<Accounts, Box<dyn std::error::Error + Send + Sync>> {
+        let accounts = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Accounts {
+            path: path.to_path_buf(),
+            accounts: Arc::new(Mutex::new(accounts)),
+        })
+    }
+
+    /// Returns the spelling of the registered account with the same name as `name`, if any,
+    /// since names differing only in case or Unicode form belong to the same user
+    pub fn registered_name(&self, name: &str) -> Option<String> {
+        find(&self.accounts.lock().unwrap(), name)
+    }
+
+    /// Returns the role of a user, however their name is spelled; guests are plain users
+    pub fn role(&self, name: &str) -> Role {
+        let accounts = self.accounts.lock().unwrap();
+        find(&accounts, name).map_or(Role::User, |registered| accounts[&registered].role)
+    }
+
+    /// Gives a role to a registered user and saves it to the accounts file
+    pub fn set_role(&self, name: &str, role: Role) -> Result<()> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let registered = find(&accounts, name).ok_or_else(|| AccountError::NoSuchAccount(name.to_string()))?;
+        let previous = std::mem::replace(&mut accounts.get_mut(&registered).unwrap().role, role);
+        if let Err(e) = save(&self.path, &accounts) {
+            accounts.get_mut(&registered).unwrap().role = previous;
+            return Err(AccountError::Internal(e.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Moves an account, with its password and role, to the new name of its user and saves the accounts file.
+    /// Guests have nothing to move.
+    pub fn rename(&self, old: &str, new: &str) -> Result<()> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(registered) = find(&accounts, old) else {
+            return Ok(());
+        };
+        if let Some(taken) = find(&accounts, new).filter(|taken| *taken != registered) {
+            return Err(AccountError::AlreadyExists(taken));
+        }
+        let account = accounts.remove(&registered).unwrap();
+        accounts.insert(new.to_string(), account);
+        if let Err(e) = save(&self.path, &accounts) {
+            let account = accounts.remove(new).unwrap();
+            accounts.insert(registered, account);
+            return Err(AccountError::Internal(e.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Checks the password of a login. Guests (names that are not registered) need no password.
+    pub async fn authenticate(&self, name: &str, password: Option<&str>) -> Result<()> {
+        let hash = {
+            let accounts = self.accounts.lock().unwrap();
+            match find(&accounts, name) {
+                None => return Ok(()),
+                Some(registered) => accounts[&registered].hash.clone(),
+            }
+        };
+        let Some(password) = password else {
+            return Err(AccountError::AuthenticationFailed(name.to_string()));
+        };
+
+        let password = password.to_string();
+        let matches = task::spawn_blocking(move || -> Result<bool> {
+            let hash = PasswordHash::new(&hash).map_err(|e| AccountError::Internal(e.to_string()))?;
+            Ok(Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        })
+        .await?;
+
+        if matches {
+            Ok(())
+        } else {
+            Err(AccountError::AuthenticationFailed(name.to_string()))
+        }
+    }
+
+    /// Registers a new account and saves it to the accounts file
+    pub async fn register(&self, name: &str, password: &str) -> Result<()> {
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AccountError::WeakPassword);
+        }
+        if let Some(registered) = self.registered_name(name) {
+            return Err(AccountError::AlreadyExists(registered));
+        }
+
+        let password = password.to_string();
+        let hash = task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|e| AccountError::Internal(e.to_string()))
+        })
+        .await?;
+
+        // Someone may have registered the same name while we were hashing
+        let mut accounts = self.accounts.lock().unwrap();
+        if let Some(registered) = find(&accounts, name) {
+            return Err(AccountError::AlreadyExists(registered));
+        }
+        accounts.insert(name.to_string(), Account { hash, created: Utc::now().timestamp_millis(), role: Role::User });
+        if let Err(e) = save(&self.path, &accounts) {
+            accounts.remove(name);
+            return Err(AccountError::Internal(e.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name an account is saved under, for a name spelled in any case or Unicode form
+fn find(accounts: &HashMap<String, Account>, name: &str) -> Option<String> {
+    let key = name_key(name);
+    accounts.keys().find(|registered| name_key(registered) == key).cloned()
+}
+
+/// Replaces the accounts file with the given accounts
+fn save(path: &Path, accounts: &HashMap<String, Account>) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(accounts)?)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_then_authenticate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.json");
+
+        task::block_on(async {
+            let accounts = Accounts::open(&path).unwrap();
+            assert!(accounts.authenticate("alice", None).await.is_ok());
+            assert!(matches!(accounts.register("alice", "short").await, Err(AccountError::WeakPassword)));
+
+            accounts.register("alice", "correct horse").await.unwrap();
+            assert!(matches!(
+                accounts.register("alice", "battery staple").await,
+                Err(AccountError::AlreadyExists(_))
+            ));
+            assert!(matches!(
+                accounts.register("ALICE", "battery staple").await,
+                Err(AccountError::AlreadyExists(name)) if name == "alice"
+            ));
+            assert_eq!(accounts.registered_name("Alice").as_deref(), Some("alice"));
+            // Guests may still use any other name
+            assert!(accounts.authenticate("bob", None).await.is_ok());
+            assert_eq!(accounts.registered_name("bob"), None);
+        });
+
+        // The account survives a restart, without the password in clear
+        assert!(!fs::read_to_string(&path).unwrap().contains("correct horse"));
+        task::block_on(async {
+            let accounts = Accounts::open(&path).unwrap();
+            assert_eq!(accounts.registered_name("alice").as_deref(), Some("alice"));
+            assert!(accounts.authenticate("alice", Some("correct horse")).await.is_ok());
+            assert!(matches!(
+                accounts.authenticate("alice", Some("wrong horse")).await,
+                Err(AccountError::AuthenticationFailed(_))
+            ));
+            assert!(matches!(accounts.authenticate("alice", None).await, Err(AccountError::AuthenticationFailed(_))));
+        });
+    }
+
+    #[test]
+    fn roles_are_kept_with_the_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.json");
+
+        let accounts = Accounts::open(&path).unwrap();
+        task::block_on(accounts.register("alice", "correct horse")).unwrap();
+        assert_eq!(accounts.role("alice"), Role::User);
+        accounts.set_role("Alice", Role::Moderator).unwrap();
+        // However the name is spelled, it is the same user
+        assert_eq!(accounts.role("ALICE"), Role::Moderator);
+        // Only registered users can be given a role
+        assert!(matches!(accounts.set_role("bob", Role::Admin), Err(AccountError::NoSuchAccount(_))));
+        assert_eq!(accounts.role("bob"), Role::User);
+
+        assert_eq!(Accounts::open(&path).unwrap().role("alice"), Role::Moderator);
+    }
+
+    #[test]
+    fn accounts_follow_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.json");
+
+        let accounts = Accounts::open(&path).unwrap();
+        task::block_on(accounts.register("alice", "correct horse")).unwrap();
+        task::block_on(accounts.register("bob", "battery staple")).unwrap();
+        accounts.set_role("alice", Role::Moderator).unwrap();
+        assert!(matches!(accounts.rename("alice", "Bob"), Err(AccountError::AlreadyExists(_))));
+        accounts.rename("alice", "al").unwrap();
+        // Guests have no account to move
+        accounts.rename("carol", "caz").unwrap();
+
+        let accounts = Accounts::open(&path).unwrap();
+        assert_eq!(accounts.registered_name("alice"), None);
+        assert_eq!(accounts.registered_name("caz"), None);
+        assert_eq!(accounts.role("al"), Role::Moderator);
+        assert!(task::block_on(accounts.authenticate("al", Some("correct horse"))).is_ok());
+    }
+}
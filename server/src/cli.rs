@@ -0,0 +1,280 @@
+/*
+    Command line interface of the server
+
+    Without a subcommand the server starts listening for clients.
+    Its settings are read from the file given with `--config` (see `config.rs`),
+    and each of them can be overridden by the matching flag, e.g. `--port 4000`.
+    The `query` subcommand is an admin tool that searches the message history and exits, e.g.
+        server --store sqlite:history.db query --user alice --since 2024-03-21 --text hello
+    The `role` subcommand gives a role to a registered user, e.g. to name the first admin:
+        server role alice admin
+*/
+
+use std::{net::IpAddr, path::PathBuf};
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use protocol::Role;
+
+use crate::{
+    config::{Config, TlsConfig},
+    outbox::Overflow,
+    store::{HistoryQuery, MessageStore, StoreSpec},
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Parser, Debug)]
+#[command(about = "Asynchronous chat server")]
+pub struct Cli {
+    /// Read the settings from this TOML file; the flags below take precedence over it
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Print a configuration file holding the default settings and exit
+    #[arg(long)]
+    pub print_default_config: bool,
+
+    /// Address to listen on, may be repeated [default: 127.0.0.1]
+    #[arg(long)]
+    pub bind: Vec<IpAddr>,
+
+    /// Port to listen on [default: 1632]
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Most clients connected at the same time [default: 1000]
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+
+    /// Longest message accepted, in bytes [default: 16384]
+    #[arg(long)]
+    pub max_message_size: Option<usize>,
+
+    /// Seconds of silence after which an idle client is pinged [default: 30]
+    #[arg(long)]
+    pub heartbeat_interval: Option<u64>,
+
+    /// Heartbeats a client may miss before it is disconnected [default: 3]
+    #[arg(long)]
+    pub heartbeat_misses: Option<u32>,
+
+    /// Frames a client may send per second, on average [default: 5]
+    #[arg(long)]
+    pub messages_per_second: Option<f64>,
+
+    /// Bytes of message text a client may send per second, on average [default: 32768]
+    #[arg(long)]
+    pub bytes_per_second: Option<f64>,
+
+    /// Frames dropped for coming too fast before the client is disconnected [default: 10]
+    #[arg(long)]
+    pub flood_strikes: Option<u32>,
+
+    /// Seconds between the shutdown notice sent to the clients and the server closing their connections [default: 5]
+    #[arg(long)]
+    pub shutdown_grace: Option<u64>,
+
+    /// Most frames waiting to be written to a single client [default: 1024]
+    #[arg(long)]
+    pub queue_capacity: Option<usize>,
+
+    /// What happens when a client does not read fast enough and its queue is full [default: disconnect]
+    #[arg(long)]
+    pub queue_overflow: Option<Overflow>,
+
+    /// Where the message history is kept: file:PATH or sqlite:PATH [default: file:history.jsonl]
+    #[arg(long, global = true)]
+    pub store: Option<StoreSpec>,
+
+    /// File holding the registered accounts and their password hashes [default: accounts.json]
+    #[arg(long, global = true)]
+    pub accounts: Option<PathBuf>,
+
+    /// File holding the banned names and addresses [default: bans.json]
+    #[arg(long)]
+    pub bans: Option<PathBuf>,
+
+    /// How much to log: off, error, warn, info, debug or trace [default: info]
+    #[arg(long)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Message of the day, shown to every client when they log in
+    #[arg(long)]
+    pub motd: Option<String>,
+
+    /// Encrypt connections with TLS, using this PEM certificate chain (requires --tls-key)
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate (requires --tls-cert)
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Search the message history and print the matching messages
+    Query {
+        /// Only messages sent by or addressed to this user
+        #[arg(long)]
+        user: Option<String>,
+        /// Only messages containing this text
+        #[arg(long)]
+        text: Option<String>,
+        /// Only messages sent at or after this time (YYYY-MM-DD or RFC 3339, UTC)
+        #[arg(long, value_parser = parse_time)]
+        since: Option<i64>,
+        /// Only messages sent before this time (YYYY-MM-DD or RFC 3339, UTC)
+        #[arg(long, value_parser = parse_time)]
+        until: Option<i64>,
+        /// Only print the most recent N matching messages
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Give a role to a registered user: user, moderator or admin.
+    /// Stop the server first, it would overwrite the change.
+    Role {
+        name: String,
+        #[arg(value_parser = parse_role)]
+        role: Role,
+    },
+}
+
+impl Cli {
+    /// Merges the settings: the defaults, overridden by the configuration file, overridden by the flags.
+    /// The result is validated.
+    pub fn config(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if !self.bind.is_empty() {
+            config.bind = self.bind.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(max_clients) = self.max_clients {
+            config.max_clients = max_clients;
+        }
+        if let Some(max_message_size) = self.max_message_size {
+            config.max_message_size = max_message_size;
+        }
+        if let Some(heartbeat_interval) = self.heartbeat_interval {
+            config.heartbeat_interval = heartbeat_interval;
+        }
+        if let Some(heartbeat_misses) = self.heartbeat_misses {
+            config.heartbeat_misses = heartbeat_misses;
+        }
+        if let Some(messages_per_second) = self.messages_per_second {
+            config.messages_per_second = messages_per_second;
+        }
+        if let Some(bytes_per_second) = self.bytes_per_second {
+            config.bytes_per_second = bytes_per_second;
+        }
+        if let Some(flood_strikes) = self.flood_strikes {
+            config.flood_strikes = flood_strikes;
+        }
+        if let Some(shutdown_grace) = self.shutdown_grace {
+            config.shutdown_grace = shutdown_grace;
+        }
+        if let Some(queue_capacity) = self.queue_capacity {
+            config.queue_capacity = queue_capacity;
+        }
+        if let Some(queue_overflow) = self.queue_overflow {
+            config.queue_overflow = queue_overflow;
+        }
+        if let Some(store) = &self.store {
+            config.store = store.clone();
+        }
+        if let Some(accounts) = &self.accounts {
+            config.accounts = accounts.clone();
+        }
+        if let Some(bans) = &self.bans {
+            config.bans = bans.clone();
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(motd) = &self.motd {
+            config.motd = Some(motd.clone());
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            config.tls = Some(TlsConfig { cert: cert.clone(), key: key.clone() });
+        }
+
+        config.validate().map_err(|e| match &self.config {
+            Some(path) => format!("{}: {}", path.display(), e),
+            None => e.to_string(),
+        })?;
+        Ok(config)
+    }
+}
+
+/// Parses a date (midnight UTC) or an RFC 3339 time into milliseconds since the Unix epoch
+fn parse_time(s: &str) -> std::result::Result<i64, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+        return Ok(Utc.from_utc_datetime(&midnight).timestamp_millis());
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|time| time.timestamp_millis())
+        .map_err(|_| format!("expected YYYY-MM-DD or an RFC 3339 time, got '{}'", s))
+}
+
+/// Parses the name of a role
+fn parse_role(s: &str) -> std::result::Result<Role, String> {
+    match s {
+        "user" => Ok(Role::User),
+        "moderator" => Ok(Role::Moderator),
+        "admin" => Ok(Role::Admin),
+        _ => Err(format!("expected user, moderator or admin, got '{}'", s)),
+    }
+}
+
+/// Runs the `query` admin command, printing one message per line
+pub fn print_history(store: &dyn MessageStore, query: &HistoryQuery) -> Result<()> {
+    for message in store.query(query)? {
+        let time = Utc
+            .timestamp_millis_opt(message.timestamp)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        println!("[{}] {} {} -> {}: {}", message.id, time, message.from, message.to.join(", "), message.msg);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_accept_dates_and_rfc3339() {
+        assert_eq!(parse_time("1970-01-02"), Ok(86_400_000));
+        assert_eq!(parse_time("1970-01-01T00:00:01+00:00"), Ok(1_000));
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(&path, "port = 4000\nmax_clients = 10\nmotd = \"Hello\"").unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = Cli::parse_from(["server", "--config", path, "--max-clients", "20"]).config().unwrap();
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.max_clients, 20);
+        assert_eq!(config.motd.as_deref(), Some("Hello"));
+
+        let error = Cli::parse_from(["server", "--config", path, "--port", "0"]).config().unwrap_err();
+        assert!(error.to_string().contains("`port`"));
+    }
+}
@@ -0,0 +1,302 @@
+/*
+    Server configuration
+
+    Every setting has a default, can be set in a TOML file (`--config server.toml`),
+    and can be overridden on the command line (see `cli.rs`). `--print-default-config`
+    prints `DEFAULT_CONFIG`, a commented file holding every default, as a starting point.
+    Settings are validated once merged; errors name the offending key.
+*/
+
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::LevelFilter;
+use protocol::MAX_FRAME_LEN;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    outbox::Overflow,
+    ratelimit::{RateLimits, BURST_SECONDS},
+    store::StoreSpec,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Longest `max_message_size` allowed, leaving room in the frame for escaping and the other fields
+pub const MAX_MESSAGE_SIZE_LIMIT: usize = (MAX_FRAME_LEN - FRAME_OVERHEAD) / MAX_ESCAPED_LEN;
+
+/// Room left in a frame for the fields around the message text, e.g. its recipients
+pub const FRAME_OVERHEAD: usize = 4096;
+
+/// Longest JSON encoding of a byte of message text: a control character is escaped as `\u00XX`
+const MAX_ESCAPED_LEN: usize = 6;
+
+/// Smallest `queue_capacity` allowed: a user logging in is sent their mailbox and the messages they missed at once
+pub const MIN_QUEUE_CAPACITY: usize = 256;
+
+/// The configuration file matching `Config::default()`
+pub const DEFAULT_CONFIG: &str = r#"# Configuration of the chat server
+# Every setting can also be given on the command line, e.g. --port 1632 or --max-clients 100
+
+# Addresses to listen on; use "0.0.0.0" or "::" to accept clients from other machines
+bind = ["127.0.0.1"]
+# Port to listen on
+port = 1632
+# Most clients connected at the same time; the others are turned away
+max_clients = 1000
+# Longest message accepted, in bytes
+max_message_size = 16384
+# Seconds of silence after which an idle client is pinged
+heartbeat_interval = 30
+# Heartbeats a client may miss before it is disconnected
+heartbeat_misses = 3
+# Frames a client may send per second, on average; short bursts of twice as many are let through
+messages_per_second = 5
+# Bytes of message text a client may send per second, on average
+bytes_per_second = 32768
+# Frames dropped for coming too fast before the client is disconnected; one is forgiven every minute
+flood_strikes = 10
+# Seconds between the shutdown notice sent to the clients and the server closing their connections
+shutdown_grace = 5
+# Most frames waiting to be written to a single client
+queue_capacity = 1024
+# What happens when a client does not read fast enough and its queue is full:
+# "drop-oldest" drops its oldest frame, "disconnect" disconnects it,
+# "block" stops reading from the clients writing to it until it catches up
+queue_overflow = "disconnect"
+# Where the message history is kept: "file:PATH" or "sqlite:PATH"
+store = "file:history.jsonl"
+# File holding the registered accounts and their password hashes
+accounts = "accounts.json"
+# File holding the banned names and addresses
+bans = "bans.json"
+# How much the server logs: "off", "error", "warn", "info", "debug" or "trace"
+log_level = "info"
+# Message of the day, shown to every client when they log in
+# motd = "Welcome to the chat!"
+
+# Encrypt connections with TLS, using PEM encoded files
+# [tls]
+# cert = "cert.pem"
+# key = "key.pem"
+"#;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub max_clients: usize,
+    /// In bytes
+    pub max_message_size: usize,
+    /// In seconds
+    pub heartbeat_interval: u64,
+    pub heartbeat_misses: u32,
+    pub messages_per_second: f64,
+    pub bytes_per_second: f64,
+    pub flood_strikes: u32,
+    /// In seconds
+    pub shutdown_grace: u64,
+    /// In frames
+    pub queue_capacity: usize,
+    pub queue_overflow: Overflow,
+    pub store: StoreSpec,
+    pub accounts: PathBuf,
+    pub bans: PathBuf,
+    pub log_level: LevelFilter,
+    pub motd: Option<String>,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key of the certificate
+    pub key: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            port: 1632,
+            max_clients: 1000,
+            max_message_size: 16 * 1024,
+            heartbeat_interval: 30,
+            heartbeat_misses: 3,
+            messages_per_second: 5.0,
+            bytes_per_second: 32.0 * 1024.0,
+            flood_strikes: 10,
+            shutdown_grace: 5,
+            queue_capacity: 1024,
+            queue_overflow: Overflow::Disconnect,
+            store: StoreSpec::File("history.jsonl".into()),
+            accounts: "accounts.json".into(),
+            bans: "bans.json".into(),
+            log_level: LevelFilter::Info,
+            motd: None,
+            tls: None,
+        }
+    }
+}
+
+/// A setting with an invalid value
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub key: &'static str,
+    pub msg: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid value for `{}`: {}", self.key, self.msg)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the settings of a configuration file; those it does not mention keep their default
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Parses the settings of a configuration file
+    pub fn parse(text: &str) -> std::result::Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Longest frame read from a client: a message of `max_message_size` bytes, however it is escaped
+    pub fn max_frame_len(&self) -> usize {
+        (self.max_message_size * MAX_ESCAPED_LEN + FRAME_OVERHEAD).min(MAX_FRAME_LEN)
+    }
+
+    /// How long a client may stay silent before it is pinged
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval)
+    }
+
+    /// What each client may send
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            messages_per_second: self.messages_per_second,
+            bytes_per_second: self.bytes_per_second,
+            strikes: self.flood_strikes,
+        }
+    }
+
+    /// How long the clients are warned before the server shuts down
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace)
+    }
+
+    /// Checks the settings that their types alone do not constrain
+    pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+        let error = |key, msg: &str| Err(ConfigError { key, msg: msg.to_string() });
+
+        if self.bind.is_empty() {
+            return error("bind", "at least one address is needed");
+        }
+        if self.port == 0 {
+            return error("port", "must be between 1 and 65535");
+        }
+        if self.max_clients == 0 {
+            return error("max_clients", "must be at least 1");
+        }
+        if !(1..=MAX_MESSAGE_SIZE_LIMIT).contains(&self.max_message_size) {
+            return error("max_message_size", &format!("must be between 1 and {}", MAX_MESSAGE_SIZE_LIMIT));
+        }
+        if self.heartbeat_interval == 0 {
+            return error("heartbeat_interval", "must be at least 1 second");
+        }
+        if self.heartbeat_misses == 0 {
+            return error("heartbeat_misses", "must be at least 1");
+        }
+        // Fractions are fine, e.g. one frame every other second
+        if !(self.messages_per_second > 0.0 && self.messages_per_second.is_finite()) {
+            return error("messages_per_second", "must be a positive number");
+        }
+        // The longest message must fit in a burst
+        if !(self.bytes_per_second.is_finite() && self.bytes_per_second * BURST_SECONDS >= self.max_message_size as f64) {
+            return error("bytes_per_second", "must be at least half of max_message_size");
+        }
+        if self.flood_strikes == 0 {
+            return error("flood_strikes", "must be at least 1");
+        }
+        if self.queue_capacity < MIN_QUEUE_CAPACITY {
+            return error("queue_capacity", &format!("must be at least {}", MIN_QUEUE_CAPACITY));
+        }
+        if self.motd.as_ref().is_some_and(|motd| motd.len() > self.max_message_size) {
+            return error("motd", "must not be longer than max_message_size");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_file_matches_the_defaults() {
+        let config = Config::parse(DEFAULT_CONFIG).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn settings_override_the_defaults() {
+        let config = Config::parse(
+            r#"
+            bind = ["0.0.0.0", "::1"]
+            port = 4000
+            store = "sqlite:chat.db"
+            log_level = "DEBUG"
+            motd = "Hi!"
+            queue_overflow = "drop-oldest"
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind, vec!["0.0.0.0".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.store, StoreSpec::Sqlite("chat.db".into()));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.motd.as_deref(), Some("Hi!"));
+        assert_eq!(config.queue_overflow, Overflow::DropOldest);
+        assert_eq!(config.tls.unwrap().key, PathBuf::from("key.pem"));
+        assert_eq!(config.max_clients, Config::default().max_clients);
+    }
+
+    #[test]
+    fn errors_name_the_offending_key() {
+        let parse_error = |text: &str| Config::parse(text).unwrap_err().to_string();
+        assert!(parse_error("prot = 1").contains("prot"));
+        assert!(parse_error("port = 70000").contains("port = 70000"));
+        assert!(parse_error("bind = [\"localhost\"]").contains("bind"));
+        assert!(parse_error("store = \"redis:x\"").contains("expected file:PATH or sqlite:PATH"));
+        assert!(parse_error("queue_overflow = \"wait\"").contains("queue_overflow"));
+        assert!(parse_error("[tls]\ncert = \"cert.pem\"").contains("key"));
+
+        let invalid_key = |text: &str| Config::parse(text).unwrap().validate().unwrap_err().key;
+        assert_eq!(invalid_key("port = 0"), "port");
+        assert_eq!(invalid_key("bind = []"), "bind");
+        assert_eq!(invalid_key("max_clients = 0"), "max_clients");
+        assert_eq!(invalid_key("max_message_size = 0"), "max_message_size");
+        assert_eq!(invalid_key("heartbeat_interval = 0"), "heartbeat_interval");
+        assert_eq!(invalid_key("messages_per_second = 0"), "messages_per_second");
+        assert_eq!(invalid_key("bytes_per_second = 100"), "bytes_per_second");
+        assert_eq!(invalid_key("flood_strikes = 0"), "flood_strikes");
+        assert_eq!(invalid_key("queue_capacity = 10"), "queue_capacity");
+        assert_eq!(invalid_key("max_message_size = 4\nmotd = \"Hello!\""), "motd");
+    }
+}
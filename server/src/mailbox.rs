@@ -0,0 +1,251 @@
+/*
+    Per-user mailboxes keeping the messages sent to users while they are offline
+
+    A mailbox is opened when a user logs in, under their name however it is spelled, and closed
+    some time after they leave: the broker keeps those of registered users for the time to live
+    of the messages, and those of guests only while their session can be resumed.
+    A closed mailbox is forgotten with the messages left in it, so the boxes do not pile up.
+    Each mailbox is bounded (the oldest message is dropped when it is full) and messages
+    expire after a time to live. When the user logs in again with the same name,
+    the mailbox is flushed to them in the order the messages were received.
+*/
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use protocol::{name_key, ServerFrame};
+
+/// A message waiting for its recipient to come back online
+struct Queued {
+    id: u64,
+    timestamp: i64,
+    from: String,
+    /// Room the message was written in, None for a direct message
+    room: Option<String>,
+    msg: String,
+    queued_at: Instant,
+}
+
+/// The messages kept for one user
+struct Mailbox {
+    queue: VecDeque<Queued>,
+    /// When the mailbox is forgotten; None while its user is online
+    closes: Option<Instant>,
+}
+
+pub struct Mailboxes {
+    capacity: usize,
+    ttl: Duration,
+    boxes: HashMap<String, Mailbox>,
+}
+
+impl Mailboxes {
+    /// Creates mailboxes holding at most `capacity` messages each, for at most `ttl`
+    pub fn new(capacity: usize, ttl: Duration) -> Mailboxes {
+        Mailboxes {
+            capacity,
+            ttl,
+            boxes: HashMap::new(),
+        }
+    }
+
+    /// Opens a mailbox for a user that just came online, if they do not have one yet,
+    /// and keeps it until they leave
+    pub fn register(&mut self, name: &str) {
+        self.mailbox(name).closes = None;
+    }
+
+    /// Opens a mailbox for an offline user, e.g. a registered user whose mailbox was forgotten,
+    /// keeping it until the messages pushed now expire
+    pub fn open(&mut self, name: &str) {
+        let closes = Instant::now() + self.ttl;
+        let mailbox = self.mailbox(name);
+        if let Some(previous) = mailbox.closes {
+            mailbox.closes = Some(previous.max(closes));
+        }
+    }
+
+    /// Forgets the mailbox of a user who just left after `keep`, unless it is opened again
+    pub fn close(&mut self, name: &str, keep: Duration) {
+        if let Some(mailbox) = self.boxes.get_mut(&name_key(name)) {
+            mailbox.closes = Some(Instant::now() + keep);
+        }
+    }
+
+    /// Moves the mailbox of a user to their new name, replacing any mailbox of that name, see `holds`
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(mailbox) = self.boxes.remove(&name_key(old)) {
+            self.boxes.insert(name_key(new), mailbox);
+        }
+    }
+
+    /// Returns true if messages that have not expired are kept for `name`
+    pub fn holds(&mut self, name: &str) -> bool {
+        let now = Instant::now();
+        self.forget_closed(now);
+        self.boxes
+            .get(&name_key(name))
+            .is_some_and(|mailbox| mailbox.queue.iter().any(|queued| now.duration_since(queued.queued_at) < self.ttl))
+    }
+
+    fn mailbox(&mut self, name: &str) -> &mut Mailbox {
+        let now = Instant::now();
+        self.boxes.entry(name_key(name)).or_insert_with(|| Mailbox { queue: VecDeque::new(), closes: Some(now) })
+    }
+
+    /// Forgets the mailboxes that are closed
+    fn forget_closed(&mut self, now: Instant) {
+        self.boxes.retain(|_, mailbox| mailbox.closes.is_none_or(|closes| now < closes));
+    }
+
+    /// Keeps a message for an offline user, dropping their oldest message if the mailbox is full.
+    /// `timestamp` is the server time the message was sent at, and `room` the room it was written in if any.
+    /// Returns false if the user has no mailbox.
+    pub fn push(
+        &mut self,
+        name: &str,
+        id: u64,
+        timestamp: i64,
+        from: String,
+        room: Option<String>,
+        msg: String,
+    ) -> bool {
+        let now = Instant::now();
+        self.forget_closed(now);
+        let ttl = self.ttl;
+        let Some(Mailbox { queue, .. }) = self.boxes.get_mut(&name_key(name)) else {
+            return false;
+        };
+
+        queue.retain(|queued| now.duration_since(queued.queued_at) < ttl);
+        while !queue.is_empty() && queue.len() >= self.capacity {
+            queue.pop_front();
+        }
+        if self.capacity > 0 {
+            queue.push_back(Queued { id, timestamp, from, room, msg, queued_at: now });
+        }
+        true
+    }
+
+    /// Empties a user's mailbox, returning the messages that have not expired
+    /// in the order they were received, marked as delivered while offline
+    pub fn take(&mut self, name: &str) -> Vec<ServerFrame> {
+        let now = Instant::now();
+        self.forget_closed(now);
+        let Some(Mailbox { queue, .. }) = self.boxes.get_mut(&name_key(name)) else {
+            return Vec::new();
+        };
+
+        queue
+            .drain(..)
+            .filter(|queued| now.duration_since(queued.queued_at) < self.ttl)
+            .map(|queued| ServerFrame::Message {
+                id: queued.id,
+                timestamp: queued.timestamp,
+                from: queued.from,
+                room: queued.room,
+                msg: queued.msg,
+                offline: true,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(frames: Vec<ServerFrame>) -> Vec<String> {
+        frames
+            .into_iter()
+            .map(|frame| match frame {
+                ServerFrame::Message { msg, offline: true, .. } => msg,
+                frame => panic!("unexpected frame {:?}", frame),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_flushed_in_order_once() {
+        let mut mailboxes = Mailboxes::new(10, Duration::from_secs(60));
+        mailboxes.register("bob");
+        assert!(mailboxes.push("bob", 1, 0, "alice".into(), None, "one".into()));
+        assert!(mailboxes.push("Bob", 2, 0, "carol".into(), None, "two".into()));
+
+        assert_eq!(texts(mailboxes.take("bob")), vec!["one", "two"]);
+        assert!(mailboxes.take("bob").is_empty());
+        assert!(mailboxes.push("bob", 3, 0, "alice".into(), None, "three".into()));
+    }
+
+    #[test]
+    fn unknown_users_have_no_mailbox() {
+        let mut mailboxes = Mailboxes::new(10, Duration::from_secs(60));
+        assert!(!mailboxes.push("ghost", 1, 0, "alice".into(), None, "boo".into()));
+        assert!(mailboxes.take("ghost").is_empty());
+    }
+
+    #[test]
+    fn full_mailbox_drops_the_oldest_message() {
+        let mut mailboxes = Mailboxes::new(2, Duration::from_secs(60));
+        mailboxes.register("bob");
+        for (id, msg) in [(1, "one"), (2, "two"), (3, "three")] {
+            mailboxes.push("bob", id, 0, "alice".into(), None, msg.into());
+        }
+        assert_eq!(texts(mailboxes.take("bob")), vec!["two", "three"]);
+    }
+
+    #[test]
+    fn expired_messages_are_not_delivered() {
+        let mut mailboxes = Mailboxes::new(10, Duration::ZERO);
+        mailboxes.register("bob");
+        mailboxes.push("bob", 1, 0, "alice".into(), None, "stale".into());
+        assert!(mailboxes.take("bob").is_empty());
+    }
+
+    #[test]
+    fn closed_mailboxes_are_forgotten() {
+        let mut mailboxes = Mailboxes::new(10, Duration::from_secs(60));
+        mailboxes.register("guest");
+        mailboxes.close("guest", Duration::ZERO);
+        assert!(!mailboxes.push("guest", 1, 0, "alice".into(), None, "gone".into()));
+        assert!(mailboxes.boxes.is_empty());
+
+        // A registered user gets theirs back when a message comes for them, and keeps it
+        mailboxes.open("bob");
+        assert!(mailboxes.push("bob", 2, 0, "alice".into(), None, "later".into()));
+        mailboxes.register("bob");
+        mailboxes.close("bob", Duration::from_secs(60));
+        assert_eq!(texts(mailboxes.take("Bob")), vec!["later"]);
+    }
+
+    #[test]
+    fn room_messages_keep_their_room() {
+        let mut mailboxes = Mailboxes::new(10, Duration::from_secs(60));
+        mailboxes.register("bob");
+        mailboxes.push("bob", 1, 0, "alice".into(), Some("#rust".into()), "one".into());
+        mailboxes.push("bob", 2, 0, "alice".into(), None, "two".into());
+        let rooms: Vec<Option<String>> = mailboxes
+            .take("bob")
+            .into_iter()
+            .map(|frame| match frame {
+                ServerFrame::Message { room, .. } => room,
+                frame => panic!("unexpected frame {:?}", frame),
+            })
+            .collect();
+        assert_eq!(rooms, vec![Some("#rust".to_string()), None]);
+    }
+
+    #[test]
+    fn mailboxes_follow_renames() {
+        let mut mailboxes = Mailboxes::new(10, Duration::from_secs(60));
+        mailboxes.register("bob");
+        mailboxes.push("bob", 1, 0, "alice".into(), None, "one".into());
+        assert!(mailboxes.holds("Bob"));
+        mailboxes.rename("bob", "robert");
+        assert!(!mailboxes.holds("bob"));
+        assert!(!mailboxes.push("bob", 2, 0, "alice".into(), None, "two".into()));
+        assert_eq!(texts(mailboxes.take("Robert")), vec!["one"]);
+    }
+}
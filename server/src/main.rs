@@ -3,208 +3,1186 @@
     Date:   3/21/2024
 
     This Rust code implements a simple peer-to-peer network using asynchronous I/O and channels for message passing.
-    The `accept_loop` function asynchronously accepts incoming TCP connections on the specified address, spawning connection tasks for each accepted connection and managing a broker loop for handling peer connections and messages.
-    The `connection_loop` function handles communication with a client, forwarding messages to the broker and notifying it about new peer connections.
+    The `accept_loop` function asynchronously accepts incoming TCP connections on the configured addresses (see `config.rs`), spawning connection tasks for each accepted connection and managing a broker loop for handling peer connections and messages.
+    On SIGINT or SIGTERM it stops accepting, warns the clients, and closes every connection once their queued messages are written.
+    The `connection_loop` function handles communication with a client, decoding the frames it sends (see the `protocol` crate), forwarding messages to the broker and notifying it about new peer connections.
+    Every connection starts with the `handshake` function, which rejects clients speaking an incompatible protocol version.
+    The client then logs in; registered names are protected by a password (see `accounts.rs`).
+    Moderators can kick, ban and mute users (see `moderation.rs`); banned names and addresses are turned away.
+    Clients beyond the configured maximum are turned away, and messages longer than the configured size are refused.
+    Clients sending too fast are throttled, and disconnected if they keep at it (see `ratelimit.rs`).
+    Idle clients are pinged, and disconnected if they stop answering (see `features::HEARTBEAT`).
     The `connection_writer_loop` function continuously writes messages from a channel to a TCP stream, listening for a shutdown signal to exit gracefully.
+    Each peer's channel is a bounded queue (see `outbox.rs`), so that a client that stops reading cannot make the server grow without limit.
+    Connections can optionally be encrypted with TLS (see `protocol::tls`), in which case they are split into a reading and a writing half.
     The `broker_loop` function is an asynchronous event loop for managing peer connections and message forwarding, with support for disconnecting peers and cleanup.
+    Users can gather in named rooms (see `rooms.rs`); messages addressed to a room reach its members only.
+    Messages for users that went offline are kept in their mailbox (see `mailbox.rs`) until they log in again.
+    A client whose connection dropped can resume its session for a while (see `sessions.rs`), without missing any message.
+    Every routed message is recorded in a persistent history (see `store.rs`), which admins can search with the `query` subcommand (see `cli.rs`).
     The code uses the `futures` and `async_std` crates for asynchronous programming, and it defines custom event types to represent different actions within the peer-to-peer network.
     Note: The code includes error handling and logging for any encountered errors.
 
 */
 use std::{
-    collections::hash_map::{Entry, HashMap},
-    sync::Arc,
+    collections::{
+        hash_map::{Entry, HashMap},
+        BTreeSet, HashSet,
+    },
+    net::{Shutdown, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use futures::{channel::mpsc, select, FutureExt, SinkExt};
+use async_signal::{Signal, Signals};
+use chrono::Utc;
+use clap::Parser;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{Fuse, Shared},
+    select,
+    stream::{self, BoxStream},
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, SinkExt,
+};
+use log::{debug, error, info, warn};
 
 use async_std::{
-    io::BufReader,
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    future,
+    net::{TcpListener, TcpStream},
     prelude::*,
+    sync::Mutex,
     task,
 };
 
+mod accounts;
+use accounts::{AccountError, Accounts};
+
+mod cli;
+use cli::{Cli, Command};
+
+mod config;
+use config::Config;
+
+mod mailbox;
+use mailbox::Mailboxes;
+
+mod moderation;
+use moderation::{describe_duration, Ban, Bans, ModCommand, Mutes};
+
+mod ratelimit;
+use ratelimit::{AccountThrottles, Limiter, Verdict};
+
+mod outbox;
+use outbox::{Outbox, OutboxReceiver, QueueMetrics, QueueStats, SendError, Space};
+
+mod rooms;
+use rooms::{RoomCommand, Rooms};
+
+mod sessions;
+use sessions::{Sessions, Suspended};
+
+mod store;
+use store::{History, HistoryQuery, MessageStore, StoredMessage, Viewer};
+
+use protocol::{
+    features, tls::TlsAcceptor, is_room, name_key, normalize_name, validate_name, BanTarget, ClientFrame, ErrorCode,
+    HistoryMessage, Presence, ResumeToken, ServerFrame, BROADCAST, MAX_NAME_LEN, MAX_STATUS_LEN, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 type Sender<T> = mpsc::UnboundedSender<T>;
 type Receiver<T> = mpsc::UnboundedReceiver<T>;
 
+/// Reading half of a client connection, over plain TCP or TLS
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Writing half of a client connection, shared by the connection task and its writer task
+type Writer = Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
 #[derive(Debug)]
 enum Void {}
 
+/// Name of a connected peer, shared by the broker and the peer's writer task so that the writer follows renames
+type PeerName = Arc<std::sync::Mutex<String>>;
+
+/// Resolves once the server starts shutting down; every connection holds a clone
+type ShutdownSignal = Shared<oneshot::Receiver<()>>;
+
+/// Optional protocol features announced to clients in the welcome frame
+const SERVER_FEATURES: &[&str] = &[
+    features::PEER_LIST,
+    features::HISTORY,
+    features::ROOMS,
+    features::ACCOUNTS,
+    features::RESUME,
+    features::HEARTBEAT,
+    features::MODERATION,
+    features::DELIVERY,
+    features::READ_RECEIPTS,
+    features::TYPING,
+    features::PRESENCE,
+    features::RENAME,
+];
+
+/// Most messages replayed in answer to a single history request
+const MAX_HISTORY_BATCH: usize = 100;
+
+/// Most messages kept for a single offline user
+const MAILBOX_CAPACITY: usize = 100;
+
+/// How long messages are kept for an offline user
+const MAILBOX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long the session of a user whose connection dropped can be resumed
+const RESUME_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How long the writers are given to flush their queues once the server shuts down
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the depth of the outbound queues is logged, if it changed
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a flooding client is given to take in its last frames
+const HANG_UP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Counter handing out a unique id to every session
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
 fn main() -> Result<()> {
-    task::block_on(accept_loop("127.0.0.1:1632"))
+    let cli = Cli::parse();
+    if cli.print_default_config {
+        print!("{}", config::DEFAULT_CONFIG);
+        return Ok(());
+    }
+    let config = cli.config()?;
+    env_logger::Builder::new().filter_level(config.log_level).init();
+    let store = config.store.open()?;
+
+    match cli.command {
+        Some(Command::Query { user, text, since, until, limit }) => {
+            cli::print_history(&*store, &HistoryQuery { since, until, user, text, limit, ..Default::default() })
+        }
+        Some(Command::Role { name, role }) => {
+            Accounts::open(&config.accounts)?.set_role(&name, role).map_err(|e| e.to_string())?;
+            println!("{} now has the {} role", name, role);
+            Ok(())
+        }
+        None => {
+            let accounts = Accounts::open(&config.accounts)?;
+            let bans = Bans::open(&config.bans)?;
+            let tls = match &config.tls {
+                Some(tls) => Some(protocol::tls::acceptor(&tls.cert, &tls.key)?),
+                None => None,
+            };
+            task::block_on(accept_loop(config, store, accounts, bans, tls))
+        }
+    }
 }
 
-/// Asynchronously accepts incoming TCP connections on the specified address,
+/// Asynchronously accepts incoming TCP connections on the configured addresses,
 /// spawns connection tasks for each accepted connection, and manages a broker loop
-/// for handling peer connections and messages.
-async fn accept_loop(addr: impl ToSocketAddrs) -> Result<()> {
-    let listener = TcpListener::bind(addr).await?;
-
-    let (broker_sender, broker_receiver) = mpsc::unbounded();
-    let broker = task::spawn(broker_loop(broker_receiver));
-    let mut incoming = listener.incoming();
-    while let Some(stream) = incoming.next().await {
-        let stream = stream?;
-        println!("Accepting from: {}", stream.peer_addr()?);
-        spawn_and_log_error(connection_loop(broker_sender.clone(), stream));
+/// for handling peer connections and messages. With `tls` set, every connection is encrypted.
+/// Returns once the server was shut down by SIGINT or SIGTERM.
+async fn accept_loop(
+    config: Config,
+    store: Box<dyn MessageStore>,
+    accounts: Accounts,
+    bans: Bans,
+    tls: Option<TlsAcceptor>,
+) -> Result<()> {
+    let mut listeners = Vec::new();
+    for ip in &config.bind {
+        let addr = SocketAddr::new(*ip, config.port);
+        let listener = TcpListener::bind(addr).await.map_err(|e| format!("cannot listen on {}: {}", addr, e))?;
+        info!("Listening on {}", addr);
+        listeners.push(listener);
     }
+
+    // Message ids keep increasing across restarts
+    let last_message_id = store.last_id()?;
+    let history = History::spawn(store)?;
+
+    let config = Arc::new(config);
+    let metrics = Arc::new(QueueMetrics::default());
+    task::spawn(report_queue_metrics(Arc::clone(&metrics)));
+
+    let (mut broker_sender, broker_receiver) = mpsc::unbounded();
+    let broker = task::spawn(broker_loop(
+        broker_receiver,
+        history,
+        last_message_id,
+        Arc::clone(&config),
+        metrics,
+        accounts.clone(),
+        bans.clone(),
+    ));
+    let services = Services {
+        clients: ClientCount::new(config.max_clients),
+        config: Arc::clone(&config),
+        accounts,
+        bans,
+        throttles: AccountThrottles::default(),
+        tls,
+    };
+    let (shutdown_sender, shutdown) = oneshot::channel::<()>();
+    let shutdown = shutdown.shared();
+    let mut signals = Signals::new([Signal::Int, Signal::Term])?;
+
+    let mut incoming = stream::select_all(listeners.iter().map(|listener| listener.incoming()));
+    loop {
+        let stream = select! {
+            stream = incoming.next().fuse() => match stream {
+                Some(stream) => stream?,
+                None => break,
+            },
+            signal = signals.next().fuse() => {
+                if let Some(Ok(signal)) = signal {
+                    info!("Received {:?}, shutting down", signal);
+                }
+                break;
+            },
+        };
+        info!("Accepting from: {}", stream.peer_addr()?);
+        let connection = connection_loop(broker_sender.clone(), stream, services.clone(), shutdown.clone());
+        spawn_and_log_error(until_shutdown(connection, shutdown.clone()));
+    }
+
+    // Stop accepting, and give the clients a moment to wrap up
+    drop(incoming);
+    drop(listeners);
+    let msg = match config.shutdown_grace {
+        0 => "The server is shutting down".to_string(),
+        grace => format!("The server is shutting down in {} seconds", grace),
+    };
+    broker_sender.send(Event::Notice { to: vec![BROADCAST.to_string()], msg }).await.unwrap();
+    // A second signal cuts the wait short
+    let _ = future::timeout(config.shutdown_grace(), signals.next()).await;
+
+    // Every connection stops, the broker ends once the last one is gone
+    info!("Closing every connection");
+    let _ = shutdown_sender.send(());
     drop(broker_sender);
     broker.await;
+    info!("Server stopped");
     Ok(())
 }
 
+/// Runs a connection until it ends or the server shuts down.
+/// Dropping the connection closes its shutdown channel, which stops its writer.
+async fn until_shutdown(connection: impl Future<Output = Result<()>>, shutdown: ShutdownSignal) -> Result<()> {
+    futures::pin_mut!(connection);
+    select! {
+        res = connection.fuse() => res,
+        _ = shutdown.fuse() => Ok(()),
+    }
+}
+
+/// Logs the depth of the outbound queues every `METRICS_INTERVAL`, when it changed
+async fn report_queue_metrics(metrics: Arc<QueueMetrics>) {
+    let mut last = QueueStats::default();
+    loop {
+        task::sleep(METRICS_INTERVAL).await;
+        let stats = metrics.snapshot();
+        if stats != last {
+            info!("Outbound queues: {}", stats);
+            last = stats;
+        }
+    }
+}
+
+/// Number of connected clients, shared by every connection
+#[derive(Clone)]
+struct ClientCount {
+    count: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// Place of a connected client in the `ClientCount`, given back when dropped
+struct ClientSlot(Arc<AtomicUsize>);
+
+impl ClientCount {
+    fn new(max: usize) -> ClientCount {
+        ClientCount { count: Arc::new(AtomicUsize::new(0)), max }
+    }
+
+    /// Takes a place for a new client, unless the server is full
+    fn acquire(&self) -> Option<ClientSlot> {
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < self.max).then_some(count + 1))
+            .ok()
+            .map(|_| ClientSlot(Arc::clone(&self.count)))
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// What every connection shares
+#[derive(Clone)]
+struct Services {
+    config: Arc<Config>,
+    accounts: Accounts,
+    bans: Bans,
+    throttles: AccountThrottles,
+    tls: Option<TlsAcceptor>,
+    clients: ClientCount,
+}
+
 /// Asynchronous function to handle communication with a client,
 /// forwarding messages to the broker and notifying it about new peer connections.
-async fn connection_loop(mut broker: Sender<Event>, stream: TcpStream) -> Result<()> {
-    let stream = Arc::new(stream);
-    let reader = BufReader::new(&*stream);
-    let mut lines = reader.lines();
+/// Messages longer than the configured size are refused, and clients that stay silent
+/// for too many heartbeats are disconnected, as are the clients a moderator kicks.
+/// The writer of the connection flushes its queue if `shutdown` fires (see `until_shutdown`).
+async fn connection_loop(mut broker: Sender<Event>, stream: TcpStream, services: Services, shutdown: ShutdownSignal) -> Result<()> {
+    let Services { config, accounts, bans, throttles, tls, clients } = services;
 
-    // set the username of the client 
-    let name = match lines.next().await {
-        None => return Err("peer disconnected immediately".into()),
-        Some(line) => line?,
+    // Kept to cut the connection of a peer that does not read its messages
+    let socket = stream.clone();
+
+    // The TLS handshake happens here rather than in the accept loop, so that a slow client cannot hold it up
+    let (reader, writer): (Reader, Box<dyn AsyncWrite + Send + Unpin>) = match tls {
+        Some(acceptor) => {
+            let (reader, writer) = acceptor.accept(stream).await?.split();
+            (Box::new(reader), Box::new(writer))
+        }
+        None => (Box::new(stream.clone()), Box::new(stream)),
     };
+    let stream: Writer = Arc::new(Mutex::new(writer));
+    // Longer frames are skipped, so that a client cannot make the server buffer more than that
+    let mut frames = protocol::frames_with_limit::<_, ClientFrame>(reader, config.max_frame_len());
 
-    let (_shutdown_sender, shutdown_receiver) = mpsc::unbounded::<Void>();
-    // Send a message to the broker about a new peer 
-    broker
-        .send(Event::NewPeer {
-            name: name.clone(),
-            stream: Arc::clone(&stream),
-            shutdown: shutdown_receiver,
-        })
-        .await
-        .unwrap();
+    // Banned addresses are turned away before anything else
+    if let Some(ban) = bans.find(&BanTarget::Ip(socket.peer_addr()?.ip())) {
+        return reject(&stream, ErrorCode::Banned, ban.to_string()).await;
+    }
+
+    // The place is held until the connection ends
+    let Some(_slot) = clients.acquire() else {
+        let msg = format!("The server is full ({} clients), try again later", clients.max);
+        return reject(&stream, ErrorCode::ServerFull, msg).await;
+    };
+
+    // Agree on a protocol version before anything else
+    let session = handshake(&mut frames, &stream, config.max_message_size).await?;
+    debug!("Session {} started with capabilities {:?}", session.id, session.capabilities);
+
+    // Clients that know about delivery acknowledgements are sent them
+    let acks = session.capabilities.iter().any(|capability| capability == features::DELIVERY);
+    let receipts = session.capabilities.iter().any(|capability| capability == features::READ_RECEIPTS);
+    let typing = session.capabilities.iter().any(|capability| capability == features::TYPING);
+    let presence_updates = session.capabilities.iter().any(|capability| capability == features::PRESENCE);
+    let renames = session.capabilities.iter().any(|capability| capability == features::RENAME);
+
+    // Login attempts are limited too, passwords are slow to check
+    let mut limiter = Limiter::new(config.rate_limits(), throttles);
+
+    // Set the username of the client, letting it retry until it picks a free name
+    // (and gives the right password if the name is registered, or the token of the session it resumes)
+    let (mut name, _shutdown_sender, mut kicked, mut pressure) = loop {
+        let mut frame = match frames.next().await {
+            None => return Err("peer disconnected during login".into()),
+            Some(frame) => frame?,
+        };
+        match throttle(&mut limiter, &frame, &stream).await? {
+            Verdict::Allowed => (),
+            Verdict::Throttled => continue,
+            Verdict::Disconnect => return hang_up(&stream, &socket, &mut frames).await,
+        }
+        // Names are checked first, and spelled the way they were registered
+        if let ClientFrame::Login { name, .. } | ClientFrame::Register { name, .. } | ClientFrame::Resume { name, .. } =
+            &mut frame
+        {
+            *name = normalize_name(name);
+            if let Err(e) = validate_name(name) {
+                let rejection = ServerFrame::LoginRejected { code: ErrorCode::InvalidName, msg: e.to_string(), suggestion: None };
+                protocol::write_frame(&mut *stream.lock().await, &rejection).await?;
+                continue;
+            }
+            if let Some(registered) = accounts.registered_name(name) {
+                *name = registered;
+            }
+        }
+        let (name, authenticated, resume) = match frame {
+                ClientFrame::Login { name, password } => {
+                    if let Some(ban) = bans.find(&BanTarget::User(name.clone())) {
+                        return reject(&stream, ErrorCode::Banned, ban.to_string()).await;
+                    }
+                    let password = password.as_ref().map(|password| password.0.as_str());
+                    let authenticated = accounts.authenticate(&name, password).await;
+                    (name, authenticated, None)
+                }
+                ClientFrame::Register { name, password } => {
+                    if let Some(ban) = bans.find(&BanTarget::User(name.clone())) {
+                        return reject(&stream, ErrorCode::Banned, ban.to_string()).await;
+                    }
+                    let registered = accounts.register(&name, &password.0).await;
+                    if registered.is_ok() {
+                        info!("Registered account {}", name);
+                    }
+                    (name, registered, None)
+                }
+                // The broker checks the token
+                ClientFrame::Resume { name, token } => {
+                    if let Some(ban) = bans.find(&BanTarget::User(name.clone())) {
+                        return reject(&stream, ErrorCode::Banned, ban.to_string()).await;
+                    }
+                    (name, Ok(()), Some(token))
+                }
+                frame => return reject(&stream, ErrorCode::UnexpectedFrame, format!("expected a login frame, got {:?}", frame)).await,
+        };
+
+        if let Err(e) = authenticated {
+            if let AccountError::Internal(cause) = &e {
+                error!("Login of {} failed: {}", name, cause);
+            }
+            let rejection = ServerFrame::LoginRejected { code: e.code(), msg: e.to_string(), suggestion: None };
+            protocol::write_frame(&mut *stream.lock().await, &rejection).await?;
+            continue;
+        }
+
+        let (shutdown_sender, shutdown_receiver) = mpsc::unbounded::<Void>();
+        let (pressure_sender, pressure_receiver) = mpsc::unbounded::<Space<ServerFrame>>();
+        let (login_sender, login_receiver) = oneshot::channel();
+        let (kick_sender, kick_receiver) = oneshot::channel();
+        // Send a message to the broker about a new peer 
+        broker
+            .send(Event::NewPeer {
+                name: name.clone(),
+                stream: Arc::clone(&stream),
+                socket: socket.clone(),
+                shutdown: shutdown_receiver,
+                server_shutdown: shutdown.clone(),
+                resume,
+                login: login_sender,
+                kick: kick_sender,
+                pressure: pressure_sender,
+                acks,
+                receipts,
+                typing,
+                presence_updates,
+                renames,
+            })
+            .await
+            .unwrap();
+
+        match login_receiver.await? {
+            Ok(()) => {
+                limiter.log_in(&name);
+                break (name, shutdown_sender, kick_receiver.fuse(), pressure_receiver);
+            }
+            Err(rejection) => protocol::write_frame(&mut *stream.lock().await, &rejection).await?,
+        }
+    };
 
     // Send a notification about the new client to all existing clients
     broker
-        .send(Event::Message {
-            from: "**".to_string(),         // Use Server indicates a system message, not user
-            to: vec!["*".to_string()],          // Send to all clients ("*" represents all)
+        .send(Event::Notice {
+            to: vec![BROADCAST.to_string()],    // Send to all clients
             msg: format!("New client joined: {}", name),
         })
         .await
         .unwrap();
 
 
-    // Get the lines read in from the client 
-    while let Some(line) = lines.next().await {
-        let line = line?;
+    // Clients that know about heartbeats are pinged when they go quiet
+    let heartbeat = session.capabilities.iter().any(|capability| capability == features::HEARTBEAT);
+    let mut unanswered_pings = 0;
 
-        println!("Client msg: {}", line);
-        // If a client sends a disconnect signal
-        if line == "Client_Disconnect" {
-            broker 
-                .send(Event::Message { 
-                    from: "**".to_string(),                 // Use Server indicates a system message, not user
-                    to: vec!["*".to_string()],              // Send to all clients ("*" represents all)
-                    msg: format!("Client, {}, has disconnected ", name),
-                })
+    // Get the frames read in from the client 
+    loop {
+        // A client writing to peers that do not keep up waits for them first, see `Overflow::Block`
+        let frame = match held_back(&mut pressure, &mut kicked).await {
+            Err(kick) => Err(kick),
+            Ok(()) if heartbeat => match future::timeout(config.heartbeat_interval(), next_frame(&mut frames, &mut kicked))
                 .await
-                .unwrap();
+            {
+                Ok(frame) => frame,
+                Err(_) => {
+                    // Each ping had a whole interval to be answered
+                    if unanswered_pings >= config.heartbeat_misses {
+                        broker
+                            .send(Event::Notice {
+                                to: vec![BROADCAST.to_string()],    // Send to all clients
+                                msg: format!("Client, {}, timed out", name),
+                            })
+                            .await
+                            .unwrap();
+                        return Err(format!("{} missed {} heartbeats", name, config.heartbeat_misses).into());
+                    }
+                    unanswered_pings += 1;
+
+                    // A client that cannot even take a ping is missing it too
+                    let ping = async { protocol::write_frame(&mut *stream.lock().await, &ServerFrame::Ping).await };
+                    let _ = future::timeout(config.heartbeat_interval(), ping).await;
+                    continue;
+                }
+            },
+            Ok(()) => next_frame(&mut frames, &mut kicked).await,
+        };
+        let frame = match frame {
+            Ok(Some(Ok(frame))) => frame,
+            // The frame was skipped, the client can go on
+            Ok(Some(Err(protocol::Error::FrameTooLarge { .. }))) => {
+                let error = message_too_large(config.max_message_size, None);
+                protocol::write_frame(&mut *stream.lock().await, &error).await?;
+                continue;
+            }
+            Ok(Some(Err(e))) => return Err(e.into()),
+            Ok(None) => break,
+            Err(kick) => {
+                // The client gets the reason, if it still reads, before the connection is cut
+                let notify = async { protocol::write_frame(&mut *stream.lock().await, &kick).await };
+                let _ = future::timeout(config.heartbeat_interval(), notify).await;
+                let _ = socket.shutdown(Shutdown::Both);
+                return Err(format!("{} was kicked", name).into());
+            }
+        };
+        // Any frame shows the client is alive
+        unanswered_pings = 0;
+
+        match throttle(&mut limiter, &frame, &stream).await? {
+            Verdict::Allowed => (),
+            Verdict::Throttled => continue,
+            Verdict::Disconnect => {
+                broker
+                    .send(Event::Notice {
+                        to: vec![BROADCAST.to_string()],    // Send to all clients
+                        msg: format!("Client, {}, was disconnected for flooding", name),
+                    })
+                    .await
+                    .unwrap();
+                warn!("Disconnecting {}, it kept sending too fast", name);
+                return hang_up(&stream, &socket, &mut frames).await;
+            }
         }
 
-        if line == "Client_PeerList_Request" {
-            broker
-                .send(Event::ClientListRequest { 
-                    from: name.to_string(),
-                })
-                .await
-                .unwrap()
+        debug!("Client frame: {:?}", frame);
+        match frame {
+            ClientFrame::Message { client_id, to, msg } => {
+                if msg.len() > config.max_message_size {
+                    let error = message_too_large(config.max_message_size, Some(msg.len()));
+                    protocol::write_frame(&mut *stream.lock().await, &error).await?;
+                    continue;
+                }
+                broker
+                    .send(Event::Message {
+                        from: name.clone(),
+                        client_id,
+                        to,
+                        msg: msg.trim().to_string(),
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            ClientFrame::HistoryRequest { with, before, limit } => {
+                broker
+                    .send(Event::HistoryRequest {
+                        from: name.clone(),
+                        with,
+                        before,
+                        limit,
+                    })
+                    .await
+                    .unwrap()
+            }
+
+            ClientFrame::CreateRoom { room } => room_command(&mut broker, &name, RoomCommand::Create(room)).await,
+            ClientFrame::JoinRoom { room } => room_command(&mut broker, &name, RoomCommand::Join(room)).await,
+            ClientFrame::LeaveRoom { room } => room_command(&mut broker, &name, RoomCommand::Leave(room)).await,
+            ClientFrame::RoomListRequest => room_command(&mut broker, &name, RoomCommand::List).await,
+            ClientFrame::RoomMembersRequest { room } => room_command(&mut broker, &name, RoomCommand::Members(room)).await,
+            ClientFrame::SetTopic { room, topic } => {
+                room_command(&mut broker, &name, RoomCommand::SetTopic(room, topic)).await
+            }
+
+            ClientFrame::MarkRead { with, up_to } => {
+                broker.send(Event::MarkRead { from: name.clone(), with, up_to }).await.unwrap()
+            }
+            ClientFrame::ReadReceipts { enabled } => {
+                broker.send(Event::ReadReceipts { from: name.clone(), enabled }).await.unwrap()
+            }
+            ClientFrame::Typing { to, typing } => {
+                broker.send(Event::Typing { from: name.clone(), to, typing }).await.unwrap()
+            }
+            ClientFrame::SetPresence { presence, status } => {
+                // An empty status text clears it
+                let status = status.map(|status| status.trim().to_string()).filter(|status| !status.is_empty());
+                let refusal = match &status {
+                    _ if presence == Presence::Offline => Some("You go offline by disconnecting".to_string()),
+                    Some(status) if status.chars().count() > MAX_STATUS_LEN => {
+                        Some(format!("Status texts are at most {} characters long", MAX_STATUS_LEN))
+                    }
+                    _ => None,
+                };
+                if let Some(msg) = refusal {
+                    let error = ServerFrame::Error { code: ErrorCode::InvalidPresence, msg };
+                    protocol::write_frame(&mut *stream.lock().await, &error).await?;
+                    continue;
+                }
+                broker.send(Event::SetPresence { from: name.clone(), presence, status }).await.unwrap()
+            }
+            ClientFrame::Rename { name: new_name } => {
+                // The new name is checked like at login, then the broker makes sure nobody connected uses it
+                let new_name = normalize_name(&new_name);
+                let registered = accounts.registered_name(&new_name);
+                let refusal = match validate_name(&new_name) {
+                    Err(e) => Some((ErrorCode::InvalidName, e.to_string())),
+                    // Registered users may spell their own name differently
+                    Ok(()) if registered.is_some_and(|registered| name_key(&registered) != name_key(&name)) => {
+                        Some((ErrorCode::NameTaken, format!("The name {} is registered", new_name)))
+                    }
+                    Ok(()) => bans
+                        .find(&BanTarget::User(new_name.clone()))
+                        .map(|_| (ErrorCode::InvalidName, format!("The name {} is banned", new_name))),
+                };
+                if let Some((code, msg)) = refusal {
+                    protocol::write_frame(&mut *stream.lock().await, &ServerFrame::Error { code, msg }).await?;
+                    continue;
+                }
+                let (done_sender, done) = oneshot::channel();
+                broker
+                    .send(Event::Rename { from: name.clone(), to: new_name.clone(), done: done_sender })
+                    .await
+                    .unwrap();
+                if done.await? {
+                    // The broker moved the account, the rate limits follow too
+                    limiter.rename(&new_name);
+                    name = new_name;
+                }
+            }
+
+            ClientFrame::PeerListRequest => {
+                broker
+                    .send(Event::ClientListRequest { 
+                        from: name.to_string(),
+                    })
+                    .await
+                    .unwrap()
+            }
+
+            ClientFrame::Kick { name: target, reason } => {
+                moderate(&mut broker, &name, ModCommand::Kick { name: target, reason }).await
+            }
+            ClientFrame::Ban { target, duration, reason } => {
+                let duration = duration.map(Duration::from_secs);
+                moderate(&mut broker, &name, ModCommand::Ban { target, duration, reason }).await
+            }
+            ClientFrame::Unban { target } => moderate(&mut broker, &name, ModCommand::Unban(target)).await,
+            ClientFrame::Mute { name: target, duration } => {
+                let duration = duration.map(Duration::from_secs);
+                moderate(&mut broker, &name, ModCommand::Mute { name: target, duration }).await
+            }
+            ClientFrame::Unmute { name: target } => moderate(&mut broker, &name, ModCommand::Unmute(target)).await,
+            ClientFrame::SetRole { name: target, role } => {
+                moderate(&mut broker, &name, ModCommand::SetRole { name: target, role }).await
+            }
+
+            ClientFrame::Ping => protocol::write_frame(&mut *stream.lock().await, &ServerFrame::Pong).await?,
+            // Only resets the heartbeat count
+            ClientFrame::Pong => (),
+
+            // If a client sends a disconnect signal
+            ClientFrame::Disconnect => {
+                broker 
+                    .send(Event::Notice { 
+                        to: vec![BROADCAST.to_string()],    // Send to all clients
+                        msg: format!("Client, {}, has disconnected ", name),
+                    })
+                    .await
+                    .unwrap();
+                break;
+            }
+
+            ClientFrame::Hello { .. }
+            | ClientFrame::Login { .. }
+            | ClientFrame::Register { .. }
+            | ClientFrame::Resume { .. } => {
+                return Err(format!("{} repeated the handshake", name).into())
+            }
         }
-        
-        let (dest, msg) = match line.find(':') {
-            None => continue,
-            Some(idx) => (&line[..idx], line[idx + 1..].trim()),
-        };
+    }
 
-        let dest: Vec<String> = dest
-            .split(',')
-            .map(|name| name.trim().to_string())
-            .collect();
-        let msg: String = msg.trim().to_string();
+    Ok(())
+}
 
-        broker
-            .send(Event::Message {
-                from: name.clone(),
-                to: dest,
-                msg,
-            })
-            .await
-            .unwrap();
+/// Forwards a client's request about rooms to the broker
+async fn room_command(broker: &mut Sender<Event>, from: &str, command: RoomCommand) {
+    broker
+        .send(Event::Room {
+            from: from.to_string(),
+            command,
+        })
+        .await
+        .unwrap()
+}
+
+/// Checks a frame against the rate limits. A throttled client is warned that its frame is dropped.
+async fn throttle(limiter: &mut Limiter, frame: &ClientFrame, stream: &Writer) -> Result<Verdict> {
+    let bytes = match frame {
+        ClientFrame::Message { msg, .. } => msg.len(),
+        // Answers to the server, and goodbyes, always go through
+        ClientFrame::Pong | ClientFrame::Disconnect => return Ok(Verdict::Allowed),
+        _ => 0,
+    };
+    let verdict = limiter.check(bytes);
+    if verdict == Verdict::Throttled {
+        let msg = "You are sending too fast, this was dropped. Slow down or you will be disconnected".to_string();
+        protocol::write_frame(&mut *stream.lock().await, &ServerFrame::Error { code: ErrorCode::RateLimited, msg }).await?;
     }
+    Ok(verdict)
+}
+
+/// Disconnects a flooding client, telling it why.
+/// What it keeps sending is read (for a while) and dropped: closing the connection with unread data
+/// would reset it, and the client would likely lose our last frames.
+async fn hang_up(stream: &Writer, socket: &TcpStream, frames: &mut BoxStream<'_, protocol::Result<ClientFrame>>) -> Result<()> {
+    let msg = "You were disconnected for sending too fast".to_string();
+    protocol::write_frame(&mut *stream.lock().await, &ServerFrame::Error { code: ErrorCode::Flooding, msg }).await?;
+    socket.shutdown(Shutdown::Write)?;
+    let _ = future::timeout(HANG_UP_TIMEOUT, async { while frames.next().await.is_some() {} }).await;
+    Err("disconnected for flooding".into())
+}
 
+/// Forwards a moderator's request to the broker, which checks their role
+async fn moderate(broker: &mut Sender<Event>, from: &str, command: ModCommand) {
+    broker
+        .send(Event::Moderation {
+            from: from.to_string(),
+            command,
+        })
+        .await
+        .unwrap()
+}
+
+/// Reads the next frame of a client, unless a moderator kicks it first,
+/// in which case the frame telling it why is returned as the error
+async fn next_frame(
+    frames: &mut BoxStream<'_, protocol::Result<ClientFrame>>,
+    kicked: &mut Fuse<oneshot::Receiver<ServerFrame>>,
+) -> std::result::Result<Option<protocol::Result<ClientFrame>>, ServerFrame> {
+    let kick = {
+        let mut frame = frames.next().fuse();
+        select! {
+            frame = frame => return Ok(frame),
+            kick = &mut *kicked => kick,
+        }
+    };
+    match kick {
+        Ok(kick) => Err(kick),
+        // The broker let go of the peer without kicking it
+        Err(oneshot::Canceled) => Ok(frames.next().await),
+    }
+}
+
+/// Waits until the peers the client wrote to have room for more frames (see `Overflow::Block`),
+/// or returns the frame telling it why if it is kicked meanwhile
+async fn held_back(
+    pressure: &mut Receiver<Space<ServerFrame>>,
+    kicked: &mut Fuse<oneshot::Receiver<ServerFrame>>,
+) -> std::result::Result<(), ServerFrame> {
+    while let Ok(space) = pressure.try_recv() {
+        let mut space = space.fuse();
+        select! {
+            () = space => (),
+            kick = &mut *kicked => match kick {
+                Ok(kick) => return Err(kick),
+                // The broker let go of the peer without kicking it
+                Err(oneshot::Canceled) => space.await,
+            },
+        }
+    }
     Ok(())
 }
 
+/// A client that completed the handshake
+#[derive(Debug)]
+struct Session {
+    id: u64,
+    capabilities: Vec<String>,
+}
+
+/// Performs the hello/welcome handshake with a newly connected client, telling it the longest message it may send.
+/// Clients speaking an unsupported protocol version are sent an error frame and disconnected.
+async fn handshake(
+    frames: &mut BoxStream<'_, protocol::Result<ClientFrame>>,
+    stream: &Writer,
+    max_message_size: usize,
+) -> Result<Session> {
+    let (version, capabilities) = match frames.next().await {
+        None => return Err("peer disconnected immediately".into()),
+        Some(frame) => match frame? {
+            ClientFrame::Hello { version, capabilities } => (version, capabilities),
+            frame => return reject(stream, ErrorCode::UnexpectedFrame, format!("expected a hello frame, got {:?}", frame)).await,
+        },
+    };
+
+    if !protocol::is_supported(version) {
+        let msg = format!(
+            "protocol version {} is not supported, this server speaks versions {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        return reject(stream, ErrorCode::IncompatibleVersion, msg).await;
+    }
+
+    let session = Session {
+        id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+        capabilities,
+    };
+    let welcome = ServerFrame::Welcome {
+        version,
+        features: SERVER_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        session_id: session.id,
+        // Clients speaking version 1 do not expect it
+        max_message_size: Some(max_message_size).filter(|_| version >= 2),
+    };
+    protocol::write_frame(&mut *stream.lock().await, &welcome).await?;
+
+    Ok(session)
+}
+
+/// The error telling a client its message of `len` bytes (if known) was not delivered
+fn message_too_large(max_message_size: usize, len: Option<usize>) -> ServerFrame {
+    let msg = match len {
+        Some(len) => format!("Messages may not be longer than {} bytes, this one has {}", max_message_size, len),
+        None => format!("Messages may not be longer than {} bytes, this one was dropped", max_message_size),
+    };
+    ServerFrame::Error { code: ErrorCode::MessageTooLarge, msg }
+}
+
+/// Sends a fatal error frame to a client and returns the matching error,
+/// which ends the connection.
+async fn reject<T>(stream: &Writer, code: ErrorCode, msg: String) -> Result<T> {
+    protocol::write_frame(&mut *stream.lock().await, &ServerFrame::Error { code, msg: msg.clone() }).await?;
+    Err(msg.into())
+}
+
 /// Asynchronous function to continuously write messages from a channel to a TCP stream,
 /// listening for a shutdown signal to exit gracefully.
+/// If the server is shutting down, the messages still queued are written and the connection is closed first.
 async fn connection_writer_loop(
-    messages: &mut Receiver<String>,
-    stream: Arc<TcpStream>,
+    name: &PeerName,
+    messages: &mut OutboxReceiver<ServerFrame>,
+    stream: Writer,
     mut shutdown: Receiver<Void>,
+    server_shutdown: ShutdownSignal,
+    deliveries: Sender<Delivery>,
 ) -> Result<()> {
-    let mut stream = &*stream;
     loop {
         select! {
             msg = messages.next().fuse() => match msg {
-                Some(msg) => stream.write_all(msg.as_bytes()).await?,
+                Some(msg) => {
+                    protocol::write_frame(&mut *stream.lock().await, &msg).await?;
+                    report_delivery(&name.lock().unwrap(), &msg, &deliveries);
+                }
                 None => break,
             },
             void = shutdown.next().fuse() => match void {
                 Some(void) => match void {},
-                None => break,
+                None => {
+                    // The queue ends once the broker lets go of it, after the last frames of the server
+                    if server_shutdown.peek().is_some() {
+                        while let Some(msg) = messages.next().await {
+                            protocol::write_frame(&mut *stream.lock().await, &msg).await?;
+                            report_delivery(&name.lock().unwrap(), &msg, &deliveries);
+                        }
+                    }
+                    break;
+                }
             }
         }
     }
+    if server_shutdown.peek().is_some() {
+        stream.lock().await.close().await?;
+    }
     Ok(())
 }
 
+/// Tells the broker that a message was written to one of its recipients, so that its sender can be told
+fn report_delivery(name: &str, frame: &ServerFrame, deliveries: &Sender<Delivery>) {
+    if let ServerFrame::Message { id, from, .. } = frame {
+        // Nobody needs to hear that their own broadcast reached them
+        if from != name {
+            let _ = deliveries.unbounded_send(Delivery { id: *id, from: from.clone(), recipient: name.to_string() });
+        }
+    }
+}
+
+/// A message written to one of its recipients by their writer loop
+struct Delivery {
+    id: u64,
+    from: String,
+    recipient: String,
+}
+
+/// Frames for a peer prepared away from the broker, e.g. a page of history
+struct Reply {
+    to: String,
+    frames: Vec<ServerFrame>,
+}
+
 /// Represents events in the network
-#[derive(Debug)]
 enum Event {
-    // Indicates a new peer connection with the given name, TCP stream, and shutdown receiver.
+    // Indicates a new peer connection with the given name, the writing half of its connection, the underlying socket, and shutdown receiver.
+    // `resume` is set if the peer resumes a suspended session rather than logging in.
+    // The broker answers on `login` with Ok once the peer is registered,
+    // or with the `LoginRejected` frame to send, e.g. if the requested name is taken.
     NewPeer {
         name: String,
-        stream: Arc<TcpStream>,
+        stream: Writer,
+        socket: TcpStream,
         shutdown: Receiver<Void>,
+        server_shutdown: ShutdownSignal,
+        resume: Option<ResumeToken>,
+        login: oneshot::Sender<std::result::Result<(), ServerFrame>>,
+        // Fired with the frame telling the peer why, if a moderator disconnects it
+        kick: oneshot::Sender<ServerFrame>,
+        // Where the broker hands the queues of the peers the client writes to that are full, see `Overflow::Block`
+        pressure: Sender<Space<ServerFrame>>,
+        // Set if the peer announced `features::DELIVERY`
+        acks: bool,
+        // Set if the peer announced `features::READ_RECEIPTS`
+        receipts: bool,
+        // Set if the peer announced `features::TYPING`
+        typing: bool,
+        // Set if the peer announced `features::PRESENCE`
+        presence_updates: bool,
+        // Set if the peer announced `features::RENAME`
+        renames: bool,
     },
     // Indicates a message sent from one peer to one or more destination peers.
+    // `client_id` identifies the message for the sender, e.g. in undeliverable notices.
     Message {
         from: String,
+        client_id: u64,
+        to: Vec<String>,
+        msg: String,
+    },
+    // Indicates a message generated by the server for one or more destination peers.
+    Notice {
         to: Vec<String>,
         msg: String,
     },
+    // Indicates a client has read the direct messages of another user, up to a message id.
+    MarkRead {
+        from: String,
+        with: String,
+        up_to: u64,
+    },
+    // Indicates a client turns its read receipts on or off.
+    ReadReceipts {
+        from: String,
+        enabled: bool,
+    },
+    // Indicates a client started or stopped writing to users or rooms.
+    Typing {
+        from: String,
+        to: Vec<String>,
+        typing: bool,
+    },
+    // Indicates a client changed its presence, or its status text.
+    SetPresence {
+        from: String,
+        presence: Presence,
+        status: Option<String>,
+    },
+    // Indicates a client wants to change its name. The broker answers on `done` whether it did.
+    Rename {
+        from: String,
+        to: String,
+        done: oneshot::Sender<bool>,
+    },
     // Indicates a client is requesting a list of the connected users.
     ClientListRequest {
         from: String,
+    },
+    // Indicates a client is requesting a page of past messages.
+    HistoryRequest {
+        from: String,
+        with: Option<String>,
+        before: Option<u64>,
+        limit: u32,
+    },
+    // Indicates a client wants to create, join, leave or inspect rooms.
+    Room {
+        from: String,
+        command: RoomCommand,
+    },
+    // Indicates a moderator wants to kick, ban or mute a user, or an admin wants to change a role.
+    Moderation {
+        from: String,
+        command: ModCommand,
+    },
+}
+
+/// A connected peer, as seen by the broker
+struct Peer {
+    /// The name the peer is connected under, followed by its writer
+    name: PeerName,
+    /// Frames waiting to be written to the peer
+    outbox: Outbox<ServerFrame>,
+    /// The connection, cut if the peer does not keep up with its messages
+    socket: TcpStream,
+    /// Tells the connection to end, once
+    kick: Option<oneshot::Sender<ServerFrame>>,
+    /// Holds the connection back until the full queues handed to it have room
+    pressure: Sender<Space<ServerFrame>>,
+    /// Set if the peer wants to know when its messages are sent and delivered
+    acks: bool,
+    /// Set if the peer shares its read receipts, and is sent those of others
+    receipts: bool,
+    /// Set if the peer wants to know who is writing to it
+    typing: bool,
+    /// Whether the user is around, as they last said
+    presence: Presence,
+    status: Option<String>,
+    /// Set if the peer wants to know when others come, go, or change their presence
+    presence_updates: bool,
+    /// Set if the peer understands `ServerFrame::Renamed`
+    renames: bool,
+    /// Users the peer exchanged direct messages with during the session
+    contacts: HashSet<String>,
+    /// For a guest, the id of the last message routed before they logged in (see `Viewer::direct_after`)
+    direct_after: Option<u64>,
+}
+
+impl Peer {
+    /// Queues a frame for the peer, returning whether it was queued.
+    /// A peer whose queue overflows with the `disconnect` policy is disconnected.
+    fn send(&self, frame: ServerFrame) -> bool {
+        match self.outbox.send(frame) {
+            Ok(()) => true,
+            Err(SendError::Overflowed(_)) => {
+                // The writer is likely stuck writing to the peer, cutting the connection unblocks it
+                let addr = self.socket.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                warn!("Disconnecting {}, it is not reading its messages", addr);
+                let _ = self.socket.shutdown(Shutdown::Both);
+                false
+            }
+            Err(SendError::Closed(_)) => false,
+        }
+    }
+
+    /// Disconnects the peer, telling it why with an error
+    fn kick(&mut self, code: ErrorCode, msg: String) {
+        if let Some(kick) = self.kick.take() {
+            let _ = kick.send(ServerFrame::Error { code, msg });
+        }
     }
 }
 
 /// Asynchronous event loop for managing peer connections and message forwarding,
 /// with support for disconnecting peers and cleanup.
-async fn broker_loop(mut events: Receiver<Event>) {
+/// Every message is recorded in the history, numbered after `last_message_id`.
+/// The message of the day, if any, greets every user when they log in.
+/// Frames wait for each peer in a queue bounded by the configuration, whose depth is tracked in `metrics`.
+/// Moderation commands are checked against the roles in `accounts`, and bans are recorded in `bans`.
+async fn broker_loop(
+    mut events: Receiver<Event>,
+    history: History,
+    mut last_message_id: u64,
+    config: Arc<Config>,
+    metrics: Arc<QueueMetrics>,
+    accounts: Accounts,
+    bans: Bans,
+) {
     // Channel for notifying about peer disconnection (name and pending messages)
-    let (disconnect_sender, mut disconnect_receiver) = mpsc::unbounded::<(String, Receiver<String>)>();
+    let (disconnect_sender, mut disconnect_receiver) = mpsc::unbounded::<(PeerName, OutboxReceiver<ServerFrame>)>();
+
+    // Channel for the writers to report the messages they wrote
+    let (delivery_sender, mut delivery_receiver) = mpsc::unbounded::<Delivery>();
+
+    // Channel for the answers read from the history
+    let (reply_sender, mut reply_receiver) = mpsc::unbounded::<Reply>();
+
+    // HashMap to store connected peers (name -> message queue)
+    // Hashmap contains the user's chosen name as the key and the bounded queue of frames for them
+    let mut peers: HashMap<String, Peer> = HashMap::new();
+
+    // Messages waiting for users that went offline
+    let mut mailboxes = Mailboxes::new(MAILBOX_CAPACITY, MAILBOX_TTL);
+
+    // Named rooms and their members
+    let mut rooms = Rooms::new();
 
-    // HashMap to store connected peers (name -> message sender)
-    // Hashmap contains the user's chosen name as the key and the unbounded mpsc channel 'client_sender'
-    let mut peers: HashMap<String, Sender<String>> = HashMap::new();
+    // Resume tokens, and the sessions of the users whose connection dropped
+    let mut sessions = Sessions::new(RESUME_TTL);
+
+    // Users whose messages are dropped
+    let mut mutes = Mutes::default();
 
     loop {
-        // Wait for either an event from the main loop or a disconnect notification
+        // Wait for either an event from the main loop, a disconnect notification or a delivery
         let event = select! {
             event = events.next().fuse() => match event {
                 None => break,
                 Some(event) => event,
             },
 
+            delivery = delivery_receiver.next().fuse() => {
+                // The broker holds a sender itself, the channel never ends
+                let Delivery { id, from, recipient } = delivery.unwrap();
+                if let Some(peer) = peers.get(&from).filter(|peer| peer.acks) {
+                    peer.send(ServerFrame::Delivered { id, recipient });
+                }
+                continue;
+            },
+
+            reply = reply_receiver.next().fuse() => {
+                // The broker holds a sender itself, the channel never ends
+                let Reply { to, frames } = reply.unwrap();
+                for frame in frames {
+                    send_to(&mut peers, std::slice::from_ref(&to), frame).await;
+                }
+                continue;
+            },
+
             disconnect = disconnect_receiver.next().fuse() => {
-                let (name, _pending_messages) = disconnect.unwrap();
-                assert!(peers.remove(&name).is_some());
+                let (name, mut pending_messages) = disconnect.unwrap();
+                // The peer may have changed its name since its writer stopped
+                let name = name.lock().unwrap().clone();
+                let direct_after = peers.remove(&name).unwrap().direct_after;
+                announce_presence(&peers, &name, Presence::Offline, None).await;
+                for peer in peers.values_mut() {
+                    peer.contacts.remove(&name);
+                }
+
+                // Keep the messages the writer did not get to for the next login,
+                // for as long as a guest may resume their session
+                let keep = match accounts.registered_name(&name) {
+                    Some(_) => MAILBOX_TTL,
+                    None => RESUME_TTL,
+                };
+                mailboxes.close(&name, keep);
+                debug!(
+                    "The queue of {} held up to {} frames, {} were dropped",
+                    name,
+                    pending_messages.peak(),
+                    pending_messages.dropped()
+                );
+                for frame in pending_messages.drain() {
+                    if let ServerFrame::Message { id, timestamp, from, room, msg, .. } = frame {
+                        mailboxes.push(&name, id, timestamp, from, room, msg);
+                    }
+                }
+
+                // Only online users are room members
+                let left = rooms.leave_all(&name);
+                for room in &left {
+                    let members = rooms.members(room).unwrap_or_default();
+                    let notice = ServerFrame::Notice { msg: format!("{} left {}", name, room) };
+                    send_to(&mut peers, &members, notice).await;
+                }
+
+                // The user may come back shortly, e.g. if their network dropped
+                sessions.suspend(&name, Suspended { rooms: left, last_message_id, direct_after });
 
                 continue;
             },
@@ -212,42 +1190,280 @@ async fn broker_loop(mut events: Receiver<Event>) {
 
         match event {
             
-            Event::Message { from, to, msg } => {
+            Event::Message { from, client_id, mut to, msg } => {
+                if mutes.is_muted(&from) {
+                    if let Some(peer) = peers.get(&from) {
+                        let msg = "You are muted, your message was not delivered".to_string();
+                        peer.send(ServerFrame::Error { code: ErrorCode::Muted, msg });
+                    }
+                    continue;
+                }
+
+                // Only members can write to a room
+                let mut refused = Vec::new();
+                to.retain(|recipient| {
+                    let allowed = !is_room(recipient) || rooms.is_member(recipient, &from);
+                    if !allowed {
+                        refused.push(recipient.clone());
+                    }
+                    allowed
+                });
+                for room in refused {
+                    let reason = match rooms.members(&room) {
+                        Ok(_) => format!("You are not a member of {}", room),
+                        Err(e) => e.to_string(),
+                    };
+                    if let Some(peer) = peers.get(&from) {
+                        peer.send(ServerFrame::Undeliverable { client_id, recipient: room, reason });
+                    }
+                }
+                if to.is_empty() {
+                    continue;
+                }
+                // Users are addressed by the name they are known by, however the sender spelled it
+                for recipient in to.iter_mut().filter(|recipient| !is_room(recipient) && *recipient != BROADCAST) {
+                    *recipient = known_name(&peers, &accounts, recipient);
+                }
+
+                // Record the message in the history
+                last_message_id += 1;
+                let record = StoredMessage {
+                    id: last_message_id,
+                    timestamp: Utc::now().timestamp_millis(),
+                    from: from.clone(),
+                    to: to.clone(),
+                    msg: msg.clone(),
+                };
+                history.append(record.clone());
+                if let Some(peer) = peers.get(&from).filter(|peer| peer.acks) {
+                    peer.send(ServerFrame::Sent { client_id, id: record.id, timestamp: record.timestamp });
+                }
+
                 // Handle incoming message: send to intended recipients
-                if to == vec!["*".to_string()] {
-                    // Send to all clients
-                    // `HashMap::iter()` returns an iterator that yields 
-                    // (&'a key, &'a value) pairs in arbitrary order.
-                    for (_name, client_sender_channel) in &peers {
-                            let mut peer = client_sender_channel;
-                            let msg = format!("{}{}\n", from, msg);
-                            peer.send(msg).await.unwrap();
+                let (to_rooms, to_users): (Vec<String>, Vec<String>) = to.into_iter().partition(|to| is_room(to));
+                let frame = ServerFrame::Message {
+                    id: record.id,
+                    timestamp: record.timestamp,
+                    from: from.clone(),
+                    room: None,
+                    msg: msg.clone(),
+                    offline: false,
+                };
+                let missing = send_to(&mut peers, &to_users, frame).await;
+
+                // The users who got a direct message are in a conversation with its sender, e.g. to hear about renames
+                for recipient in to_users.iter().filter(|to| **to != BROADCAST && !missing.contains(to) && **to != from) {
+                    if let Some(peer) = peers.get_mut(recipient) {
+                        peer.contacts.insert(from.clone());
                     }
-                } else {
-                    for addr in to {
-                        // Check if the name is in the hashtable
-                        if let Some(peer) = peers.get_mut(&addr) {
-                            let msg = format!("{}: {}\n", from, msg);
-                            peer.send(msg).await.unwrap();
-                        }
+                    if let Some(peer) = peers.get_mut(&from) {
+                        peer.contacts.insert(recipient.clone());
+                    }
+                }
+
+                // Fan room messages out to the other members
+                let mut reached = match to_users == [BROADCAST] {
+                    true => peers.keys().cloned().collect(),
+                    false => to_users,
+                };
+                for room in to_rooms {
+                    let members: Vec<String> = rooms
+                        .members(&room)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|member| *member != from)
+                        .collect();
+                    let frame = ServerFrame::Message {
+                        id: record.id,
+                        timestamp: record.timestamp,
+                        from: from.clone(),
+                        room: Some(room),
+                        msg: msg.clone(),
+                        offline: false,
+                    };
+                    send_to(&mut peers, &members, frame).await;
+                    reached.extend(members);
+                }
+
+                // The sender is not read anymore until the recipients who do not keep up catch up
+                if let Some(sender) = peers.get(&from) {
+                    for space in reached.iter().filter_map(|name| peers.get(name)?.outbox.space()) {
+                        let _ = sender.pressure.unbounded_send(space);
                     }
                 }
+
+                // Keep the message for registered users, and for guests who may resume their session,
+                // and tell the sender about every recipient that could not be reached
+                for recipient in missing {
+                    if accounts.registered_name(&recipient).is_some() {
+                        mailboxes.open(&recipient);
+                    }
+                    let answer = if mailboxes.push(&recipient, record.id, record.timestamp, from.clone(), None, msg.clone()) {
+                        ServerFrame::Queued { client_id, recipient }
+                    } else {
+                        let reason = format!("{} is not online", recipient);
+                        ServerFrame::Undeliverable { client_id, recipient, reason }
+                    };
+                    if let Some(peer) = peers.get(&from) {
+                        peer.send(answer);
+                    }
+                }
+            },
+
+            Event::Notice { to, msg } => {
+                // Handle server notice: send to intended recipients
+                let msg = ServerFrame::Notice { msg };
+                send_to(&mut peers, &to, msg).await;
             },
 
-            Event::NewPeer { name, stream, shutdown } => match peers.entry(name.clone()) {
+            Event::NewPeer {
+                name,
+                stream,
+                socket,
+                shutdown,
+                server_shutdown,
+                resume,
+                login,
+                kick,
+                pressure,
+                acks,
+                receipts,
+                typing,
+                presence_updates,
+                renames,
+            } => match peers.entry(connected_name(&peers, &name)) {
                 // Handle new peer connection:
-                Entry::Occupied(..) => (),          // Ignore duplicate connection attempts
+                Entry::Occupied(..) => {
+                    // Refuse duplicate names, however they are spelled, so the client can pick another one
+                    let suggestion = suggest_name(&peers, &name);
+                    let _ = login.send(Err(ServerFrame::LoginRejected {
+                        code: ErrorCode::NameTaken,
+                        msg: format!("The name {} is already taken", name),
+                        suggestion: Some(suggestion),
+                    }));
+                },
                 Entry::Vacant(entry) => {
-                    // Create a new channel for sending messages to this peer
-                    let (client_sender, mut client_receiver) = mpsc::unbounded();
-                    entry.insert(client_sender);
+                    // A resumed session needs the token of the last login with this name
+                    let resumed = match resume {
+                        Some(token) => match sessions.resume(&name, &token) {
+                            Some(suspended) => Some(suspended),
+                            None => {
+                                let _ = login.send(Err(ServerFrame::LoginRejected {
+                                    code: ErrorCode::ResumeFailed,
+                                    msg: format!("The session of {} cannot be resumed, log in again", name),
+                                    suggestion: None,
+                                }));
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+
+                    // Create a new queue for sending messages to this peer
+                    let (client_sender, mut client_receiver) =
+                        outbox::channel(config.queue_capacity, config.queue_overflow, Arc::clone(&metrics));
+                    let peer_name: PeerName = Arc::new(std::sync::Mutex::new(name.clone()));
+                    // A guest does not see the conversations of whoever used the name before
+                    let direct_after = match (accounts.registered_name(&name), &resumed) {
+                        (Some(_), _) => None,
+                        (None, Some(suspended)) => suspended.direct_after,
+                        (None, None) => Some(last_message_id),
+                    };
+                    let peer = Peer {
+                        name: Arc::clone(&peer_name),
+                        outbox: client_sender,
+                        socket,
+                        kick: Some(kick),
+                        pressure,
+                        acks,
+                        receipts,
+                        typing,
+                        presence: Presence::Online,
+                        status: None,
+                        presence_updates,
+                        renames,
+                        contacts: HashSet::new(),
+                        direct_after,
+                    };
+                    let resume_token = sessions.start(&name);
+                    peer.send(ServerFrame::LoggedIn { name: name.clone(), resume_token });
+                    if let (Some(motd), None) = (&config.motd, &resumed) {
+                        peer.send(ServerFrame::Notice { msg: motd.clone() });
+                    }
+
+                    // Deliver what was kept while the user was offline.
+                    // After a resume, the messages routed since the connection dropped are replayed from the history below.
+                    mailboxes.register(&name);
+                    let mut senders = HashSet::new();
+                    for frame in mailboxes.take(&name) {
+                        let missed = match (&frame, &resumed) {
+                            (ServerFrame::Message { id, .. }, Some(suspended)) => *id > suspended.last_message_id,
+                            _ => false,
+                        };
+                        if let ServerFrame::Message { from, .. } = &frame {
+                            senders.insert(from.clone());
+                        }
+                        if !missed {
+                            peer.send(frame);
+                        }
+                    }
+                    entry.insert(peer);
+
+                    // The senders of the kept messages who are still online are in a conversation with the user
+                    for sender in senders {
+                        let sender = connected_name(&peers, &sender);
+                        if sender == name {
+                            continue;
+                        }
+                        if let Some(peer) = peers.get_mut(&sender) {
+                            peer.contacts.insert(name.clone());
+                            peers.get_mut(&name).unwrap().contacts.insert(sender);
+                        }
+                    }
+                    let _ = login.send(Ok(()));
+
+                    // Tell the others the user is here, and the user who is not simply online
+                    announce_presence(&peers, &name, Presence::Online, None).await;
+                    if presence_updates {
+                        let known: Vec<ServerFrame> = peers
+                            .iter()
+                            .filter(|(other, known)| {
+                                **other != name && (known.presence != Presence::Online || known.status.is_some())
+                            })
+                            .map(|(other, known)| ServerFrame::Presence {
+                                name: other.clone(),
+                                presence: known.presence,
+                                status: known.status.clone(),
+                            })
+                            .collect();
+                        for frame in known {
+                            send_to(&mut peers, std::slice::from_ref(&name), frame).await;
+                        }
+                    }
+
+                    if let Some(suspended) = resumed {
+                        rejoin_rooms(&mut peers, &mut rooms, &name, suspended.rooms).await;
+                        let mut channels = vec![BROADCAST.to_string()];
+                        channels.extend(rooms.rooms_of(&name));
+                        let missed = missed_messages(history.clone(), name.clone(), channels, suspended.last_message_id);
+                        reply_later(&reply_sender, &name, missed);
+                    }
                 
                     // Spawn a separate task to handle writing messages to the peer
                     let mut disconnect_sender = disconnect_sender.clone();
+                    let deliveries = delivery_sender.clone();
                     spawn_and_log_error(async move {
-                        let res = connection_writer_loop(&mut client_receiver, stream, shutdown).await;
+                        let res = connection_writer_loop(
+                            &peer_name,
+                            &mut client_receiver,
+                            stream,
+                            shutdown,
+                            server_shutdown,
+                            deliveries,
+                        )
+                        .await;
                         disconnect_sender
-                            .send((name, client_receiver))
+                            .send((peer_name, client_receiver))
                             .await
                             .unwrap();
                         res
@@ -255,35 +1471,527 @@ async fn broker_loop(mut events: Receiver<Event>) {
                 }
             },
             
+            Event::MarkRead { from, with, up_to } => {
+                // Receipts are only passed between users who both share theirs, about messages that exist,
+                // and nothing is kept for users who are offline
+                let with = connected_name(&peers, &with);
+                let Some(reader) = peers.get(&from).filter(|peer| peer.receipts) else {
+                    continue;
+                };
+                if up_to > last_message_id || !peers.get(&with).is_some_and(|peer| peer.receipts) {
+                    continue;
+                }
+                let read = ServerFrame::Read { by: from.clone(), up_to };
+                if reader.contacts.contains(&with) {
+                    peers[&with].send(read);
+                    continue;
+                }
+
+                // Otherwise the reader must have been sent a direct message they can see, e.g. before reconnecting
+                let query = HistoryQuery {
+                    viewer: Some(Viewer {
+                        name: from.clone(),
+                        channels: Vec::new(),
+                        with: Some(with.clone()),
+                        direct_after: reader.direct_after,
+                    }),
+                    before: Some(up_to + 1),
+                    ..Default::default()
+                };
+                let (history, sender) = (history.clone(), with.clone());
+                let receipt = async move {
+                    match history.query(query).await {
+                        Ok(messages) if messages.iter().any(|message| message.from == sender) => vec![read],
+                        Ok(_) => Vec::new(),
+                        Err(e) => {
+                            error!("Failed to read the history for a receipt of {}: {}", sender, e);
+                            Vec::new()
+                        }
+                    }
+                };
+                reply_later(&reply_sender, &with, receipt);
+            },
+
+            Event::ReadReceipts { from, enabled } => {
+                if let Some(peer) = peers.get_mut(&from) {
+                    peer.receipts = enabled;
+                }
+            },
+
+            Event::Typing { from, to, typing } => {
+                // Only what would reach the recipients as a message is forwarded, and nothing is kept
+                if mutes.is_muted(&from) {
+                    continue;
+                }
+                for recipient in to {
+                    let (names, room) = if is_room(&recipient) {
+                        if !rooms.is_member(&recipient, &from) {
+                            continue;
+                        }
+                        let members = rooms.members(&recipient).unwrap_or_default();
+                        (members.into_iter().filter(|member| *member != from).collect(), Some(recipient))
+                    } else if recipient == BROADCAST {
+                        // Everyone need not know
+                        continue;
+                    } else {
+                        (vec![connected_name(&peers, &recipient)], None)
+                    };
+                    for name in names {
+                        if let Some(peer) = peers.get(&name).filter(|peer| peer.typing) {
+                            peer.send(ServerFrame::Typing { from: from.clone(), room: room.clone(), typing });
+                        }
+                    }
+                }
+            },
+
+            Event::SetPresence { from, presence, status } => {
+                // Everyone can read a status text, like a broadcast
+                if status.is_some() && mutes.is_muted(&from) {
+                    if let Some(peer) = peers.get(&from) {
+                        let msg = "You are muted, your status was not changed".to_string();
+                        peer.send(ServerFrame::Error { code: ErrorCode::Muted, msg });
+                    }
+                    continue;
+                }
+                let Some(peer) = peers.get_mut(&from) else {
+                    continue;
+                };
+                if peer.presence == presence && peer.status == status {
+                    continue;
+                }
+                peer.presence = presence;
+                peer.status = status.clone();
+                announce_presence(&peers, &from, presence, status).await;
+            },
+
+            Event::Rename { from, to, done } => {
+                // Asking for the name one already has changes nothing
+                let Some(peer) = peers.get(&from) else {
+                    let _ = done.send(false);
+                    continue;
+                };
+                if to == from {
+                    let msg = format!("You are already known as {}", to);
+                    let unchanged = match peer.renames {
+                        true => ServerFrame::Renamed { from, to },
+                        false => ServerFrame::Notice { msg },
+                    };
+                    peer.send(unchanged);
+                    let _ = done.send(false);
+                    continue;
+                }
+                // Only the user themselves may use another spelling of their name
+                let taken = connected_name(&peers, &to);
+                if peers.contains_key(&taken) && name_key(&taken) != name_key(&from) {
+                    let msg = format!("The name {} is already taken", to);
+                    peer.send(ServerFrame::Error { code: ErrorCode::NameTaken, msg });
+                    let _ = done.send(false);
+                    continue;
+                }
+                // Nor the name of someone who left, and may come back for their messages or their session
+                if name_key(&to) != name_key(&from) && (sessions.is_suspended(&to) || mailboxes.holds(&to)) {
+                    let msg = format!("The name {} is kept for someone who left", to);
+                    peer.send(ServerFrame::Error { code: ErrorCode::NameTaken, msg });
+                    let _ = done.send(false);
+                    continue;
+                }
+                // The account follows its user, with their password and role, or the user keeps their name
+                if let Err(e) = accounts.rename(&from, &to) {
+                    if let AccountError::Internal(cause) = &e {
+                        error!("Failed to move the account of {} to {}: {}", from, to, cause);
+                    }
+                    peer.send(ServerFrame::Error { code: e.code(), msg: e.to_string() });
+                    let _ = done.send(false);
+                    continue;
+                }
+                let mut peer = peers.remove(&from).unwrap();
+
+                // The old name is gone for those who follow presence
+                announce_presence(&peers, &from, Presence::Offline, None).await;
+
+                // Everything kept under the old name moves to the new one at once.
+                // What others said to the new name before is none of the user's business.
+                *peer.name.lock().unwrap() = to.clone();
+                peer.direct_after = Some(last_message_id);
+                let (presence, status) = (peer.presence, peer.status.clone());
+                peers.insert(to.clone(), peer);
+                let joined = rooms.rename(&from, &to);
+                mutes.rename(&from, &to);
+                sessions.rename(&from, &to);
+                mailboxes.rename(&from, &to);
+                for peer in peers.values_mut() {
+                    if peer.contacts.remove(&from) {
+                        peer.contacts.insert(to.clone());
+                    }
+                }
+                let _ = done.send(true);
+                info!("{} is now known as {}", from, to);
+                announce_presence(&peers, &to, presence, status).await;
+
+                // Tell the user, and everyone who shares a room or a conversation with them
+                let mut told: BTreeSet<String> = peers[&to].contacts.iter().cloned().collect();
+                for room in &joined {
+                    told.extend(rooms.members(room).unwrap_or_default());
+                }
+                told.insert(to.clone());
+                let renamed = ServerFrame::Renamed { from: from.clone(), to: to.clone() };
+                let notice = ServerFrame::Notice { msg: format!("{} is now known as {}", from, to) };
+                for name in told {
+                    if let Some(peer) = peers.get(&name) {
+                        peer.send(if peer.renames { renamed.clone() } else { notice.clone() });
+                    }
+                }
+            },
+
             Event::ClientListRequest { from } => {
                 // Collect all names from the hashmap into a vector
                 let names: Vec<_> = peers.keys().cloned().collect();
 
                 // The client that sent the request recieves the list
                 // Make sure the client is in the hashtable 
-                if let Some(peer) = peers.get_mut(&from) {
-
-                    let start_msg = format!("**Clients Connected:\n");
-                    peer.send(start_msg).await.unwrap();
+                if let Some(peer) = peers.get(&from) {
+                    peer.send(ServerFrame::PeerList { names });
+                }
+            },
 
-                    // Iterate over the vector and send each name followed by "FIN"
-                    for name in names {
-                        // Get rid of the ':'
-                        let formated_name = name.trim_end_matches(':').to_string();
-                        // Send name
-                        let msg = format!("**Server: {}\n", formated_name);
-                        peer.send(msg).await.unwrap();
+            Event::HistoryRequest { from, with, before, limit } => {
+                // Only members can read the history of a room
+                if let Some(room) = with.as_ref().filter(|with| is_room(with) && !rooms.is_member(with, &from)) {
+                    if let Some(peer) = peers.get(&from) {
+                        let msg = format!("You are not a member of {}", room);
+                        peer.send(ServerFrame::Error { code: ErrorCode::NotInRoom, msg });
                     }
-                    // Send "**FIN" to denote end of list. Don't allow ** char in username
-                    let fin_msg = format!("**FIN\n");
-                    peer.send(fin_msg).await.unwrap();
+                    continue;
+                }
+
+                // Replay the messages the client can see: its own, broadcasts, and those of its rooms
+                let mut channels = vec![BROADCAST.to_string()];
+                channels.extend(rooms.rooms_of(&from));
+                let limit = (limit as usize).min(MAX_HISTORY_BATCH);
+                let query = HistoryQuery {
+                    viewer: Some(Viewer {
+                        name: from.clone(),
+                        channels,
+                        with: with.as_ref().map(|with| match is_room(with) || with == BROADCAST {
+                            true => with.clone(),
+                            false => known_name(&peers, &accounts, with),
+                        }),
+                        direct_after: peers.get(&from).and_then(|peer| peer.direct_after),
+                    }),
+                    before,
+                    // Ask for one more message to find out whether there are older ones
+                    limit: Some(limit + 1),
+                    ..Default::default()
+                };
+
+                // Reading the history may take a while, the answer is sent once it is ready
+                let (history, viewer) = (history.clone(), from.clone());
+                let page = async move {
+                    let answer = match history.query(query).await {
+                        Ok(mut messages) => {
+                            let has_more = messages.len() > limit;
+                            if has_more {
+                                messages.remove(0);
+                            }
+                            let messages = messages.into_iter().map(HistoryMessage::from).collect();
+                            ServerFrame::History { with, messages, has_more }
+                        }
+                        Err(e) => {
+                            error!("Failed to read the history for {}: {}", viewer, e);
+                            ServerFrame::Error { code: ErrorCode::Internal, msg: "The history is unavailable".to_string() }
+                        }
+                    };
+                    vec![answer]
+                };
+                reply_later(&reply_sender, &from, page);
+            },
+
+            Event::Room { from, command } => {
+                let answer = match command {
+                    RoomCommand::Create(room) => rooms.create(&room, &from).map(|()| ServerFrame::RoomJoined {
+                        room,
+                        topic: None,
+                        members: vec![from.clone()],
+                    }),
+                    RoomCommand::Join(room) => match rooms.join(&room, &from) {
+                        Ok(topic) => {
+                            // Let the other members know
+                            let members = rooms.members(&room).unwrap_or_default();
+                            let others: Vec<String> = members.iter().filter(|member| **member != from).cloned().collect();
+                            let notice = ServerFrame::Notice { msg: format!("{} joined {}", from, room) };
+                            send_to(&mut peers, &others, notice).await;
+                            Ok(ServerFrame::RoomJoined { room, topic, members })
+                        }
+                        Err(e) => Err(e),
+                    },
+                    RoomCommand::Leave(room) => match rooms.leave(&room, &from) {
+                        Ok(()) => {
+                            let members = rooms.members(&room).unwrap_or_default();
+                            let notice = ServerFrame::Notice { msg: format!("{} left {}", from, room) };
+                            send_to(&mut peers, &members, notice).await;
+                            Ok(ServerFrame::RoomLeft { room })
+                        }
+                        Err(e) => Err(e),
+                    },
+                    RoomCommand::List => Ok(ServerFrame::RoomList { rooms: rooms.list() }),
+                    RoomCommand::Members(room) => rooms.members(&room).map(|names| ServerFrame::RoomMembers { room, names }),
+                    RoomCommand::SetTopic(room, topic) => match rooms.set_topic(&room, &from, topic) {
+                        Ok(topic) => {
+                            // Every member, including the one who changed it, gets the new topic
+                            let members = rooms.members(&room).unwrap_or_default();
+                            let frame = ServerFrame::RoomTopic { room, topic, by: from.clone() };
+                            send_to(&mut peers, &members, frame).await;
+                            continue;
+                        }
+                        Err(e) => Err(e),
+                    },
+                };
+
+                let answer = answer.unwrap_or_else(|e| ServerFrame::Error { code: e.code(), msg: e.to_string() });
+                if let Some(peer) = peers.get(&from) {
+                    peer.send(answer);
+                }
+            },
+
+            Event::Moderation { from, command } => {
+                let answer = enforce(&mut peers, &accounts, &bans, &mut mutes, &from, command).await;
+                if let (Some(answer), Some(peer)) = (answer, peers.get(&from)) {
+                    peer.send(answer);
                 }
             },
         } 
     }
     drop(peers);
     drop(disconnect_sender);
-    while let Some((_name, _pending_messages)) = disconnect_receiver.next().await {}
+    // Clients that do not read could hold the writers up forever
+    let writers = async { while let Some((_name, _pending_messages)) = disconnect_receiver.next().await {} };
+    if future::timeout(SHUTDOWN_FLUSH_TIMEOUT, writers).await.is_err() {
+        warn!("Some clients did not get their last messages");
+    }
+    if let Err(e) = history.flush().await {
+        error!("Failed to flush the history: {}", e);
+    }
+}
+
+/// Carries out a moderation command of `from`, telling the users concerned.
+/// Returns the answer for `from`, if the others were not told.
+async fn enforce(
+    peers: &mut HashMap<String, Peer>,
+    accounts: &Accounts,
+    bans: &Bans,
+    mutes: &mut Mutes,
+    from: &str,
+    mut command: ModCommand,
+) -> Option<ServerFrame> {
+    let error = |code, msg: String| Some(ServerFrame::Error { code, msg });
+    let with_reason = |msg: String, reason: &Option<String>| match reason {
+        Some(reason) => format!("{}: {}", msg, reason),
+        None => msg,
+    };
+
+    // "ADMIN" is the same user as "admin"
+    if let Some(target) = command.target_mut() {
+        *target = known_name(peers, accounts, target);
+    }
+
+    // Moderators can only act on users below them
+    let role = accounts.role(from);
+    if role < command.required_role() {
+        return error(ErrorCode::PermissionDenied, format!("Only a {} can do this", command.required_role()));
+    }
+    if let Some(target) = command.target() {
+        let target_role = accounts.role(target);
+        if target_role >= role {
+            return error(ErrorCode::PermissionDenied, format!("{} is a {} too", target, target_role));
+        }
+    }
+
+    let notice = match command {
+        ModCommand::Kick { name, reason } => {
+            let Some(peer) = peers.get_mut(&name) else {
+                return error(ErrorCode::NoSuchUser, format!("{} is not online", name));
+            };
+            peer.kick(ErrorCode::Kicked, with_reason(format!("You were kicked by {}", from), &reason));
+            with_reason(format!("{} was kicked by {}", name, from), &reason)
+        }
+        ModCommand::Ban { target, duration, reason } => {
+            let until = duration.map(|duration| Utc::now().timestamp_millis() + duration.as_millis() as i64);
+            let ban = Ban { target: target.clone(), by: from.to_string(), reason: reason.clone(), until };
+            if let Err(e) = bans.ban(ban.clone()) {
+                error!("Failed to save the ban of {}: {}", target, e);
+                return error(ErrorCode::Internal, "The ban could not be saved".to_string());
+            }
+            info!("{} banned {} {}", from, target, describe_duration(duration));
+            match &target {
+                BanTarget::User(name) => {
+                    if let Some(peer) = peers.get_mut(name) {
+                        peer.kick(ErrorCode::Banned, ban.to_string());
+                    }
+                    with_reason(format!("{} was banned {} by {}", name, describe_duration(duration), from), &reason)
+                }
+                BanTarget::Ip(ip) => {
+                    // Addresses are not shown to everyone, only the moderator hears about it
+                    let mut kicked = 0;
+                    for (name, peer) in peers.iter_mut() {
+                        let same_ip = peer.socket.peer_addr().is_ok_and(|addr| addr.ip() == *ip);
+                        if same_ip && accounts.role(name) < role {
+                            peer.kick(ErrorCode::Banned, ban.to_string());
+                            kicked += 1;
+                        }
+                    }
+                    let msg = format!("{} is banned {}, {} users were disconnected", ip, describe_duration(duration), kicked);
+                    return Some(ServerFrame::Notice { msg });
+                }
+            }
+        }
+        ModCommand::Unban(target) => {
+            return match bans.unban(&target) {
+                Ok(true) => {
+                    info!("{} lifted the ban of {}", from, target);
+                    Some(ServerFrame::Notice { msg: format!("{} is no longer banned", target) })
+                }
+                Ok(false) => error(ErrorCode::NoSuchUser, format!("{} is not banned", target)),
+                Err(e) => {
+                    error!("Failed to save the bans: {}", e);
+                    error(ErrorCode::Internal, "The ban could not be lifted".to_string())
+                }
+            };
+        }
+        ModCommand::Mute { name, duration } => {
+            mutes.mute(&name, duration);
+            format!("{} was muted {} by {}", name, describe_duration(duration), from)
+        }
+        ModCommand::Unmute(name) => {
+            if !mutes.unmute(&name) {
+                return error(ErrorCode::NoSuchUser, format!("{} is not muted", name));
+            }
+            format!("{} was unmuted by {}", name, from)
+        }
+        ModCommand::SetRole { name, role } => {
+            if let Err(e) = accounts.set_role(&name, role) {
+                return error(e.code(), e.to_string());
+            }
+            info!("{} made {} a {}", from, name, role);
+            let notice = ServerFrame::Notice { msg: format!("{} is now a {}", name, role) };
+            send_to(peers, &[from.to_string(), name], notice).await;
+            return None;
+        }
+    };
+    send_to(peers, &[BROADCAST.to_string()], ServerFrame::Notice { msg: notice }).await;
+    None
+}
+
+/// Puts a user that resumed their session back in the rooms they were in
+async fn rejoin_rooms(peers: &mut HashMap<String, Peer>, rooms: &mut Rooms, name: &str, left: Vec<String>) {
+    for room in left {
+        // The room may be gone if everyone left it in the meantime
+        let Ok(topic) = rooms.join(&room, name) else {
+            continue;
+        };
+        let members = rooms.members(&room).unwrap_or_default();
+        let others: Vec<String> = members.iter().filter(|member| *member != name).cloned().collect();
+        let notice = ServerFrame::Notice { msg: format!("{} joined {}", name, room) };
+        send_to(peers, &others, notice).await;
+        send_to(peers, &[name.to_string()], ServerFrame::RoomJoined { room, topic, members }).await;
+    }
+}
+
+/// Returns the messages a user who resumed their session missed since `last_message_id`
+/// in their `channels`, marked as delivered while offline
+async fn missed_messages(history: History, name: String, channels: Vec<String>, last_message_id: u64) -> Vec<ServerFrame> {
+    let query = HistoryQuery {
+        viewer: Some(Viewer { name: name.clone(), channels, with: None, direct_after: None }),
+        after: Some(last_message_id),
+        limit: Some(MAILBOX_CAPACITY),
+        ..Default::default()
+    };
+    let missed = match history.query(query).await {
+        Ok(missed) => missed,
+        Err(e) => {
+            error!("Failed to replay the missed messages of {}: {}", name, e);
+            return Vec::new();
+        }
+    };
+    missed
+        .into_iter()
+        .filter(|message| message.from != name)
+        .map(|message| ServerFrame::Message {
+            id: message.id,
+            timestamp: message.timestamp,
+            room: message.to.iter().find(|to| is_room(to)).cloned(),
+            from: message.from,
+            msg: message.msg,
+            offline: true,
+        })
+        .collect()
+}
+
+/// Sends `to` the frames `frames` resolves to once they are ready, without holding up the broker meanwhile
+fn reply_later(replies: &Sender<Reply>, to: &str, frames: impl Future<Output = Vec<ServerFrame>> + Send + 'static) {
+    let mut replies = replies.clone();
+    let to = to.to_string();
+    task::spawn(async move {
+        let frames = frames.await;
+        let _ = replies.send(Reply { to, frames }).await;
+    });
+}
+
+/// Tells the peers that follow presence, other than `name` itself, that `name` is now `presence`
+async fn announce_presence(peers: &HashMap<String, Peer>, name: &str, presence: Presence, status: Option<String>) {
+    let frame = ServerFrame::Presence { name: name.to_string(), presence, status };
+    for (_, peer) in peers.iter().filter(|(other, peer)| *other != name && peer.presence_updates) {
+        peer.send(frame.clone());
+    }
+}
+
+/// Returns the name under which a peer with the same name as `name`, spelled in any case or Unicode form,
+/// is connected. Returns `name` if there is none.
+fn connected_name(peers: &HashMap<String, Peer>, name: &str) -> String {
+    let key = name_key(name);
+    peers.keys().find(|peer| name_key(peer) == key).cloned().unwrap_or_else(|| name.to_string())
+}
+
+/// Returns the name under which a user with the same name as `name` is connected, or else registered.
+/// Returns `name` if there is none.
+fn known_name(peers: &HashMap<String, Peer>, accounts: &Accounts, name: &str) -> String {
+    let key = name_key(name);
+    match peers.keys().find(|peer| name_key(peer) == key) {
+        Some(connected) => connected.clone(),
+        None => accounts.registered_name(name).unwrap_or_else(|| name.to_string()),
+    }
+}
+
+/// Suggests a variant of `name` that no connected peer is using, e.g. "alice2"
+fn suggest_name(peers: &HashMap<String, Peer>, name: &str) -> String {
+    // Leave room for the number
+    let name: String = name.chars().take(MAX_NAME_LEN - 4).collect();
+    (2..)
+        .map(|n| format!("{}{}", name, n))
+        .find(|candidate| !peers.keys().any(|peer| name_key(peer) == name_key(candidate)))
+        .unwrap()
+}
+
+/// Sends a frame to each named peer, or to every peer if the recipients are `BROADCAST`.
+/// Returns the names that are not in the hashtable, or whose queue did not take the frame.
+async fn send_to(peers: &mut HashMap<String, Peer>, to: &[String], msg: ServerFrame) -> Vec<String> {
+    let mut missing = Vec::new();
+    if to == [BROADCAST] {
+        // Send to all clients
+        // `HashMap::iter()` returns an iterator that yields 
+        // (&'a key, &'a value) pairs in arbitrary order.
+        for (_name, peer) in peers.iter() {
+            peer.send(msg.clone());
+        }
+    } else {
+        for addr in to {
+            // Check if the name is in the hashtable
+            match peers.get(addr) {
+                Some(peer) if peer.send(msg.clone()) => (),
+                _ => missing.push(addr.clone()),
+            }
+        }
+    }
+    missing
 }
 
 /// Spawns a new asynchronous task to execute the given future, logging any errors that occur.
@@ -293,7 +2001,7 @@ where
 {
     task::spawn(async move {
         if let Err(e) = fut.await {
-            eprintln!("{}", e)
+            warn!("{}", e)
         }
     })
 }
\ No newline at end of file
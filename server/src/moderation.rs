@@ -0,0 +1,296 @@
+/*
+    Moderation: bans and mutes
+
+    Moderators and admins (see the roles in `accounts.rs`) can kick a connected user, ban a name
+    or an address, and mute a user. They cannot act on users whose role is as high as their own.
+    Bans may expire. They are kept in a JSON file, rewritten through a temporary file like the accounts,
+    so that they survive restarts. Mutes only last until the server stops: the broker drops the
+    messages of a muted user and tells them why.
+*/
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{TimeZone, Utc};
+use protocol::{name_key, BanTarget, Role};
+use serde::{Deserialize, Serialize};
+
+/// A moderation request, as sent by a client
+#[derive(Debug)]
+pub enum ModCommand {
+    Kick { name: String, reason: Option<String> },
+    Ban { target: BanTarget, duration: Option<Duration>, reason: Option<String> },
+    Unban(BanTarget),
+    Mute { name: String, duration: Option<Duration> },
+    Unmute(String),
+    SetRole { name: String, role: Role },
+}
+
+impl ModCommand {
+    /// The role needed to send the command
+    pub fn required_role(&self) -> Role {
+        match self {
+            ModCommand::SetRole { .. } => Role::Admin,
+            _ => Role::Moderator,
+        }
+    }
+
+    /// The user the command acts on, who must have a lower role than the sender
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            ModCommand::Kick { name, .. }
+            | ModCommand::Ban { target: BanTarget::User(name), .. }
+            | ModCommand::Mute { name, .. }
+            | ModCommand::SetRole { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The user the command acts on, to replace the name as typed by the name the user is known by
+    pub fn target_mut(&mut self) -> Option<&mut String> {
+        match self {
+            ModCommand::Kick { name, .. }
+            | ModCommand::Ban { target: BanTarget::User(name), .. }
+            | ModCommand::Mute { name, .. }
+            | ModCommand::Unmute(name)
+            | ModCommand::SetRole { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// A ban as saved in the bans file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// The moderator who issued the ban
+    pub by: String,
+    pub reason: Option<String>,
+    /// When the ban ends, in milliseconds since the Unix epoch; None for good
+    pub until: Option<i64>,
+}
+
+impl Ban {
+    fn is_active(&self, now: i64) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+}
+
+/// Describes the ban to the banned client, e.g. "mallory is banned until 2024-03-21 10:00 UTC: spam"
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is banned", self.target)?;
+        if let Some(until) = self.until.and_then(|until| Utc.timestamp_millis_opt(until).single()) {
+            write!(f, " until {}", until.format("%Y-%m-%d %H:%M UTC"))?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Handle on the bans, shared by every connection
+#[derive(Clone)]
+pub struct Bans {
+    path: PathBuf,
+    bans: Arc<Mutex<Vec<Ban>>>,
+}
+
+impl Bans {
+    /// Loads the bans file, which is created on the first ban
+    pub fn open(path: &Path) -> Result<Bans, Box<dyn std::error::Error + Send + Sync>> {
+        let bans = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Bans {
+            path: path.to_path_buf(),
+            bans: Arc::new(Mutex::new(bans)),
+        })
+    }
+
+    /// Returns the ban that currently applies to the target, if any
+    pub fn find(&self, target: &BanTarget) -> Option<Ban> {
+        let now = Utc::now().timestamp_millis();
+        self.bans
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|ban| same_target(&ban.target, target) && ban.is_active(now))
+            .cloned()
+    }
+
+    /// Records a ban, replacing any previous ban of the same target, and saves the bans file.
+    /// Expired bans are forgotten meanwhile.
+    pub fn ban(&self, ban: Ban) -> std::io::Result<()> {
+        let now = Utc::now().timestamp_millis();
+        let mut bans = self.bans.lock().unwrap();
+        let mut updated: Vec<Ban> =
+            bans.iter().filter(|old| !same_target(&old.target, &ban.target) && old.is_active(now)).cloned().collect();
+        updated.push(ban);
+        save(&self.path, &updated)?;
+        *bans = updated;
+        Ok(())
+    }
+
+    /// Lifts the ban of a target and saves the bans file. Returns false if the target was not banned.
+    pub fn unban(&self, target: &BanTarget) -> std::io::Result<bool> {
+        let now = Utc::now().timestamp_millis();
+        let mut bans = self.bans.lock().unwrap();
+        if !bans.iter().any(|ban| same_target(&ban.target, target) && ban.is_active(now)) {
+            return Ok(false);
+        }
+        let updated: Vec<Ban> =
+            bans.iter().filter(|ban| !same_target(&ban.target, target) && ban.is_active(now)).cloned().collect();
+        save(&self.path, &updated)?;
+        *bans = updated;
+        Ok(true)
+    }
+}
+
+/// Whether two targets are the same, banning "mallory" also bans "Mallory"
+fn same_target(a: &BanTarget, b: &BanTarget) -> bool {
+    match (a, b) {
+        (BanTarget::User(a), BanTarget::User(b)) => name_key(a) == name_key(b),
+        _ => a == b,
+    }
+}
+
+/// Replaces the bans file with the given bans
+fn save(path: &Path, bans: &[Ban]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(bans)?)?;
+    fs::rename(&tmp, path)
+}
+
+/// Users whose messages are dropped, until a deadline or until they are unmuted.
+/// Users are known by the key of their name, so muting "mallory" also mutes "Mallory".
+#[derive(Default)]
+pub struct Mutes {
+    muted: HashMap<String, Option<Instant>>,
+}
+
+impl Mutes {
+    /// Mutes a user for `duration`, or until they are unmuted
+    pub fn mute(&mut self, name: &str, duration: Option<Duration>) {
+        self.muted.insert(name_key(name), duration.map(|duration| Instant::now() + duration));
+    }
+
+    /// Lets a user write again. Returns false if they were not muted.
+    pub fn unmute(&mut self, name: &str) -> bool {
+        self.is_muted(name) && self.muted.remove(&name_key(name)).is_some()
+    }
+
+    /// Keeps a user muted under their new name
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(until) = self.muted.remove(&name_key(old)) {
+            self.muted.insert(name_key(new), until);
+        }
+    }
+
+    pub fn is_muted(&mut self, name: &str) -> bool {
+        let key = name_key(name);
+        match self.muted.get(&key) {
+            None => false,
+            Some(Some(until)) if *until <= Instant::now() => {
+                self.muted.remove(&key);
+                false
+            }
+            Some(_) => true,
+        }
+    }
+}
+
+/// Describes how long a ban or a mute lasts, e.g. "for 10 minutes", or "for good"
+pub fn describe_duration(duration: Option<Duration>) -> String {
+    let Some(duration) = duration else {
+        return "for good".to_string();
+    };
+    let secs = duration.as_secs();
+    let (count, unit) = match secs {
+        0..120 => (secs, "second"),
+        120..7_200 => (secs / 60, "minute"),
+        7_200..172_800 => (secs / 3_600, "hour"),
+        _ => (secs / 86_400, "day"),
+    };
+    format!("for {} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(name: &str, until: Option<i64>) -> Ban {
+        Ban {
+            target: BanTarget::User(name.to_string()),
+            by: "admin".to_string(),
+            reason: Some("spam".to_string()),
+            until,
+        }
+    }
+
+    #[test]
+    fn bans_survive_restarts_until_they_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+        let mallory = BanTarget::User("mallory".to_string());
+        let ip = BanTarget::Ip("10.0.0.1".parse().unwrap());
+
+        let bans = Bans::open(&path).unwrap();
+        assert_eq!(bans.find(&mallory), None);
+        bans.ban(ban("mallory", None)).unwrap();
+        bans.ban(Ban { target: ip.clone(), ..ban("", None) }).unwrap();
+        // Expired bans do not apply
+        bans.ban(ban("eve", Some(Utc::now().timestamp_millis() - 1))).unwrap();
+        assert_eq!(bans.find(&BanTarget::User("eve".to_string())), None);
+
+        let bans = Bans::open(&path).unwrap();
+        assert_eq!(bans.find(&mallory), Some(ban("mallory", None)));
+        assert!(bans.find(&ip).is_some());
+        assert_eq!(bans.find(&BanTarget::User("alice".to_string())), None);
+        // Spelling the name differently does not help
+        assert!(bans.find(&BanTarget::User("MALLORY".to_string())).is_some());
+
+        assert!(bans.unban(&mallory).unwrap());
+        assert!(!bans.unban(&mallory).unwrap());
+        assert_eq!(Bans::open(&path).unwrap().find(&mallory), None);
+    }
+
+    #[test]
+    fn mutes_expire() {
+        let mut mutes = Mutes::default();
+        mutes.mute("mallory", None);
+        mutes.mute("eve", Some(Duration::ZERO));
+        assert!(mutes.is_muted("mallory"));
+        assert!(mutes.is_muted("Mallory"));
+        assert!(!mutes.is_muted("eve"));
+        assert!(!mutes.unmute("eve"));
+        assert!(mutes.unmute("MALLORY"));
+        assert!(!mutes.is_muted("mallory"));
+    }
+
+    #[test]
+    fn mutes_follow_renames() {
+        let mut mutes = Mutes::default();
+        mutes.mute("mallory", None);
+        mutes.rename("mallory", "mal");
+        assert!(!mutes.is_muted("mallory"));
+        assert!(mutes.is_muted("mal"));
+    }
+
+    #[test]
+    fn durations_are_described_in_the_largest_unit() {
+        assert_eq!(describe_duration(None), "for good");
+        assert_eq!(describe_duration(Some(Duration::from_secs(1))), "for 1 second");
+        assert_eq!(describe_duration(Some(Duration::from_secs(600))), "for 10 minutes");
+        assert_eq!(describe_duration(Some(Duration::from_secs(86_400))), "for 24 hours");
+        assert_eq!(describe_duration(Some(Duration::from_secs(7 * 86_400))), "for 7 days");
+    }
+}
@@ -0,0 +1,361 @@
+/*
+    Outbound queues
+
+    The broker hands the frames for each peer to a bounded queue, which the writer task of
+    the connection empties. A peer that stops reading can no longer make the server grow
+    without limit: once its queue holds `capacity` frames, the configured `Overflow` policy
+    decides what happens to the next one.
+    The broker itself never waits on a queue: with the `block` policy, it is the clients writing
+    to a peer whose queue is full that wait, as their connections stop being read until it has room.
+    The depth of every queue is tracked in `QueueMetrics`, which the server logs regularly.
+*/
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use clap::ValueEnum;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+
+/// What happens to a frame sent to a peer whose queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Drop the oldest queued frame to make room
+    DropOldest,
+    /// Disconnect the peer; the messages it did not get are kept in its mailbox
+    Disconnect,
+    /// Keep the frame, and stop reading from the clients writing to the peer until it catches up.
+    /// The queue holds a few more frames than its capacity meanwhile, those already on their way.
+    Block,
+}
+
+/// Depth statistics shared by every outbound queue
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    queued: AtomicUsize,
+    deepest: AtomicUsize,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+/// A reading of `QueueMetrics`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Frames waiting in all the queues
+    pub queued: usize,
+    /// Most frames ever waiting in a single queue
+    pub deepest: usize,
+    /// Frames dropped to make room
+    pub dropped: u64,
+    /// Peers disconnected because their queue was full
+    pub disconnected: u64,
+}
+
+impl QueueMetrics {
+    pub fn snapshot(&self) -> QueueStats {
+        QueueStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            deepest: self.deepest.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} frames queued, deepest queue {} frames, {} frames dropped, {} slow peers disconnected",
+            self.queued, self.deepest, self.dropped, self.disconnected
+        )
+    }
+}
+
+/// Why a frame could not be queued; the frame is handed back
+#[derive(Debug, PartialEq)]
+pub enum SendError<T> {
+    /// The queue is full and the peer must be disconnected
+    Overflowed(T),
+    /// The peer is gone, or is being disconnected
+    Closed(T),
+}
+
+struct Shared<T> {
+    capacity: usize,
+    overflow: Overflow,
+    metrics: Arc<QueueMetrics>,
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    frames: VecDeque<T>,
+    /// Set once the queue overflowed with the `disconnect` policy; nothing is read or queued after that
+    overflowed: bool,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+    /// Most frames waiting at once
+    peak: usize,
+    /// Frames dropped to make room
+    dropped: u64,
+    /// The writer, waiting for a frame
+    receiver_waker: Option<Waker>,
+    /// The connections held back until the queue has room, with the `block` policy
+    space_wakers: Vec<Waker>,
+}
+
+impl<T> State<T> {
+    fn has_space(&self, capacity: usize) -> bool {
+        self.frames.len() < capacity || self.overflowed || self.receiver_dropped
+    }
+
+    fn wake_senders(&mut self, capacity: usize) {
+        if self.has_space(capacity) {
+            self.space_wakers.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let frames = self.state.get_mut().unwrap().frames.len();
+        self.metrics.queued.fetch_sub(frames, Ordering::Relaxed);
+    }
+}
+
+/// The sending half of a peer's queue, held by the broker
+pub struct Outbox<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving half of a peer's queue, emptied by the writer task
+pub struct OutboxReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a queue holding at most `capacity` frames
+pub fn channel<T>(capacity: usize, overflow: Overflow, metrics: Arc<QueueMetrics>) -> (Outbox<T>, OutboxReceiver<T>) {
+    let shared = Arc::new(Shared {
+        capacity,
+        overflow,
+        metrics,
+        state: Mutex::new(State {
+            frames: VecDeque::new(),
+            overflowed: false,
+            sender_dropped: false,
+            receiver_dropped: false,
+            peak: 0,
+            dropped: 0,
+            receiver_waker: None,
+            space_wakers: Vec::new(),
+        }),
+    });
+    (Outbox { shared: Arc::clone(&shared) }, OutboxReceiver { shared })
+}
+
+impl<T> Outbox<T> {
+    /// Queues a frame, applying the overflow policy if the queue is full.
+    /// Never waits, so that a peer that does not read cannot hold up the broker.
+    pub fn send(&self, frame: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.overflowed || state.receiver_dropped {
+            return Err(SendError::Closed(frame));
+        }
+
+        if state.frames.len() >= shared.capacity {
+            match shared.overflow {
+                // The clients writing to the peer are held back instead, see `space`
+                Overflow::Block => (),
+                Overflow::DropOldest => {
+                    state.frames.pop_front();
+                    state.dropped += 1;
+                    shared.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    shared.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Overflow::Disconnect => {
+                    // The writer stops, leaving the queued frames to `drain`
+                    state.overflowed = true;
+                    state.wake_senders(shared.capacity);
+                    shared.metrics.disconnected.fetch_add(1, Ordering::Relaxed);
+                    if let Some(waker) = state.receiver_waker.take() {
+                        waker.wake();
+                    }
+                    return Err(SendError::Overflowed(frame));
+                }
+            }
+        }
+
+        state.frames.push_back(frame);
+        state.peak = state.peak.max(state.frames.len());
+        shared.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        shared.metrics.deepest.fetch_max(state.frames.len(), Ordering::Relaxed);
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns a future resolving once the queue has room again, if it is full with the `block` policy
+    pub fn space(&self) -> Option<Space<T>> {
+        let full = !self.shared.state.lock().unwrap().has_space(self.shared.capacity);
+        (self.shared.overflow == Overflow::Block && full).then(|| Space { shared: Arc::clone(&self.shared) })
+    }
+}
+
+/// Resolves once a full queue has room, or nobody reads it anymore
+pub struct Space<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Future for Space<T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.has_space(self.shared.capacity) {
+            return Poll::Ready(());
+        }
+        if !state.space_wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.space_wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Outbox<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.sender_dropped = true;
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> OutboxReceiver<T> {
+    /// Takes every frame left in the queue, e.g. once the peer disconnected
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        let frames: Vec<T> = state.frames.drain(..).collect();
+        self.shared.metrics.queued.fetch_sub(frames.len(), Ordering::Relaxed);
+        state.wake_senders(self.shared.capacity);
+        frames
+    }
+
+    /// Most frames that waited in the queue at once
+    pub fn peak(&self) -> usize {
+        self.shared.state.lock().unwrap().peak
+    }
+
+    /// Frames dropped to make room
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+}
+
+/// Yields the queued frames in order, and ends once the broker dropped the queue
+/// (after the remaining frames) or the queue overflowed (right away)
+impl<T> Stream for OutboxReceiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.overflowed {
+            return Poll::Ready(None);
+        }
+        match state.frames.pop_front() {
+            Some(frame) => {
+                shared.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                state.wake_senders(shared.capacity);
+                Poll::Ready(Some(frame))
+            }
+            None if state.sender_dropped => Poll::Ready(None),
+            None => {
+                state.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for OutboxReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_dropped = true;
+        state.wake_senders(self.shared.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, StreamExt};
+
+    use super::*;
+
+    /// Queues frames 0 to `count - 1` for a reader that does not read
+    fn stalled(capacity: usize, overflow: Overflow, count: u32) -> (Outbox<u32>, OutboxReceiver<u32>, Arc<QueueMetrics>) {
+        let metrics = Arc::new(QueueMetrics::default());
+        let (outbox, receiver) = channel(capacity, overflow, Arc::clone(&metrics));
+        for frame in 0..count {
+            assert_eq!(outbox.send(frame), Ok(()));
+        }
+        (outbox, receiver, metrics)
+    }
+
+    #[test]
+    fn stalled_readers_lose_their_oldest_frames() {
+        let (outbox, receiver, metrics) = stalled(3, Overflow::DropOldest, 5);
+        assert_eq!(
+            metrics.snapshot(),
+            QueueStats { queued: 3, deepest: 3, dropped: 2, disconnected: 0 }
+        );
+        assert_eq!(receiver.dropped(), 2);
+
+        // The reader gets what is left once the broker lets go of the queue
+        drop(outbox);
+        assert_eq!(block_on(receiver.collect::<Vec<_>>()), vec![2, 3, 4]);
+        assert_eq!(metrics.snapshot().queued, 0);
+    }
+
+    #[test]
+    fn stalled_readers_are_disconnected() {
+        let (outbox, mut receiver, metrics) = stalled(2, Overflow::Disconnect, 2);
+        assert_eq!(outbox.send(2), Err(SendError::Overflowed(2)));
+        assert_eq!(outbox.send(3), Err(SendError::Closed(3)));
+        assert_eq!(metrics.snapshot().disconnected, 1);
+
+        // The writer stops at once, the frames it did not write are still there
+        assert_eq!(block_on(receiver.next()), None);
+        assert_eq!(receiver.drain(), vec![0, 1]);
+        assert_eq!(receiver.peak(), 2);
+        assert_eq!(metrics.snapshot().queued, 0);
+    }
+
+    #[test]
+    fn stalled_readers_hold_back_their_senders() {
+        let (outbox, mut receiver, metrics) = stalled(2, Overflow::Block, 3);
+        assert_eq!(metrics.snapshot().dropped, 0);
+        let space = outbox.space().expect("the queue is full");
+
+        // The sender waits until the reader took enough frames
+        let waiting = std::thread::spawn(move || block_on(space));
+        assert_eq!(block_on(receiver.next()), Some(0));
+        assert!(outbox.space().is_some());
+        assert_eq!(block_on(receiver.next()), Some(1));
+        waiting.join().unwrap();
+        assert!(outbox.space().is_none());
+        assert_eq!(block_on(receiver.next()), Some(2));
+    }
+}
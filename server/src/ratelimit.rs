@@ -0,0 +1,328 @@
+/*
+    Flood protection
+
+    Every connection reads its frames through a `Limiter`, made of token buckets: one for the frames
+    and one for the bytes of message text, each refilled at the configured rate and holding
+    `BURST_SECONDS` worth of tokens. The same limits apply to each account (or guest name), whose
+    buckets are shared by its connections over time, so that reconnecting does not refill them.
+    The buckets of an account are only forgotten once it has no connection left and they are full again.
+    A frame that finds a bucket short is dropped and costs the client a strike; a client out of
+    strikes, because it kept sending too fast, is disconnected. Strikes are forgiven slowly.
+*/
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use protocol::name_key;
+
+/// The buckets hold this many seconds of traffic, so that short bursts go through
+pub const BURST_SECONDS: f64 = 2.0;
+
+/// A strike is forgiven after this long
+pub const STRIKE_RECOVERY: Duration = Duration::from_secs(60);
+
+/// What a client may send
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    pub messages_per_second: f64,
+    pub bytes_per_second: f64,
+    /// Frames dropped for going too fast before the client is disconnected
+    pub strikes: u32,
+}
+
+/// Tokens refilled at a steady rate, up to a capacity
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(capacity: f64, per_second: f64, now: Instant) -> TokenBucket {
+        TokenBucket { capacity, per_second, tokens: capacity, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Whether `amount` tokens are available
+    fn has(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount
+    }
+
+    /// Takes `amount` tokens if they are available
+    pub fn take(&mut self, amount: f64, now: Instant) -> bool {
+        let available = self.has(amount, now);
+        if available {
+            self.tokens -= amount;
+        }
+        available
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// The buckets of a connection or of an account
+#[derive(Debug, Clone)]
+struct Throttle {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    /// The connections logged in to the account
+    connections: usize,
+}
+
+impl Throttle {
+    fn new(limits: &RateLimits, now: Instant) -> Throttle {
+        Throttle {
+            messages: TokenBucket::new(limits.messages_per_second * BURST_SECONDS, limits.messages_per_second, now),
+            bytes: TokenBucket::new(limits.bytes_per_second * BURST_SECONDS, limits.bytes_per_second, now),
+            connections: 0,
+        }
+    }
+
+    fn has(&mut self, bytes: usize, now: Instant) -> bool {
+        self.messages.has(1.0, now) && self.bytes.has(bytes as f64, now)
+    }
+
+    fn take(&mut self, bytes: usize, now: Instant) {
+        self.messages.take(1.0, now);
+        self.bytes.take(bytes as f64, now);
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.messages.is_full(now) && self.bytes.is_full(now)
+    }
+}
+
+/// The buckets of every account by the key of its name, shared by the connections
+#[derive(Clone, Default)]
+pub struct AccountThrottles {
+    throttles: Arc<Mutex<HashMap<String, Throttle>>>,
+}
+
+/// What happens to a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// The frame is dropped and the client warned
+    Throttled,
+    /// The client is disconnected
+    Disconnect,
+}
+
+/// The rate limits of a connection
+pub struct Limiter {
+    limits: RateLimits,
+    connection: Throttle,
+    strikes: TokenBucket,
+    accounts: AccountThrottles,
+    /// The key of the account of the connection, once logged in
+    account: Option<String>,
+}
+
+impl Limiter {
+    pub fn new(limits: RateLimits, accounts: AccountThrottles) -> Limiter {
+        let now = Instant::now();
+        Limiter {
+            limits,
+            connection: Throttle::new(&limits, now),
+            strikes: TokenBucket::new(limits.strikes as f64, 1.0 / STRIKE_RECOVERY.as_secs_f64(), now),
+            accounts,
+            account: None,
+        }
+    }
+
+    /// Counts the frames of the connection against the account `name` too, from now on
+    pub fn log_in(&mut self, name: &str) {
+        self.log_in_at(name, Instant::now())
+    }
+
+    fn log_in_at(&mut self, name: &str, now: Instant) {
+        let mut throttles = self.accounts.throttles.lock().unwrap();
+        // Accounts nobody uses that stayed quiet long enough to fill up their buckets are forgotten
+        throttles.retain(|_, throttle| throttle.connections > 0 || !throttle.is_full(now));
+        let key = name_key(name);
+        throttles.entry(key.clone()).or_insert_with(|| Throttle::new(&self.limits, now)).connections += 1;
+        if let Some(previous) = self.account.replace(key) {
+            release(&mut throttles, &previous);
+        }
+    }
+
+    /// Counts the frames of the connection against the new name of its account from now on,
+    /// with the tokens the account had left
+    pub fn rename(&mut self, name: &str) {
+        let Some(previous) = self.account.take() else {
+            return self.log_in(name);
+        };
+        let mut throttles = self.accounts.throttles.lock().unwrap();
+        let mut carried =
+            throttles.get(&previous).cloned().unwrap_or_else(|| Throttle::new(&self.limits, Instant::now()));
+        release(&mut throttles, &previous);
+        carried.connections = 0;
+        let key = name_key(name);
+        throttles.entry(key.clone()).or_insert(carried).connections += 1;
+        self.account = Some(key);
+    }
+
+    /// Decides what happens to a frame carrying `bytes` of message text
+    pub fn check(&mut self, bytes: usize) -> Verdict {
+        self.check_at(bytes, Instant::now())
+    }
+
+    fn check_at(&mut self, bytes: usize, now: Instant) -> Verdict {
+        let mut throttles = self.accounts.throttles.lock().unwrap();
+        let mut account = self.account.as_ref().and_then(|name| throttles.get_mut(name));
+        let allowed =
+            self.connection.has(bytes, now) && account.as_mut().is_none_or(|account| account.has(bytes, now));
+        if allowed {
+            self.connection.take(bytes, now);
+            if let Some(account) = account {
+                account.take(bytes, now);
+            }
+            Verdict::Allowed
+        } else if self.strikes.take(1.0, now) {
+            Verdict::Throttled
+        } else {
+            Verdict::Disconnect
+        }
+    }
+}
+
+impl Drop for Limiter {
+    fn drop(&mut self) {
+        if let Some(account) = &self.account {
+            release(&mut self.accounts.throttles.lock().unwrap(), account);
+        }
+    }
+}
+
+/// Counts one connection less against an account
+fn release(throttles: &mut HashMap<String, Throttle>, account: &str) {
+    if let Some(throttle) = throttles.get_mut(account) {
+        throttle.connections -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: RateLimits = RateLimits { messages_per_second: 5.0, bytes_per_second: 1000.0, strikes: 3 };
+
+    /// Sends `count` frames of `bytes` bytes at once, and counts the verdicts
+    fn blast(limiter: &mut Limiter, count: usize, bytes: usize, now: Instant) -> (usize, usize, usize) {
+        let mut verdicts = (0, 0, 0);
+        for _ in 0..count {
+            match limiter.check_at(bytes, now) {
+                Verdict::Allowed => verdicts.0 += 1,
+                Verdict::Throttled => verdicts.1 += 1,
+                Verdict::Disconnect => verdicts.2 += 1,
+            }
+        }
+        verdicts
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 1.0, start);
+        assert!(bucket.take(2.0, start));
+        assert!(!bucket.take(1.0, start));
+        assert!(!bucket.take(1.0, start + Duration::from_millis(500)));
+        assert!(bucket.take(1.0, start + Duration::from_secs(1)));
+        // Never more than the capacity
+        assert!(!bucket.take(3.0, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn floods_are_throttled_then_disconnected() {
+        let start = Instant::now();
+        let mut limiter = Limiter::new(LIMITS, AccountThrottles::default());
+
+        // A burst of two seconds goes through, then each frame costs a strike
+        assert_eq!(blast(&mut limiter, 1000, 10, start), (10, 3, 987));
+
+        // A polite client is never throttled
+        let mut limiter = Limiter::new(LIMITS, AccountThrottles::default());
+        for second in 0..100 {
+            let now = start + Duration::from_secs(second);
+            assert_eq!(blast(&mut limiter, 5, 100, now), (5, 0, 0));
+        }
+    }
+
+    #[test]
+    fn long_messages_count_against_the_bytes() {
+        let start = Instant::now();
+        let mut limiter = Limiter::new(LIMITS, AccountThrottles::default());
+        assert_eq!(blast(&mut limiter, 3, 1000, start), (2, 1, 0));
+        assert_eq!(blast(&mut limiter, 1, 1000, start + Duration::from_secs(1)), (1, 0, 0));
+    }
+
+    #[test]
+    fn accounts_do_not_get_a_fresh_start_by_reconnecting() {
+        let start = Instant::now();
+        let accounts = AccountThrottles::default();
+        let mut first = Limiter::new(LIMITS, accounts.clone());
+        first.log_in("mallory");
+        assert_eq!(blast(&mut first, 10, 10, start), (10, 0, 0));
+
+        let mut second = Limiter::new(LIMITS, accounts.clone());
+        second.log_in("mallory");
+        assert_eq!(blast(&mut second, 1, 10, start).1, 1);
+
+        // Other accounts are not held up
+        let mut other = Limiter::new(LIMITS, accounts);
+        other.log_in("alice");
+        assert_eq!(blast(&mut other, 10, 10, start), (10, 0, 0));
+    }
+
+    #[test]
+    fn accounts_in_use_are_not_forgotten() {
+        let start = Instant::now();
+        let accounts = AccountThrottles::default();
+        let mut first = Limiter::new(LIMITS, accounts.clone());
+        first.log_in_at("mallory", start);
+
+        // Another login finds the quiet account full, but it is still connected
+        Limiter::new(LIMITS, accounts.clone()).log_in_at("alice", start);
+        assert_eq!(blast(&mut first, 10, 10, start), (10, 0, 0));
+        let mut second = Limiter::new(LIMITS, accounts.clone());
+        second.log_in_at("Mallory", start);
+        assert_eq!(blast(&mut second, 1, 10, start).1, 1);
+
+        // Once nobody uses it and it has refilled, it is
+        drop(first);
+        drop(second);
+        Limiter::new(LIMITS, accounts.clone()).log_in_at("alice", start + Duration::from_secs(60));
+        assert!(!accounts.throttles.lock().unwrap().contains_key("mallory"));
+    }
+
+    #[test]
+    fn renaming_keeps_the_buckets() {
+        let start = Instant::now();
+        let accounts = AccountThrottles::default();
+        let mut limiter = Limiter::new(LIMITS, accounts.clone());
+        limiter.log_in_at("mallory", start);
+        assert_eq!(blast(&mut limiter, 10, 10, start), (10, 0, 0));
+        limiter.rename("mal");
+
+        // A new connection under the new name finds the account empty, and the old name is free
+        let mut other = Limiter::new(LIMITS, accounts.clone());
+        other.log_in_at("mal", start);
+        assert_eq!(blast(&mut other, 1, 10, start).1, 1);
+        assert_eq!(accounts.throttles.lock().unwrap()["mallory"].connections, 0);
+    }
+}
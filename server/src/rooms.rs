@@ -0,0 +1,282 @@
+/*
+    Named chat rooms
+
+    A room is created by a user, who becomes its first member. Any user can then join it,
+    and messages addressed to the room (e.g. "#general: hi") are fanned out to its members only.
+    Membership is indexed both per room and per user, so that a disconnecting user
+    can be removed from every room at once. Rooms outlive their members until the server stops.
+*/
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+};
+
+use protocol::{ErrorCode, RoomInfo, ROOM_PREFIX};
+
+/// Longest room name accepted, prefix included
+const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// A request about rooms, as sent by a client
+#[derive(Debug)]
+pub enum RoomCommand {
+    Create(String),
+    Join(String),
+    Leave(String),
+    List,
+    Members(String),
+    SetTopic(String, Option<String>),
+}
+
+/// Why a room request was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoomError {
+    InvalidName(String),
+    NoSuchRoom(String),
+    AlreadyExists(String),
+    AlreadyMember(String),
+    NotAMember(String),
+}
+
+impl RoomError {
+    /// The code reported to the client in `ServerFrame::Error`
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RoomError::InvalidName(_) => ErrorCode::InvalidRoomName,
+            RoomError::NoSuchRoom(_) => ErrorCode::NoSuchRoom,
+            RoomError::AlreadyExists(_) => ErrorCode::RoomExists,
+            RoomError::AlreadyMember(_) => ErrorCode::AlreadyInRoom,
+            RoomError::NotAMember(_) => ErrorCode::NotInRoom,
+        }
+    }
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::InvalidName(room) => write!(
+                f,
+                "{} is not a valid room name: use {} followed by up to {} letters, digits, '-' or '_'",
+                room,
+                ROOM_PREFIX,
+                MAX_ROOM_NAME_LEN - 1
+            ),
+            RoomError::NoSuchRoom(room) => write!(f, "There is no room named {}", room),
+            RoomError::AlreadyExists(room) => write!(f, "The room {} already exists", room),
+            RoomError::AlreadyMember(room) => write!(f, "You are already in {}", room),
+            RoomError::NotAMember(room) => write!(f, "You are not a member of {}", room),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+type Result<T> = std::result::Result<T, RoomError>;
+
+#[derive(Default)]
+struct Room {
+    topic: Option<String>,
+    members: BTreeSet<String>,
+}
+
+#[derive(Default)]
+pub struct Rooms {
+    /// Rooms by name, sorted for listing
+    rooms: BTreeMap<String, Room>,
+    /// Names of the rooms each user is a member of
+    memberships: HashMap<String, BTreeSet<String>>,
+}
+
+/// Returns true if `room` is the prefix followed by a non-empty run of letters, digits, '-' or '_'
+pub fn is_valid_room_name(room: &str) -> bool {
+    match room.strip_prefix(ROOM_PREFIX) {
+        Some(rest) => {
+            !rest.is_empty()
+                && room.chars().count() <= MAX_ROOM_NAME_LEN
+                && rest.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        }
+        None => false,
+    }
+}
+
+impl Rooms {
+    pub fn new() -> Rooms {
+        Rooms::default()
+    }
+
+    /// Creates a room with `creator` as its only member
+    pub fn create(&mut self, room: &str, creator: &str) -> Result<()> {
+        if !is_valid_room_name(room) {
+            return Err(RoomError::InvalidName(room.to_string()));
+        }
+        if self.rooms.contains_key(room) {
+            return Err(RoomError::AlreadyExists(room.to_string()));
+        }
+        self.rooms.insert(room.to_string(), Room::default());
+        self.join(room, creator).map(|_| ())
+    }
+
+    /// Adds `name` to the members of an existing room, returning its topic
+    pub fn join(&mut self, room: &str, name: &str) -> Result<Option<String>> {
+        let entry = self.rooms.get_mut(room).ok_or_else(|| RoomError::NoSuchRoom(room.to_string()))?;
+        if !entry.members.insert(name.to_string()) {
+            return Err(RoomError::AlreadyMember(room.to_string()));
+        }
+        self.memberships.entry(name.to_string()).or_default().insert(room.to_string());
+        Ok(entry.topic.clone())
+    }
+
+    /// Removes `name` from the members of a room
+    pub fn leave(&mut self, room: &str, name: &str) -> Result<()> {
+        let entry = self.rooms.get_mut(room).ok_or_else(|| RoomError::NoSuchRoom(room.to_string()))?;
+        if !entry.members.remove(name) {
+            return Err(RoomError::NotAMember(room.to_string()));
+        }
+        if let Some(rooms) = self.memberships.get_mut(name) {
+            rooms.remove(room);
+            if rooms.is_empty() {
+                self.memberships.remove(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `name` from every room, e.g. when they disconnect.
+    /// Returns the rooms they were a member of.
+    pub fn leave_all(&mut self, name: &str) -> Vec<String> {
+        let rooms: Vec<String> = self.memberships.remove(name).unwrap_or_default().into_iter().collect();
+        for room in &rooms {
+            if let Some(entry) = self.rooms.get_mut(room) {
+                entry.members.remove(name);
+            }
+        }
+        rooms
+    }
+
+    /// Moves the memberships of `old` to `new`, e.g. when a user changes their name.
+    /// Returns the rooms they are a member of.
+    pub fn rename(&mut self, old: &str, new: &str) -> Vec<String> {
+        let rooms = self.leave_all(old);
+        for room in &rooms {
+            if let Some(entry) = self.rooms.get_mut(room) {
+                entry.members.insert(new.to_string());
+            }
+        }
+        if !rooms.is_empty() {
+            self.memberships.insert(new.to_string(), rooms.iter().cloned().collect());
+        }
+        rooms
+    }
+
+    /// Sets the topic of a room; only its members may do so. Returns the new topic,
+    /// which is None if it was cleared.
+    pub fn set_topic(&mut self, room: &str, name: &str, topic: Option<String>) -> Result<Option<String>> {
+        let entry = self.rooms.get_mut(room).ok_or_else(|| RoomError::NoSuchRoom(room.to_string()))?;
+        if !entry.members.contains(name) {
+            return Err(RoomError::NotAMember(room.to_string()));
+        }
+        entry.topic = topic.filter(|topic| !topic.trim().is_empty());
+        Ok(entry.topic.clone())
+    }
+
+    /// Returns the members of a room, sorted by name
+    pub fn members(&self, room: &str) -> Result<Vec<String>> {
+        self.rooms
+            .get(room)
+            .map(|entry| entry.members.iter().cloned().collect())
+            .ok_or_else(|| RoomError::NoSuchRoom(room.to_string()))
+    }
+
+    /// Returns true if `name` is a member of the room
+    pub fn is_member(&self, room: &str, name: &str) -> bool {
+        self.rooms.get(room).is_some_and(|entry| entry.members.contains(name))
+    }
+
+    /// Returns the rooms `name` is a member of, sorted by name
+    pub fn rooms_of(&self, name: &str) -> Vec<String> {
+        self.memberships
+            .get(name)
+            .map(|rooms| rooms.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Describes every room, sorted by name
+    pub fn list(&self) -> Vec<RoomInfo> {
+        self.rooms
+            .iter()
+            .map(|(name, entry)| RoomInfo {
+                name: name.clone(),
+                topic: entry.topic.clone(),
+                members: entry.members.len(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_names_need_the_prefix() {
+        assert!(is_valid_room_name("#general"));
+        assert!(is_valid_room_name("#rust-lang_2024"));
+        assert!(!is_valid_room_name("general"));
+        assert!(!is_valid_room_name("#"));
+        assert!(!is_valid_room_name("#two words"));
+        assert!(!is_valid_room_name("#a:b"));
+        assert!(!is_valid_room_name(&format!("#{}", "a".repeat(MAX_ROOM_NAME_LEN))));
+    }
+
+    #[test]
+    fn create_join_and_leave() {
+        let mut rooms = Rooms::new();
+        rooms.create("#general", "alice").unwrap();
+        assert_eq!(rooms.create("#general", "bob"), Err(RoomError::AlreadyExists("#general".into())));
+        assert_eq!(rooms.join("#nowhere", "bob"), Err(RoomError::NoSuchRoom("#nowhere".into())));
+
+        rooms.set_topic("#general", "alice", Some("Say hi".into())).unwrap();
+        assert_eq!(rooms.join("#general", "bob"), Ok(Some("Say hi".into())));
+        assert_eq!(rooms.join("#general", "bob"), Err(RoomError::AlreadyMember("#general".into())));
+        assert_eq!(rooms.members("#general").unwrap(), vec!["alice", "bob"]);
+        assert!(rooms.is_member("#general", "bob"));
+
+        rooms.leave("#general", "bob").unwrap();
+        assert_eq!(rooms.leave("#general", "bob"), Err(RoomError::NotAMember("#general".into())));
+        assert_eq!(rooms.set_topic("#general", "bob", None), Err(RoomError::NotAMember("#general".into())));
+        assert!(rooms.rooms_of("bob").is_empty());
+
+        // The room is kept once empty
+        rooms.leave("#general", "alice").unwrap();
+        assert_eq!(rooms.list(), vec![RoomInfo { name: "#general".into(), topic: Some("Say hi".into()), members: 0 }]);
+    }
+
+    #[test]
+    fn disconnecting_leaves_every_room() {
+        let mut rooms = Rooms::new();
+        rooms.create("#a", "alice").unwrap();
+        rooms.create("#b", "bob").unwrap();
+        rooms.join("#b", "alice").unwrap();
+
+        assert_eq!(rooms.rooms_of("alice"), vec!["#a", "#b"]);
+        assert_eq!(rooms.leave_all("alice"), vec!["#a", "#b"]);
+        assert!(rooms.rooms_of("alice").is_empty());
+        assert!(rooms.members("#a").unwrap().is_empty());
+        assert_eq!(rooms.members("#b").unwrap(), vec!["bob"]);
+        assert!(rooms.leave_all("alice").is_empty());
+    }
+
+    #[test]
+    fn renaming_keeps_the_memberships() {
+        let mut rooms = Rooms::new();
+        rooms.create("#a", "alice").unwrap();
+        rooms.create("#b", "bob").unwrap();
+        rooms.join("#b", "alice").unwrap();
+
+        assert_eq!(rooms.rename("alice", "alicia"), vec!["#a", "#b"]);
+        assert_eq!(rooms.rooms_of("alicia"), vec!["#a", "#b"]);
+        assert!(rooms.rooms_of("alice").is_empty());
+        assert_eq!(rooms.members("#b").unwrap(), vec!["alicia", "bob"]);
+        assert!(rooms.rename("carol", "caroline").is_empty());
+    }
+}
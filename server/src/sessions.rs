@@ -0,0 +1,164 @@
+/*
+    Resumable sessions
+
+    Every login is handed a random resume token. When the connection of a user drops,
+    their session is suspended for a while, remembering the rooms they were in and the
+    last message routed before they left. A client coming back with the token in time
+    resumes the session: it rejoins its rooms and is sent the messages it missed.
+    Logging in again under the same name, or the time running out, ends the suspended session.
+*/
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use protocol::{name_key, ResumeToken};
+
+/// What is kept of a session while its user is away
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suspended {
+    /// Rooms the user was a member of
+    pub rooms: Vec<String>,
+    /// Id of the last message routed before the connection dropped
+    pub last_message_id: u64,
+    /// For a guest, the id of the last message routed before they logged in (see `Viewer::direct_after`)
+    pub direct_after: Option<u64>,
+}
+
+struct Session {
+    token: ResumeToken,
+    /// Set while the user is away, with the time the session was suspended
+    suspended: Option<(Suspended, Instant)>,
+}
+
+pub struct Sessions {
+    ttl: Duration,
+    sessions: HashMap<String, Session>,
+}
+
+impl Sessions {
+    /// Creates the session registry; suspended sessions can be resumed for `ttl`
+    pub fn new(ttl: Duration) -> Sessions {
+        Sessions {
+            ttl,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Starts a new session for a user that just logged in (or resumed), returning its resume token.
+    /// Any previous session of the name is forgotten.
+    pub fn start(&mut self, name: &str) -> ResumeToken {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let token = ResumeToken(bytes.iter().map(|byte| format!("{:02x}", byte)).collect());
+        self.sessions.insert(name.to_string(), Session { token: token.clone(), suspended: None });
+        token
+    }
+
+    /// Moves the session of a user who changed their name, so that its token resumes it under the new name.
+    /// Any previous session of the new name is forgotten, see `is_suspended`.
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(session) = self.sessions.remove(old) {
+            self.sessions.insert(new.to_string(), session);
+        }
+    }
+
+    /// Returns true if a user named `name`, spelled in any case or Unicode form, may still resume their session
+    pub fn is_suspended(&mut self, name: &str) -> bool {
+        self.expire();
+        let key = name_key(name);
+        self.sessions.iter().any(|(other, session)| session.suspended.is_some() && name_key(other) == key)
+    }
+
+    /// Suspends the session of a user whose connection dropped
+    pub fn suspend(&mut self, name: &str, suspended: Suspended) {
+        self.expire();
+        if let Some(session) = self.sessions.get_mut(name) {
+            session.suspended = Some((suspended, Instant::now()));
+        }
+    }
+
+    /// Takes the suspended session of `name`, if `token` is its resume token and it has not expired
+    pub fn resume(&mut self, name: &str, token: &ResumeToken) -> Option<Suspended> {
+        self.expire();
+        match self.sessions.get_mut(name) {
+            Some(session) if session.token == *token => session.suspended.take().map(|(suspended, _)| suspended),
+            _ => None,
+        }
+    }
+
+    /// Forgets the suspended sessions that can no longer be resumed
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.sessions.retain(|_, session| {
+            session.suspended.as_ref().is_none_or(|(_, since)| since.elapsed() < ttl)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suspended(rooms: &[&str], last_message_id: u64) -> Suspended {
+        Suspended {
+            rooms: rooms.iter().map(|room| room.to_string()).collect(),
+            last_message_id,
+            direct_after: None,
+        }
+    }
+
+    #[test]
+    fn sessions_resume_with_their_token_only() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.start("alice");
+        let other = sessions.start("bob");
+        assert_ne!(token, other);
+
+        // Nothing to resume while the user is online
+        assert_eq!(sessions.resume("alice", &token), None);
+
+        sessions.suspend("alice", suspended(&["#rust"], 7));
+        assert_eq!(sessions.resume("alice", &other), None);
+        assert_eq!(sessions.resume("bob", &token), None);
+        assert_eq!(sessions.resume("alice", &token), Some(suspended(&["#rust"], 7)));
+        // A session is only resumed once
+        assert_eq!(sessions.resume("alice", &token), None);
+    }
+
+    #[test]
+    fn logging_in_again_or_waiting_too_long_ends_the_session() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.start("alice");
+        sessions.suspend("alice", suspended(&[], 1));
+        sessions.start("alice");
+        assert_eq!(sessions.resume("alice", &token), None);
+
+        let mut sessions = Sessions::new(Duration::ZERO);
+        let token = sessions.start("alice");
+        sessions.suspend("alice", suspended(&[], 1));
+        assert_eq!(sessions.resume("alice", &token), None);
+    }
+
+    #[test]
+    fn suspended_sessions_are_found_under_any_spelling() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        sessions.start("alice");
+        assert!(!sessions.is_suspended("alice"));
+        sessions.suspend("alice", suspended(&[], 1));
+        assert!(sessions.is_suspended("ALICE"));
+        assert!(!sessions.is_suspended("bob"));
+    }
+
+    #[test]
+    fn sessions_follow_renames() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.start("alice");
+        sessions.rename("alice", "alicia");
+        sessions.suspend("alicia", suspended(&[], 1));
+        assert_eq!(sessions.resume("alice", &token), None);
+        assert_eq!(sessions.resume("alicia", &token), Some(suspended(&[], 1)));
+    }
+}
@@ -0,0 +1,363 @@
+/*
+    Persistent message history
+
+    Every message routed by the broker is recorded through the `MessageStore` trait,
+    so that the history survives server restarts. Two backends are available:
+      - `FileStore`: an append-only file with one JSON record per line
+      - `SqliteStore`: an embedded SQLite database
+    The backend is picked with `--store file:PATH` or `--store sqlite:PATH`, or the `store` setting (see `config.rs`).
+    The server hands the store to a thread of its own through `History`, so that reading and writing
+    the history never holds up the routing of messages.
+*/
+
+use std::{fmt, path::PathBuf, str::FromStr, sync::mpsc, thread};
+
+use futures::channel::oneshot;
+use log::error;
+use protocol::HistoryMessage;
+use serde::{Deserialize, Serialize};
+
+mod file;
+mod sqlite;
+
+pub use file::FileStore;
+pub use sqlite::SqliteStore;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A routed message as recorded in the history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// Unique id assigned by the broker, increasing with every message
+    pub id: u64,
+    /// Server time the message was routed at, in milliseconds since the Unix epoch
+    pub timestamp: i64,
+    pub from: String,
+    pub to: Vec<String>,
+    pub msg: String,
+}
+
+impl From<StoredMessage> for HistoryMessage {
+    fn from(message: StoredMessage) -> Self {
+        HistoryMessage {
+            id: message.id,
+            timestamp: message.timestamp,
+            from: message.from,
+            to: message.to,
+            msg: message.msg,
+        }
+    }
+}
+
+/// Filters for searching the history. Every filter that is set must match.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    /// Only messages routed at or after this time (milliseconds since the Unix epoch)
+    pub since: Option<i64>,
+    /// Only messages routed before this time (milliseconds since the Unix epoch)
+    pub until: Option<i64>,
+    /// Only messages sent by or addressed to this user
+    pub user: Option<String>,
+    /// Only messages containing this text
+    pub text: Option<String>,
+    /// Only messages a client is allowed to see
+    pub viewer: Option<Viewer>,
+    /// Only messages with an id lower than this one
+    pub before: Option<u64>,
+    /// Only messages with an id higher than this one
+    pub after: Option<u64>,
+    /// Only the most recent `limit` matching messages
+    pub limit: Option<usize>,
+}
+
+/// The client on whose behalf the history is searched
+#[derive(Debug, Clone)]
+pub struct Viewer {
+    /// The viewer sees the messages they sent and the messages addressed to them...
+    pub name: String,
+    /// ...and the messages addressed to any of these recipients (e.g. `BROADCAST`)
+    pub channels: Vec<String>,
+    /// If set, only the conversation with this recipient: a user (messages exchanged
+    /// between them and the viewer) or a channel (messages addressed to it)
+    pub with: Option<String>,
+    /// If set, the messages the viewer sent or received with an id up to this one are hidden, except in
+    /// their channels: a guest does not see those of whoever used their name before
+    pub direct_after: Option<u64>,
+}
+
+impl Viewer {
+    /// Returns true if the viewer is allowed to see the message, and it is part of the selected conversation
+    pub fn sees(&self, message: &StoredMessage) -> bool {
+        let sent = message.from == self.name;
+        let addressed_to = |name: &str| message.to.iter().any(|to| to == name);
+        let own = self.direct_after.is_none_or(|after| message.id > after);
+        let visible =
+            (own && (sent || addressed_to(&self.name))) || self.channels.iter().any(|channel| addressed_to(channel));
+
+        visible
+            && self.with.as_ref().is_none_or(|with| {
+                if self.channels.contains(with) {
+                    addressed_to(with)
+                } else {
+                    (sent && addressed_to(with)) || (message.from == *with && addressed_to(&self.name))
+                }
+            })
+    }
+}
+
+impl HistoryQuery {
+    /// Returns true if the message passes every filter except `limit`
+    pub fn matches(&self, message: &StoredMessage) -> bool {
+        self.since.is_none_or(|since| message.timestamp >= since)
+            && self.until.is_none_or(|until| message.timestamp < until)
+            && self.user.as_ref().is_none_or(|user| {
+                message.from == *user || message.to.iter().any(|to| to == user)
+            })
+            && self.text.as_ref().is_none_or(|text| message.msg.contains(text.as_str()))
+            && self.viewer.as_ref().is_none_or(|viewer| viewer.sees(message))
+            && self.before.is_none_or(|before| message.id < before)
+            && self.after.is_none_or(|after| message.id > after)
+    }
+}
+
+/// Storage backend for the message history
+pub trait MessageStore: Send {
+    /// Records a message at the end of the history
+    fn append(&mut self, message: &StoredMessage) -> Result<()>;
+
+    /// Returns the messages matching the query, oldest first
+    fn query(&self, query: &HistoryQuery) -> Result<Vec<StoredMessage>>;
+
+    /// Returns the id of the last recorded message, or 0 if the history is empty
+    fn last_id(&self) -> Result<u64>;
+
+    /// Makes sure every recorded message reached the disk, e.g. before the server exits
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// A request to the thread that owns the store
+enum Request {
+    Append(StoredMessage),
+    Query(HistoryQuery, oneshot::Sender<Result<Vec<StoredMessage>>>),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+/// Handle on a store owned by a thread of its own. The requests are served in order,
+/// so a query sees every message appended before it.
+#[derive(Clone)]
+pub struct History {
+    requests: mpsc::Sender<Request>,
+}
+
+impl History {
+    /// Moves the store to its own thread, which stops once every handle is dropped
+    pub fn spawn(mut store: Box<dyn MessageStore>) -> std::io::Result<History> {
+        let (requests, receiver) = mpsc::channel();
+        thread::Builder::new().name("history".to_string()).spawn(move || {
+            for request in receiver {
+                match request {
+                    Request::Append(message) => {
+                        if let Err(e) = store.append(&message) {
+                            error!("Failed to record message {}: {}", message.id, e);
+                        }
+                    }
+                    Request::Query(query, answer) => {
+                        let _ = answer.send(store.query(&query));
+                    }
+                    Request::Flush(answer) => {
+                        let _ = answer.send(store.flush());
+                    }
+                }
+            }
+        })?;
+        Ok(History { requests })
+    }
+
+    /// Records a message at the end of the history, without waiting for it to be written
+    pub fn append(&self, message: StoredMessage) {
+        let _ = self.requests.send(Request::Append(message));
+    }
+
+    /// Returns the messages matching the query, oldest first
+    pub async fn query(&self, query: HistoryQuery) -> Result<Vec<StoredMessage>> {
+        let (answer, answered) = oneshot::channel();
+        self.requests.send(Request::Query(query, answer)).map_err(|_| "the history is closed")?;
+        answered.await.map_err(|_| "the history is closed")?
+    }
+
+    /// Makes sure every recorded message reached the disk
+    pub async fn flush(&self) -> Result<()> {
+        let (answer, answered) = oneshot::channel();
+        self.requests.send(Request::Flush(answer)).map_err(|_| "the history is closed")?;
+        answered.await.map_err(|_| "the history is closed")?
+    }
+}
+
+/// Which backend to open and where, written `file:PATH` or `sqlite:PATH`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum StoreSpec {
+    File(PathBuf),
+    Sqlite(PathBuf),
+}
+
+impl StoreSpec {
+    /// Opens (or creates) the described store
+    pub fn open(&self) -> Result<Box<dyn MessageStore>> {
+        Ok(match self {
+            StoreSpec::File(path) => Box::new(FileStore::open(path)?),
+            StoreSpec::Sqlite(path) => Box::new(SqliteStore::open(path)?),
+        })
+    }
+}
+
+impl FromStr for StoreSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(StoreSpec::File(path.into())),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(StoreSpec::Sqlite(path.into())),
+            _ => Err(format!("expected file:PATH or sqlite:PATH, got '{}'", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for StoreSpec {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<StoreSpec> for String {
+    fn from(spec: StoreSpec) -> String {
+        spec.to_string()
+    }
+}
+
+impl fmt::Display for StoreSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreSpec::File(path) => write!(f, "file:{}", path.display()),
+            StoreSpec::Sqlite(path) => write!(f, "sqlite:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, timestamp: i64, from: &str, to: &[&str], msg: &str) -> StoredMessage {
+        StoredMessage {
+            id,
+            timestamp,
+            from: from.to_string(),
+            to: to.iter().map(|to| to.to_string()).collect(),
+            msg: msg.to_string(),
+        }
+    }
+
+    fn ids(messages: Vec<StoredMessage>) -> Vec<u64> {
+        messages.into_iter().map(|message| message.id).collect()
+    }
+
+    /// Runs the same checks against every backend
+    fn check_backend(spec: StoreSpec) {
+        {
+            let mut store = spec.open().unwrap();
+            assert_eq!(store.last_id().unwrap(), 0);
+            store.append(&message(1, 1_000, "alice", &["bob"], "hello: bob")).unwrap();
+            store.append(&message(2, 2_000, "bob", &["alice", "carol"], "hi all")).unwrap();
+            store.append(&message(3, 3_000, "carol", &["*"], "HELLO everyone")).unwrap();
+        }
+
+        // The history survives reopening the store
+        let store = spec.open().unwrap();
+        assert_eq!(store.last_id().unwrap(), 3);
+
+        let all = store.query(&HistoryQuery::default()).unwrap();
+        assert_eq!(all[1], message(2, 2_000, "bob", &["alice", "carol"], "hi all"));
+        assert_eq!(ids(all), vec![1, 2, 3]);
+
+        let by_user = HistoryQuery { user: Some("carol".into()), ..Default::default() };
+        assert_eq!(ids(store.query(&by_user).unwrap()), vec![2, 3]);
+
+        let by_text = HistoryQuery { text: Some("hello".into()), ..Default::default() };
+        assert_eq!(ids(store.query(&by_text).unwrap()), vec![1]);
+
+        let by_date = HistoryQuery { since: Some(2_000), until: Some(3_000), ..Default::default() };
+        assert_eq!(ids(store.query(&by_date).unwrap()), vec![2]);
+
+        let latest = HistoryQuery { limit: Some(2), ..Default::default() };
+        assert_eq!(ids(store.query(&latest).unwrap()), vec![2, 3]);
+
+        let page = HistoryQuery { before: Some(3), limit: Some(1), ..Default::default() };
+        assert_eq!(ids(store.query(&page).unwrap()), vec![2]);
+
+        let missed = HistoryQuery { after: Some(1), ..Default::default() };
+        assert_eq!(ids(store.query(&missed).unwrap()), vec![2, 3]);
+
+        // What alice can see, and her conversations
+        let viewer = |with: Option<&str>| Viewer {
+            name: "alice".into(),
+            channels: vec!["*".into()],
+            with: with.map(String::from),
+            direct_after: None,
+        };
+        let seen_by = |viewer: Viewer| HistoryQuery { viewer: Some(viewer), ..Default::default() };
+        assert_eq!(ids(store.query(&seen_by(viewer(None))).unwrap()), vec![1, 2, 3]);
+        assert_eq!(ids(store.query(&seen_by(viewer(Some("bob")))).unwrap()), vec![1, 2]);
+        assert_eq!(ids(store.query(&seen_by(viewer(Some("carol")))).unwrap()), Vec::<u64>::new());
+        assert_eq!(ids(store.query(&seen_by(viewer(Some("*")))).unwrap()), vec![3]);
+
+        // Carol only received the broadcast and bob's message
+        let carol = Viewer { name: "carol".into(), channels: Vec::new(), with: None, direct_after: None };
+        assert_eq!(ids(store.query(&seen_by(carol.clone())).unwrap()), vec![2, 3]);
+
+        // A guest named carol since message 2 does not see what was sent to carol before
+        let guest = Viewer { direct_after: Some(2), channels: vec!["*".into()], ..carol };
+        assert_eq!(ids(store.query(&seen_by(guest)).unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        check_backend(StoreSpec::File(dir.path().join("history.jsonl")));
+    }
+
+    #[test]
+    fn file_store_survives_damaged_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let record = |id| serde_json::to_string(&message(id, 1_000, "alice", &["bob"], "hi")).unwrap();
+        // A malformed record in the middle, and the last one cut short by a crash
+        let damaged = format!("{}\nnot json\n{}\n{}", record(1), record(2), &record(3)[..20]);
+        std::fs::write(&path, damaged).unwrap();
+
+        let spec = StoreSpec::File(path.clone());
+        {
+            let mut store = spec.open().unwrap();
+            assert_eq!(store.last_id().unwrap(), 2);
+            store.append(&message(3, 3_000, "bob", &["alice"], "again")).unwrap();
+        }
+        let store = spec.open().unwrap();
+        assert_eq!(ids(store.query(&HistoryQuery::default()).unwrap()), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn sqlite_store() {
+        let dir = tempfile::tempdir().unwrap();
+        check_backend(StoreSpec::Sqlite(dir.path().join("history.db")));
+    }
+
+    #[test]
+    fn store_spec_parsing() {
+        assert_eq!("file:a.jsonl".parse(), Ok(StoreSpec::File("a.jsonl".into())));
+        assert_eq!("sqlite:/tmp/a.db".parse(), Ok(StoreSpec::Sqlite("/tmp/a.db".into())));
+        assert!("redis:localhost".parse::<StoreSpec>().is_err());
+        assert!("file:".parse::<StoreSpec>().is_err());
+    }
+}
@@ -0,0 +1,84 @@
+/*
+    Append-only file backend: one JSON encoded `StoredMessage` per line
+
+    The file is read once when it is opened, and the history is then kept in memory.
+    A malformed record is skipped with a warning rather than keeping the server from starting,
+    and a last record cut short (e.g. by a crash while it was written) is cut off the file.
+*/
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+};
+
+use log::warn;
+
+use super::{HistoryQuery, MessageStore, Result, StoredMessage};
+
+pub struct FileStore {
+    file: File,
+    /// Every record of the file, oldest first
+    messages: Vec<StoredMessage>,
+}
+
+impl FileStore {
+    /// Opens the history file, creating it if it does not exist
+    pub fn open(path: &Path) -> Result<FileStore> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let mut messages = Vec::new();
+        let mut start = 0;
+        while start < contents.len() {
+            let end = contents[start..].iter().position(|byte| *byte == b'\n').map(|at| start + at + 1);
+            let line = &contents[start..end.unwrap_or(contents.len())];
+            match serde_json::from_slice(line) {
+                Ok(message) => {
+                    messages.push(message);
+                    if end.is_none() {
+                        // A whole record missing its line end, which the next one must not be glued to
+                        file.write_all(b"\n")?;
+                    }
+                }
+                Err(_) if line.iter().all(u8::is_ascii_whitespace) => (),
+                Err(e) if end.is_none() => {
+                    // The next record is written in its place
+                    warn!("Dropping the unfinished last record of {}: {}", path.display(), e);
+                    file.set_len(start as u64)?;
+                    break;
+                }
+                Err(e) => warn!("Skipping a malformed record of {} at byte {}: {}", path.display(), start, e),
+            }
+            start = end.unwrap_or(contents.len());
+        }
+        Ok(FileStore { file, messages })
+    }
+}
+
+impl MessageStore for FileStore {
+    fn append(&mut self, message: &StoredMessage) -> Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        // A single write keeps records whole, even if another process reads the file meanwhile
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        self.messages.push(message.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+
+    fn query(&self, query: &HistoryQuery) -> Result<Vec<StoredMessage>> {
+        let matching: Vec<&StoredMessage> = self.messages.iter().filter(|message| query.matches(message)).collect();
+        let skipped = query.limit.map_or(0, |limit| matching.len().saturating_sub(limit));
+        Ok(matching[skipped..].iter().map(|message| (*message).clone()).collect())
+    }
+
+    fn last_id(&self) -> Result<u64> {
+        Ok(self.messages.last().map_or(0, |message| message.id))
+    }
+}
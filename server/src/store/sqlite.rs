@@ -0,0 +1,160 @@
+/*
+    Embedded SQLite backend. Recipients are stored as a JSON array next to each message.
+*/
+
+use std::path::Path;
+
+use rusqlite::{params, params_from_iter, types::Value, Connection};
+
+use super::{HistoryQuery, MessageStore, Result, StoredMessage};
+
+/// Filter matching the messages whose recipients include the bound parameter
+const ADDRESSED_TO: &str = "EXISTS (SELECT 1 FROM json_each(recipients) WHERE value = ?)";
+
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Opens the database, creating it and its schema if needed
+    pub fn open(path: &Path) -> Result<SqliteStore> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                 id         INTEGER PRIMARY KEY,
+                 timestamp  INTEGER NOT NULL,
+                 sender     TEXT NOT NULL,
+                 recipients TEXT NOT NULL,
+                 body       TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);
+             CREATE INDEX IF NOT EXISTS messages_sender ON messages (sender);",
+        )?;
+        Ok(SqliteStore { conn })
+    }
+}
+
+impl MessageStore for SqliteStore {
+    fn append(&mut self, message: &StoredMessage) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO messages (id, timestamp, sender, recipients, body) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                message.id as i64,
+                message.timestamp,
+                message.from,
+                serde_json::to_string(&message.to)?,
+                message.msg,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn query(&self, query: &HistoryQuery) -> Result<Vec<StoredMessage>> {
+        let mut filters = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        if let Some(since) = query.since {
+            filters.push("timestamp >= ?".to_string());
+            values.push(since.into());
+        }
+        if let Some(until) = query.until {
+            filters.push("timestamp < ?".to_string());
+            values.push(until.into());
+        }
+        if let Some(user) = &query.user {
+            filters.push(format!("(sender = ? OR {})", ADDRESSED_TO));
+            values.push(user.clone().into());
+            values.push(user.clone().into());
+        }
+        if let Some(text) = &query.text {
+            filters.push("instr(body, ?) > 0".to_string());
+            values.push(text.clone().into());
+        }
+        if let Some(viewer) = &query.viewer {
+            // Sent by the viewer or addressed to them (after `direct_after`), or addressed to one of their channels
+            let channels = vec!["?"; viewer.channels.len()];
+            filters.push(format!(
+                "(((sender = ? OR {}) AND id > ?) OR EXISTS (SELECT 1 FROM json_each(recipients) WHERE value IN ({})))",
+                ADDRESSED_TO,
+                channels.join(", ")
+            ));
+            values.push(viewer.name.clone().into());
+            values.push(viewer.name.clone().into());
+            values.push((viewer.direct_after.unwrap_or(0) as i64).into());
+            values.extend(viewer.channels.iter().map(|channel| Value::from(channel.clone())));
+
+            match &viewer.with {
+                Some(with) if viewer.channels.contains(with) => {
+                    filters.push(ADDRESSED_TO.to_string());
+                    values.push(with.clone().into());
+                }
+                Some(with) => {
+                    // Messages exchanged between the viewer and another user
+                    filters.push(format!("((sender = ? AND {0}) OR (sender = ? AND {0}))", ADDRESSED_TO));
+                    values.push(viewer.name.clone().into());
+                    values.push(with.clone().into());
+                    values.push(with.clone().into());
+                    values.push(viewer.name.clone().into());
+                }
+                None => (),
+            }
+        }
+        if let Some(before) = query.before {
+            filters.push("id < ?".to_string());
+            values.push((before as i64).into());
+        }
+        if let Some(after) = query.after {
+            filters.push("id > ?".to_string());
+            values.push((after as i64).into());
+        }
+
+        let mut sql = String::from("SELECT id, timestamp, sender, recipients, body FROM messages");
+        if !filters.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&filters.join(" AND "));
+        }
+        // Take the most recent messages, then put them back in chronological order
+        sql.push_str(" ORDER BY id DESC");
+        if let Some(limit) = query.limit {
+            sql.push_str(" LIMIT ?");
+            values.push((limit as i64).into());
+        }
+
+        let mut statement = self.conn.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            let (id, timestamp, from, to, msg) = row?;
+            messages.push(StoredMessage {
+                id: id as u64,
+                timestamp,
+                from,
+                to: serde_json::from_str(&to)?,
+                msg,
+            });
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn last_id(&self) -> Result<u64> {
+        let id: i64 = self
+            .conn
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM messages", [], |row| row.get(0))?;
+        Ok(id as u64)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Every message is committed as it is appended
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+// Runs the server binary and talks to it like a client, for the tests that blast it
+
+#![allow(dead_code)]
+
+use std::{
+    net::TcpListener,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use async_std::{future, net::TcpStream, task};
+use futures::{
+    stream::{BoxStream, Fuse},
+    StreamExt,
+};
+use protocol::{ClientFrame, Password, ServerFrame, PROTOCOL_VERSION};
+
+/// A server listening on a free port, killed when dropped
+pub struct Server {
+    process: Child,
+    pub port: u16,
+    args: Vec<String>,
+    dir: tempfile::TempDir,
+}
+
+impl Server {
+    pub fn start(args: &[&str]) -> Server {
+        let dir = tempfile::tempdir().unwrap();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let process = Server::command(&dir, &["--port", &port.to_string()]).args(&args).spawn().unwrap();
+        Server { process, port, args, dir }
+    }
+
+    /// Gives a role to a registered user, restarting the server around it like an admin would
+    pub fn set_role(&mut self, name: &str, role: &str) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let status = Server::command(&self.dir, &["role", name, role]).stdout(Stdio::null()).status().unwrap();
+        assert!(status.success(), "{} could not be made a {}", name, role);
+        self.process = Server::command(&self.dir, &["--port", &self.port.to_string()]).args(&self.args).spawn().unwrap();
+    }
+
+    /// The server binary, keeping its files in `dir`
+    fn command(dir: &tempfile::TempDir, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_server"));
+        command
+            .args(["--log-level", "off"])
+            .arg("--store")
+            .arg(format!("file:{}", dir.path().join("history.jsonl").display()))
+            .arg("--accounts")
+            .arg(dir.path().join("accounts.json"))
+            .arg("--bans")
+            .arg(dir.path().join("bans.json"))
+            .args(args);
+        command
+    }
+}
+
+impl Server {
+    /// Registers an account, with a role if `role` is set, as users and admins would have before
+    pub fn add_account(&mut self, name: &str, password: &str, role: Option<&str>) {
+        task::block_on(async {
+            let mut client = Client::log_in_as(self, name, password, true).await;
+            client.send(&ClientFrame::Disconnect).await;
+            client.drain().await;
+        });
+        if let Some(role) = role {
+            self.set_role(name, role);
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// A message to a user or a room
+pub fn message(client_id: u64, to: &str, msg: &str) -> ClientFrame {
+    ClientFrame::Message { client_id, to: vec![to.to_string()], msg: msg.to_string() }
+}
+
+pub struct Client {
+    pub writer: TcpStream,
+    pub frames: Fuse<BoxStream<'static, protocol::Result<ServerFrame>>>,
+    /// As announced by the server
+    pub max_message_size: Option<usize>,
+}
+
+impl Client {
+    /// Connects to the server, waiting for it to start, and logs in as a guest
+    pub async fn log_in(server: &Server, name: &str) -> Client {
+        Client::log_in_with(server, name, &[]).await
+    }
+
+    /// Like `log_in`, announcing optional features
+    pub async fn log_in_with(server: &Server, name: &str, capabilities: &[&str]) -> Client {
+        Client::log_in_by(server, capabilities, ClientFrame::Login { name: name.to_string(), password: None }).await
+    }
+
+    /// Connects to the server and logs in with a password, registering the name first if `register` is set
+    pub async fn log_in_as(server: &Server, name: &str, password: &str, register: bool) -> Client {
+        let password = Password(password.to_string());
+        let frame = match register {
+            true => ClientFrame::Register { name: name.to_string(), password },
+            false => ClientFrame::Login { name: name.to_string(), password: Some(password) },
+        };
+        Client::log_in_by(server, &[], frame).await
+    }
+
+    /// Connects to the server announcing optional features, and logs in with the given frame
+    pub async fn log_in_by(server: &Server, capabilities: &[&str], login: ClientFrame) -> Client {
+        let mut client = Client::connect_with(server, capabilities).await;
+        client.send(&login).await;
+        let logged_in = client.wait_for(|frame| matches!(frame, ServerFrame::LoggedIn { .. })).await;
+        assert!(logged_in.is_some(), "could not log in with {:?}", login);
+        client
+    }
+
+    pub async fn connect(server: &Server) -> Client {
+        Client::connect_with(server, &[]).await
+    }
+
+    /// Connects to the server and shakes hands, announcing optional features
+    pub async fn connect_with(server: &Server, capabilities: &[&str]) -> Client {
+        let stream = loop {
+            match TcpStream::connect(("127.0.0.1", server.port)).await {
+                Ok(stream) => break stream,
+                Err(_) => task::sleep(Duration::from_millis(50)).await,
+            }
+        };
+        let mut client = Client { writer: stream.clone(), frames: protocol::frames(stream).fuse(), max_message_size: None };
+        let capabilities = capabilities.iter().map(|capability| capability.to_string()).collect();
+        client.send(&ClientFrame::Hello { version: PROTOCOL_VERSION, capabilities }).await;
+        match client.next().await {
+            Some(ServerFrame::Welcome { max_message_size, .. }) => client.max_message_size = max_message_size,
+            frame => panic!("expected a welcome, got {:?}", frame),
+        }
+        client
+    }
+
+    pub async fn send(&mut self, frame: &ClientFrame) {
+        // The server may have hung up on us already
+        let _ = protocol::write_frame(&mut self.writer, frame).await;
+    }
+
+    /// The next frame, or None if the connection is closed or quiet for a second
+    pub async fn next(&mut self) -> Option<ServerFrame> {
+        match future::timeout(Duration::from_secs(1), self.frames.next()).await {
+            Ok(Some(Ok(frame))) => Some(frame),
+            _ => None,
+        }
+    }
+
+    /// Skips frames until one matches
+    pub async fn wait_for(&mut self, matching: impl Fn(&ServerFrame) -> bool) -> Option<ServerFrame> {
+        while let Some(frame) = self.next().await {
+            if matching(&frame) {
+                return Some(frame);
+            }
+        }
+        None
+    }
+
+    /// Waits until the server handled every frame sent so far, skipping the frames received meanwhile
+    pub async fn sync(&mut self) {
+        self.send(&ClientFrame::PeerListRequest).await;
+        assert!(self.wait_for(|frame| matches!(frame, ServerFrame::PeerList { .. })).await.is_some());
+    }
+
+    /// Creates or joins a room, waiting until the client is a member
+    pub async fn join(&mut self, room: &str, create: bool) {
+        let room = room.to_string();
+        self.send(&if create { ClientFrame::CreateRoom { room } } else { ClientFrame::JoinRoom { room } }).await;
+        assert!(self.wait_for(|frame| matches!(frame, ServerFrame::RoomJoined { .. })).await.is_some());
+    }
+
+    /// Reads every frame until the connection is closed or quiet
+    pub async fn drain(&mut self) -> Vec<ServerFrame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = self.next().await {
+            frames.push(frame);
+        }
+        frames
+    }
+}
@@ -0,0 +1,129 @@
+// Sends messages between clients, checking that the server numbers and stamps them,
+// and tells their sender when they are recorded and when they reach each recipient
+
+mod common;
+
+use async_std::task;
+use common::{message, Client, Server};
+use protocol::{features, ClientFrame, ServerFrame};
+
+#[test]
+fn senders_are_told_when_messages_are_sent_then_delivered() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::DELIVERY]).await;
+        let mut bob = Client::log_in(&server, "bob").await;
+        alice.drain().await;
+        bob.drain().await;
+
+        alice.send(&message(7, "bob", "hi")).await;
+        let Some(ServerFrame::Sent { client_id: 7, id, timestamp }) = alice.next().await else {
+            panic!("the message was not acknowledged");
+        };
+        let received = bob.next().await;
+        assert!(
+            matches!(&received, Some(ServerFrame::Message { id: i, timestamp: t, msg, .. }) if *i == id && *t == timestamp && msg == "hi"),
+            "{:?}",
+            received
+        );
+        let delivered = alice.next().await;
+        assert!(
+            matches!(&delivered, Some(ServerFrame::Delivered { id: i, recipient }) if *i == id && recipient == "bob"),
+            "{:?}",
+            delivered
+        );
+
+        // Ids keep increasing
+        alice.send(&message(8, "bob", "again")).await;
+        let next = alice.next().await;
+        assert!(matches!(next, Some(ServerFrame::Sent { client_id: 8, id: next, .. }) if next > id), "{:?}", next);
+    });
+}
+
+#[test]
+fn messages_kept_for_offline_users_are_delivered_when_they_log_in() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut bob = Client::log_in(&server, "bob").await;
+        bob.send(&ClientFrame::Disconnect).await;
+        bob.drain().await;
+
+        let mut alice = Client::log_in_with(&server, "alice", &[features::DELIVERY]).await;
+        alice.drain().await;
+        alice.send(&message(1, "bob", "are you there?")).await;
+        assert!(matches!(alice.next().await, Some(ServerFrame::Sent { .. })));
+        assert!(matches!(alice.next().await, Some(ServerFrame::Queued { .. })));
+
+        let _bob = Client::log_in(&server, "bob").await;
+        let delivered = alice.wait_for(|frame| matches!(frame, ServerFrame::Delivered { .. })).await;
+        assert!(matches!(delivered, Some(ServerFrame::Delivered { recipient, .. }) if recipient == "bob"));
+    });
+}
+
+#[test]
+fn clients_not_asking_for_acknowledgements_do_not_get_them() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in(&server, "alice").await;
+        let mut bob = Client::log_in(&server, "bob").await;
+        alice.drain().await;
+        bob.drain().await;
+
+        alice.send(&message(1, "bob", "hi")).await;
+        assert!(matches!(bob.next().await, Some(ServerFrame::Message { .. })));
+        let answers = alice.drain().await;
+        assert!(answers.is_empty(), "{:?}", answers);
+    });
+}
+
+#[test]
+fn direct_messages_reach_any_spelling_of_the_name() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::DELIVERY]).await;
+        let mut bob = Client::log_in(&server, "bob").await;
+        alice.drain().await;
+        bob.drain().await;
+
+        alice.send(&message(1, "BOB", "hi")).await;
+        assert!(matches!(alice.next().await, Some(ServerFrame::Sent { client_id: 1, .. })));
+        assert!(matches!(bob.next().await, Some(ServerFrame::Message { msg, .. }) if msg == "hi"));
+        let delivered = alice.next().await;
+        assert!(matches!(&delivered, Some(ServerFrame::Delivered { recipient, .. }) if recipient == "bob"), "{:?}", delivered);
+
+        // Nor is the mailbox of an offline user missed
+        bob.send(&ClientFrame::Disconnect).await;
+        bob.drain().await;
+        alice.drain().await;
+        alice.send(&message(2, "Bob", "later")).await;
+        assert!(matches!(alice.next().await, Some(ServerFrame::Sent { client_id: 2, .. })));
+        let queued = alice.next().await;
+        assert!(matches!(&queued, Some(ServerFrame::Queued { client_id: 2, .. })), "{:?}", queued);
+        let mut bob = Client::log_in(&server, "bob").await;
+        assert!(bob.wait_for(|frame| matches!(frame, ServerFrame::Message { msg, .. } if msg == "later")).await.is_some());
+    });
+}
+
+#[test]
+fn messages_nobody_can_receive_are_undeliverable() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::DELIVERY]).await;
+        let mut bob = Client::log_in(&server, "bob").await;
+        bob.join("#rust", true).await;
+        alice.drain().await;
+
+        // Nobody by that name was ever seen, and alice is not in the room
+        for (client_id, to) in [(1, "ghost"), (2, "#rust"), (3, "#nowhere")] {
+            alice.send(&message(client_id, to, "hello?")).await;
+            let answer = alice.wait_for(|frame| matches!(frame, ServerFrame::Undeliverable { .. })).await;
+            assert!(
+                matches!(&answer, Some(ServerFrame::Undeliverable { client_id: c, recipient, .. }) if *c == client_id && recipient == to),
+                "{}: {:?}",
+                to,
+                answer
+            );
+        }
+        assert!(!bob.drain().await.iter().any(|frame| matches!(frame, ServerFrame::Message { .. })));
+    });
+}
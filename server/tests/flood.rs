@@ -0,0 +1,83 @@
+// Blasts a running server with frames, checking that flooders are throttled and then disconnected
+// while the other clients are still served
+
+mod common;
+
+use async_std::task;
+use common::{message, Client, Server};
+use futures::stream::FusedStream;
+use protocol::{ClientFrame, ErrorCode, ServerFrame};
+
+fn errors(frames: &[ServerFrame], code: ErrorCode) -> usize {
+    frames.iter().filter(|frame| matches!(frame, ServerFrame::Error { code: c, .. } if *c == code)).count()
+}
+
+#[test]
+fn flooders_are_throttled_then_disconnected() {
+    let server = Server::start(&["--messages-per-second", "5", "--flood-strikes", "3"]);
+    task::block_on(async {
+        let mut alice = Client::log_in(&server, "alice").await;
+        let mut mallory = Client::log_in(&server, "mallory").await;
+        alice.drain().await;
+
+        for client_id in 0..500 {
+            mallory.send(&message(client_id, "alice", "spam")).await;
+        }
+        let answers = mallory.drain().await;
+        assert_eq!(errors(&answers, ErrorCode::RateLimited), 3);
+        assert_eq!(errors(&answers, ErrorCode::Flooding), 1);
+        assert!(mallory.frames.is_terminated(), "the flooder is still connected");
+
+        // Only a burst of two seconds got through, give or take what trickled in meanwhile, and alice is still served
+        let received = alice.drain().await;
+        let spam = received.iter().filter(|frame| matches!(frame, ServerFrame::Message { .. })).count();
+        assert!((10..=12).contains(&spam), "{} messages got through", spam);
+        assert!(received.iter().any(|frame| matches!(
+            frame,
+            ServerFrame::Notice { msg } if msg == "Client, mallory, was disconnected for flooding"
+        )));
+        alice.send(&ClientFrame::Ping).await;
+        assert!(matches!(alice.next().await, Some(ServerFrame::Pong)));
+    });
+}
+
+#[test]
+fn reconnecting_does_not_refill_the_buckets() {
+    let server = Server::start(&["--messages-per-second", "5", "--flood-strikes", "100"]);
+    task::block_on(async {
+        let _alice = Client::log_in(&server, "alice").await;
+        // The login and 9 messages take the whole burst of the connection, the messages most of the account's
+        let mut mallory = Client::log_in(&server, "mallory").await;
+        for client_id in 0..9 {
+            mallory.send(&message(client_id, "alice", "spam")).await;
+        }
+        // Leaving unread frames would reset the connection, losing the messages
+        mallory.send(&ClientFrame::Disconnect).await;
+        mallory.drain().await;
+
+        // A new connection has a full burst, but the account does not
+        let mut mallory = Client::log_in(&server, "mallory").await;
+        for client_id in 9..14 {
+            mallory.send(&message(client_id, "alice", "spam")).await;
+        }
+        let answer = mallory.wait_for(|frame| matches!(frame, ServerFrame::Error { .. })).await;
+        assert!(matches!(answer, Some(ServerFrame::Error { code: ErrorCode::RateLimited, .. })), "{:?}", answer);
+    });
+}
+
+#[test]
+fn login_floods_are_cut_short() {
+    let server = Server::start(&["--messages-per-second", "5", "--flood-strikes", "2"]);
+    task::block_on(async {
+        let _alice = Client::log_in(&server, "alice").await;
+        let mut mallory = Client::connect(&server).await;
+        for _ in 0..500 {
+            mallory.send(&ClientFrame::Login { name: "alice".to_string(), password: None }).await;
+        }
+        let answers = mallory.drain().await;
+        let rejected = answers.iter().filter(|frame| matches!(frame, ServerFrame::LoginRejected { .. })).count();
+        assert!((10..=12).contains(&rejected), "{} logins were tried", rejected);
+        assert_eq!(errors(&answers, ErrorCode::RateLimited), 2);
+        assert_eq!(errors(&answers, ErrorCode::Flooding), 1);
+    });
+}
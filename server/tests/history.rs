@@ -0,0 +1,46 @@
+// Reads the history of conversations back, checking that guests only see what was said to them
+
+mod common;
+
+use async_std::task;
+use common::{message, Client, Server};
+use protocol::{ClientFrame, ServerFrame};
+
+/// The texts of the conversation with `with`, as the server tells them
+async fn conversation(client: &mut Client, with: &str) -> Vec<String> {
+    client.send(&ClientFrame::HistoryRequest { with: Some(with.to_string()), before: None, limit: 10 }).await;
+    match client.wait_for(|frame| matches!(frame, ServerFrame::History { .. })).await {
+        Some(ServerFrame::History { messages, .. }) => messages.into_iter().map(|message| message.msg).collect(),
+        frame => panic!("expected the history, got {:?}", frame),
+    }
+}
+
+#[test]
+fn guests_do_not_see_the_conversations_of_earlier_guests() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in(&server, "alice").await;
+        let mut bob = Client::log_in_as(&server, "bob", "correct horse", true).await;
+        let mut carol = Client::log_in(&server, "carol").await;
+        alice.send(&message(0, "bob", "hi bob")).await;
+        alice.send(&message(0, "carol", "hi carol")).await;
+        let is_message = |frame: &ServerFrame| matches!(frame, ServerFrame::Message { .. });
+        assert!(bob.wait_for(is_message).await.is_some());
+        assert!(carol.wait_for(is_message).await.is_some());
+        assert_eq!(conversation(&mut carol, "alice").await, vec!["hi carol"]);
+        for client in [&mut bob, &mut carol] {
+            client.send(&ClientFrame::Disconnect).await;
+            client.drain().await;
+        }
+
+        // Someone else takes the name carol
+        let mut carol = Client::log_in(&server, "carol").await;
+        assert!(conversation(&mut carol, "alice").await.is_empty());
+        carol.send(&message(0, "alice", "who are you?")).await;
+        assert_eq!(conversation(&mut carol, "Alice").await, vec!["who are you?"]);
+
+        // Registered users keep theirs
+        let mut bob = Client::log_in_as(&server, "bob", "correct horse", false).await;
+        assert_eq!(conversation(&mut bob, "alice").await, vec!["hi bob"]);
+    });
+}
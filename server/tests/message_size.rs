@@ -0,0 +1,52 @@
+// Sends messages too large for the server, checking that they are refused without ending the connection
+
+mod common;
+
+use async_std::task;
+use common::{message, Client, Server};
+use protocol::{ErrorCode, ServerFrame};
+
+#[test]
+fn oversized_messages_are_refused() {
+    let server = Server::start(&["--max-message-size", "100"]);
+    task::block_on(async {
+        let mut alice = Client::log_in(&server, "alice").await;
+        let mut bob = Client::log_in(&server, "bob").await;
+        assert_eq!(bob.max_message_size, Some(100));
+        alice.drain().await;
+        bob.drain().await;
+
+        // Slightly too long, and much longer than a frame may be: the server does not even read that one
+        for (client_id, len) in [(1, 101), (2, 1_000_000)] {
+            bob.send(&message(client_id, "alice", &"x".repeat(len))).await;
+            let answer = bob.next().await;
+            assert!(
+                matches!(&answer, Some(ServerFrame::Error { code: ErrorCode::MessageTooLarge, msg }) if msg.contains("100 bytes")),
+                "{:?}",
+                answer
+            );
+        }
+
+        // Nothing got through, and bob can go on
+        bob.send(&message(3, "alice", &"x".repeat(100))).await;
+        let received = alice.drain().await;
+        assert!(matches!(&received[..], [ServerFrame::Message { msg, .. }] if msg.len() == 100), "{:?}", received);
+    });
+}
+
+#[test]
+fn messages_of_control_characters_fit_in_a_frame() {
+    let server = Server::start(&["--max-message-size", "2000"]);
+    task::block_on(async {
+        let mut alice = Client::log_in(&server, "alice").await;
+        let mut bob = Client::log_in(&server, "bob").await;
+        alice.drain().await;
+        bob.drain().await;
+
+        // Every byte is escaped as \u0001: six times the limit, past twice the limit and the overhead
+        let text = "\u{1}".repeat(2000);
+        bob.send(&message(1, "alice", &text)).await;
+        let received = alice.drain().await;
+        assert!(matches!(&received[..], [ServerFrame::Message { msg, .. }] if *msg == text), "{:?}", received);
+    });
+}
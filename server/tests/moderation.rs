@@ -0,0 +1,57 @@
+// Moderates users by any spelling of their name, checking that roles apply however the name is typed
+
+mod common;
+
+use async_std::task;
+use common::{message, Client, Server};
+use protocol::{BanTarget, ClientFrame, ErrorCode, ServerFrame};
+
+#[test]
+fn moderators_cannot_act_on_admins_under_another_spelling() {
+    let mut server = Server::start(&[]);
+    server.add_account("admin", "correct horse", Some("admin"));
+    server.add_account("mod", "battery staple", Some("moderator"));
+    task::block_on(async {
+        let mut admin = Client::log_in_as(&server, "admin", "correct horse", false).await;
+        let mut moderator = Client::log_in_as(&server, "mod", "battery staple", false).await;
+        admin.drain().await;
+        moderator.drain().await;
+
+        for name in ["ADMIN", "Admin"] {
+            let target = BanTarget::User(name.to_string());
+            moderator.send(&ClientFrame::Ban { target, duration: None, reason: None }).await;
+            let answer = moderator.next().await;
+            assert!(
+                matches!(answer, Some(ServerFrame::Error { code: ErrorCode::PermissionDenied, .. })),
+                "{}: {:?}",
+                name,
+                answer
+            );
+        }
+        moderator.send(&ClientFrame::Kick { name: "ADMIN".to_string(), reason: None }).await;
+        assert!(matches!(moderator.next().await, Some(ServerFrame::Error { code: ErrorCode::PermissionDenied, .. })));
+        // The admin is still here, and can still log in
+        assert_eq!(admin.next().await, None);
+        admin.send(&ClientFrame::Disconnect).await;
+        admin.drain().await;
+        Client::log_in_as(&server, "admin", "correct horse", false).await;
+    });
+}
+
+#[test]
+fn mutes_apply_to_every_spelling_of_the_name() {
+    let mut server = Server::start(&[]);
+    server.add_account("mod", "battery staple", Some("moderator"));
+    task::block_on(async {
+        let mut moderator = Client::log_in_as(&server, "mod", "battery staple", false).await;
+        let mut mallory = Client::log_in(&server, "mallory").await;
+        moderator.drain().await;
+        mallory.drain().await;
+
+        moderator.send(&ClientFrame::Mute { name: "MALLORY".to_string(), duration: None }).await;
+        assert!(mallory.wait_for(|frame| matches!(frame, ServerFrame::Notice { .. })).await.is_some());
+        mallory.send(&message(1, "mod", "spam")).await;
+        let answer = mallory.next().await;
+        assert!(matches!(answer, Some(ServerFrame::Error { code: ErrorCode::Muted, .. })), "{:?}", answer);
+    });
+}
@@ -0,0 +1,76 @@
+// Logs in with names that break the rules, or that are already taken under another spelling
+
+mod common;
+
+use async_std::task;
+use common::{Client, Server};
+use protocol::{name_key, ClientFrame, ErrorCode, Password, ServerFrame};
+
+fn login(name: &str) -> ClientFrame {
+    ClientFrame::Login { name: name.to_string(), password: None }
+}
+
+#[test]
+fn invalid_names_are_rejected() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut client = Client::connect(&server).await;
+        for name in ["", "**Server", "al:ice", "alice,bob", "al ice", &"a".repeat(33)] {
+            client.send(&login(name)).await;
+            let answer = client.next().await;
+            assert!(
+                matches!(answer, Some(ServerFrame::LoginRejected { code: ErrorCode::InvalidName, .. })),
+                "{:?}: {:?}",
+                name,
+                answer
+            );
+        }
+        // The client may try again
+        client.send(&login("alice")).await;
+        assert!(matches!(client.next().await, Some(ServerFrame::LoggedIn { name, .. }) if name == "alice"));
+    });
+}
+
+#[test]
+fn names_are_unique_regardless_of_case_and_form() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let _zoe = Client::log_in(&server, "Zoë").await;
+        let mut client = Client::connect(&server).await;
+        for name in ["zoë", "ZOE\u{308}"] {
+            client.send(&login(name)).await;
+            let answer = client.next().await;
+            assert!(
+                matches!(&answer, Some(ServerFrame::LoginRejected { code: ErrorCode::NameTaken, suggestion: Some(suggestion), .. }) if name_key(suggestion) == "zoë2"),
+                "{:?}: {:?}",
+                name,
+                answer
+            );
+        }
+        // Names are stored in their composed form
+        client.send(&login("Bjo\u{308}rn")).await;
+        assert!(matches!(client.next().await, Some(ServerFrame::LoggedIn { name, .. }) if name == "Björn"));
+    });
+}
+
+#[test]
+fn registered_names_need_their_password() {
+    let mut server = Server::start(&[]);
+    server.add_account("zed", "correct horse", None);
+    task::block_on(async {
+        let mut client = Client::connect(&server).await;
+        for password in [None, Some("battery staple")] {
+            let password = password.map(|password| Password(password.to_string()));
+            client.send(&ClientFrame::Login { name: "ZED".to_string(), password }).await;
+            let answer = client.next().await;
+            assert!(
+                matches!(answer, Some(ServerFrame::LoginRejected { code: ErrorCode::AuthenticationFailed, .. })),
+                "{:?}",
+                answer
+            );
+        }
+        let password = Some(Password("correct horse".to_string()));
+        client.send(&ClientFrame::Login { name: "ZED".to_string(), password }).await;
+        assert!(matches!(client.next().await, Some(ServerFrame::LoggedIn { name, .. }) if name == "zed"));
+    });
+}
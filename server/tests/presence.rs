@@ -0,0 +1,94 @@
+// Checks that users coming, going and changing their presence are announced to the clients that follow presence
+
+mod common;
+
+use async_std::task;
+use common::{Client, Server};
+use protocol::{features, ClientFrame, ErrorCode, Presence, ServerFrame, MAX_STATUS_LEN};
+
+fn presence(name: &str, presence: Presence, status: Option<&str>) -> ServerFrame {
+    ServerFrame::Presence { name: name.to_string(), presence, status: status.map(str::to_string) }
+}
+
+fn is_presence(frame: &ServerFrame) -> bool {
+    matches!(frame, ServerFrame::Presence { .. })
+}
+
+#[test]
+fn presence_changes_are_announced() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::PRESENCE]).await;
+        let mut carol = Client::log_in(&server, "carol").await;
+        assert_eq!(alice.wait_for(is_presence).await, Some(presence("carol", Presence::Online, None)));
+        let mut bob = Client::log_in_with(&server, "bob", &[features::PRESENCE]).await;
+        assert_eq!(alice.wait_for(is_presence).await, Some(presence("bob", Presence::Online, None)));
+
+        bob.send(&ClientFrame::SetPresence { presence: Presence::Away, status: Some(" at lunch ".to_string()) }).await;
+        assert_eq!(alice.wait_for(is_presence).await, Some(presence("bob", Presence::Away, Some("at lunch"))));
+
+        // Newcomers are told who is not simply online
+        let mut dave = Client::log_in_with(&server, "dave", &[features::PRESENCE]).await;
+        let known: Vec<ServerFrame> = dave.drain().await.into_iter().filter(is_presence).collect();
+        assert_eq!(known, vec![presence("bob", Presence::Away, Some("at lunch"))]);
+        assert_eq!(alice.wait_for(is_presence).await, Some(presence("dave", Presence::Online, None)));
+
+        // A new name is a new user for the others, as they were
+        bob.send(&ClientFrame::Rename { name: "robert".to_string() }).await;
+        assert_eq!(alice.wait_for(is_presence).await, Some(presence("bob", Presence::Offline, None)));
+        assert_eq!(alice.wait_for(is_presence).await, Some(presence("robert", Presence::Away, Some("at lunch"))));
+
+        bob.send(&ClientFrame::Disconnect).await;
+        assert_eq!(alice.wait_for(is_presence).await, Some(presence("robert", Presence::Offline, None)));
+
+        // carol did not ask for any of it
+        assert!(!carol.drain().await.iter().any(is_presence));
+    });
+}
+
+#[test]
+fn invalid_presences_are_refused() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::PRESENCE]).await;
+        let mut bob = Client::log_in_with(&server, "bob", &[features::PRESENCE]).await;
+        alice.drain().await;
+        bob.drain().await;
+
+        let refused = [
+            ClientFrame::SetPresence { presence: Presence::Offline, status: None },
+            ClientFrame::SetPresence { presence: Presence::Busy, status: Some("x".repeat(MAX_STATUS_LEN + 1)) },
+        ];
+        for frame in refused {
+            bob.send(&frame).await;
+            let answer = bob.next().await;
+            assert!(matches!(answer, Some(ServerFrame::Error { code: ErrorCode::InvalidPresence, .. })), "{:?}", answer);
+        }
+        assert_eq!(alice.next().await, None);
+    });
+}
+
+#[test]
+fn muted_users_cannot_set_a_status() {
+    let mut server = Server::start(&[]);
+    server.add_account("mod", "battery staple", Some("moderator"));
+    task::block_on(async {
+        let mut moderator = Client::log_in_as(&server, "mod", "battery staple", false).await;
+        let mut alice = Client::log_in_with(&server, "alice", &[features::PRESENCE]).await;
+        let mut mallory = Client::log_in(&server, "mallory").await;
+        moderator.drain().await;
+        mallory.drain().await;
+        moderator.send(&ClientFrame::Mute { name: "MALLORY".to_string(), duration: None }).await;
+        assert!(mallory.wait_for(|frame| matches!(frame, ServerFrame::Notice { .. })).await.is_some());
+        alice.drain().await;
+
+        mallory.send(&ClientFrame::SetPresence { presence: Presence::Busy, status: Some("buy now".to_string()) }).await;
+        let answer = mallory.next().await;
+        assert!(matches!(answer, Some(ServerFrame::Error { code: ErrorCode::Muted, .. })), "{:?}", answer);
+        assert_eq!(alice.next().await, None);
+
+        // A presence without a text says nothing
+        mallory.send(&ClientFrame::SetPresence { presence: Presence::Busy, status: None }).await;
+        assert_eq!(alice.wait_for(is_presence).await, Some(presence("mallory", Presence::Busy, None)));
+    });
+}
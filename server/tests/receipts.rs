@@ -0,0 +1,124 @@
+// Reads direct messages, checking that their senders are told, unless either side keeps its receipts private
+
+mod common;
+
+use async_std::task;
+use common::{message, Client, Server};
+use protocol::{features, ClientFrame, Password, ServerFrame};
+
+/// Logs alice and bob in, alice sends bob a message, and returns them with its id
+async fn conversation(server: &Server) -> (Client, Client, u64) {
+    let mut alice = Client::log_in_with(server, "alice", &[features::READ_RECEIPTS]).await;
+    let mut bob = Client::log_in_with(server, "bob", &[features::READ_RECEIPTS]).await;
+    alice.drain().await;
+    bob.drain().await;
+
+    alice.send(&message(1, "bob", "hi")).await;
+    let Some(ServerFrame::Message { id, .. }) = bob.next().await else {
+        panic!("bob did not get the message");
+    };
+    (alice, bob, id)
+}
+
+/// Turns the receipts of a client on or off, waiting for the broker to take it in
+async fn share_receipts(client: &mut Client, enabled: bool) {
+    client.send(&ClientFrame::ReadReceipts { enabled }).await;
+    client.sync().await;
+}
+
+fn mark_read(up_to: u64) -> ClientFrame {
+    ClientFrame::MarkRead { with: "alice".to_string(), up_to }
+}
+
+#[test]
+fn senders_are_told_their_messages_were_read() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let (mut alice, mut bob, id) = conversation(&server).await;
+        bob.send(&mark_read(id)).await;
+        let read = alice.next().await;
+        assert!(matches!(&read, Some(ServerFrame::Read { by, up_to }) if by == "bob" && *up_to == id), "{:?}", read);
+
+        // Messages that do not exist yet cannot be read
+        bob.send(&mark_read(id + 100)).await;
+        assert_eq!(alice.next().await, None);
+    });
+}
+
+#[test]
+fn receipts_are_private_if_either_side_wants() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let (mut alice, mut bob, id) = conversation(&server).await;
+        share_receipts(&mut bob, false).await;
+        bob.send(&mark_read(id)).await;
+        assert_eq!(alice.next().await, None);
+
+        // Users who do not share their receipts do not see those of others either
+        share_receipts(&mut bob, true).await;
+        share_receipts(&mut alice, false).await;
+        bob.send(&mark_read(id)).await;
+        assert_eq!(alice.next().await, None);
+
+        share_receipts(&mut alice, true).await;
+        bob.send(&mark_read(id)).await;
+        assert!(matches!(alice.next().await, Some(ServerFrame::Read { .. })));
+    });
+}
+
+#[test]
+fn only_users_in_a_conversation_can_tell_they_read_it() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let (mut alice, mut bob, id) = conversation(&server).await;
+        let mut mallory = Client::log_in_with(&server, "mallory", &[features::READ_RECEIPTS]).await;
+        alice.drain().await;
+        mallory.send(&mark_read(id)).await;
+        assert_eq!(alice.next().await, None);
+
+        // Messages kept while the user was offline start a conversation too
+        bob.send(&ClientFrame::Disconnect).await;
+        bob.drain().await;
+        alice.drain().await;
+        alice.send(&message(2, "bob", "still there?")).await;
+        alice.sync().await;
+        let mut bob = Client::log_in_with(&server, "bob", &[features::READ_RECEIPTS]).await;
+        let kept = bob.wait_for(|frame| matches!(frame, ServerFrame::Message { offline: true, .. })).await;
+        let Some(ServerFrame::Message { id, .. }) = kept else {
+            panic!("bob did not get the kept message");
+        };
+        bob.send(&mark_read(id)).await;
+        let read = alice.wait_for(|frame| matches!(frame, ServerFrame::Read { .. })).await;
+        assert!(matches!(&read, Some(ServerFrame::Read { by, up_to }) if by == "bob" && *up_to == id), "{:?}", read);
+    });
+}
+
+fn bob_login() -> ClientFrame {
+    ClientFrame::Login { name: "bob".to_string(), password: Some(Password("correct horse".to_string())) }
+}
+
+#[test]
+fn conversations_read_from_the_history_get_receipts_too() {
+    let mut server = Server::start(&[]);
+    server.add_account("bob", "correct horse", None);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::READ_RECEIPTS]).await;
+        let mut bob = Client::log_in_by(&server, &[features::READ_RECEIPTS], bob_login()).await;
+        alice.drain().await;
+        bob.drain().await;
+        alice.send(&message(1, "bob", "hi")).await;
+        let received = bob.wait_for(|frame| matches!(frame, ServerFrame::Message { .. })).await;
+        let Some(ServerFrame::Message { id, .. }) = received else {
+            panic!("bob did not get the message");
+        };
+
+        // bob comes back later and reads it from the history
+        bob.send(&ClientFrame::Disconnect).await;
+        bob.drain().await;
+        let mut bob = Client::log_in_by(&server, &[features::READ_RECEIPTS], bob_login()).await;
+        alice.drain().await;
+        bob.send(&mark_read(id)).await;
+        let read = alice.wait_for(|frame| matches!(frame, ServerFrame::Read { .. })).await;
+        assert!(matches!(&read, Some(ServerFrame::Read { by, up_to }) if by == "bob" && *up_to == id), "{:?}", read);
+    });
+}
@@ -0,0 +1,130 @@
+// Changes names without reconnecting, checking who hears about it and that the new name is the only one left
+
+mod common;
+
+use async_std::task;
+use common::{message, Client, Server};
+use protocol::{features, ClientFrame, ErrorCode, Password, ServerFrame};
+
+fn rename(name: &str) -> ClientFrame {
+    ClientFrame::Rename { name: name.to_string() }
+}
+
+fn renamed(from: &str, to: &str) -> ServerFrame {
+    ServerFrame::Renamed { from: from.to_string(), to: to.to_string() }
+}
+
+#[test]
+fn renames_reach_the_rooms_and_conversations_of_the_user() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::RENAME]).await;
+        let mut bob = Client::log_in_with(&server, "bob", &[features::RENAME]).await;
+        let mut carol = Client::log_in(&server, "carol").await;
+        let mut dave = Client::log_in_with(&server, "dave", &[features::RENAME]).await;
+        let mut erin = Client::log_in_with(&server, "erin", &[features::RENAME]).await;
+
+        // bob shares a room with alice, carol too but without knowing about renames, and erin wrote to her
+        alice.join("#rust", true).await;
+        for member in [&mut bob, &mut carol] {
+            member.join("#rust", false).await;
+        }
+        erin.send(&message(1, "alice", "hi")).await;
+        assert!(alice.wait_for(|frame| matches!(frame, ServerFrame::Message { .. })).await.is_some());
+        for client in [&mut bob, &mut carol, &mut dave, &mut erin] {
+            client.drain().await;
+        }
+
+        alice.send(&rename("alicia")).await;
+        assert_eq!(alice.next().await, Some(renamed("alice", "alicia")));
+        assert_eq!(bob.next().await, Some(renamed("alice", "alicia")));
+        assert_eq!(erin.next().await, Some(renamed("alice", "alicia")));
+        assert_eq!(carol.next().await, Some(ServerFrame::Notice { msg: "alice is now known as alicia".to_string() }));
+        assert_eq!(dave.next().await, None);
+
+        // The old name is free, and its mailbox went with her; the new one reaches her, in her rooms too
+        bob.send(&message(1, "alice", "still there?")).await;
+        let answer = bob.wait_for(|frame| matches!(frame, ServerFrame::Undeliverable { .. })).await;
+        assert!(matches!(&answer, Some(ServerFrame::Undeliverable { recipient, .. }) if recipient == "alice"), "{:?}", answer);
+        bob.send(&message(1, "alicia", "hello")).await;
+        let received = alice.next().await;
+        assert!(matches!(received, Some(ServerFrame::Message { from, msg, .. }) if from == "bob" && msg == "hello"));
+        alice.send(&message(1, "#rust", "new name")).await;
+        let received = bob.next().await;
+        assert!(matches!(received, Some(ServerFrame::Message { from, room: Some(_), .. }) if from == "alicia"));
+    });
+}
+
+#[test]
+fn names_in_use_cannot_be_taken() {
+    let mut server = Server::start(&[]);
+    server.add_account("zed", "correct horse", None);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::RENAME]).await;
+        let _bob = Client::log_in(&server, "bob").await;
+        // carol may still resume her session
+        let mut carol = Client::log_in(&server, "carol").await;
+        carol.send(&ClientFrame::Disconnect).await;
+        carol.drain().await;
+        loop {
+            alice.send(&ClientFrame::PeerListRequest).await;
+            match alice.wait_for(|frame| matches!(frame, ServerFrame::PeerList { .. })).await {
+                Some(ServerFrame::PeerList { names }) if names.iter().any(|name| name == "carol") => (),
+                _ => break,
+            }
+        }
+        alice.drain().await;
+
+        let refused = [
+            ("BOB", ErrorCode::NameTaken),
+            ("Carol", ErrorCode::NameTaken),
+            ("zed", ErrorCode::NameTaken),
+            ("Zed", ErrorCode::NameTaken),
+            ("al ice", ErrorCode::InvalidName),
+            ("**Server", ErrorCode::InvalidName),
+        ];
+        for (name, code) in refused {
+            alice.send(&rename(name)).await;
+            let answer = alice.next().await;
+            assert!(matches!(&answer, Some(ServerFrame::Error { code: c, .. }) if *c == code), "{}: {:?}", name, answer);
+        }
+
+        // Her own name in another case is still hers
+        alice.send(&rename("Alice")).await;
+        assert_eq!(alice.next().await, Some(renamed("alice", "Alice")));
+    });
+}
+
+#[test]
+fn accounts_and_roles_follow_their_user() {
+    let mut server = Server::start(&[]);
+    server.add_account("zed", "correct horse", Some("moderator"));
+    task::block_on(async {
+        let password = Some(Password("correct horse".to_string()));
+        let login = ClientFrame::Login { name: "zed".to_string(), password };
+        let mut zed = Client::log_in_by(&server, &[features::RENAME], login).await;
+        let mut mallory = Client::log_in(&server, "mallory").await;
+        zed.drain().await;
+        mallory.drain().await;
+
+        // Asking for the same name again changes nothing, but is answered
+        for (from, to) in [("zed", "ZED"), ("ZED", "ZED"), ("ZED", "zoe")] {
+            zed.send(&rename(to)).await;
+            assert_eq!(zed.next().await, Some(renamed(from, to)));
+        }
+        zed.send(&ClientFrame::Mute { name: "mallory".to_string(), duration: None }).await;
+        mallory.send(&message(1, "zoe", "spam")).await;
+        let answer = mallory.wait_for(|frame| matches!(frame, ServerFrame::Error { .. })).await;
+        assert!(matches!(answer, Some(ServerFrame::Error { code: ErrorCode::Muted, .. })), "{:?}", answer);
+
+        // The password goes with the name, and the old name is free
+        zed.send(&ClientFrame::Disconnect).await;
+        zed.drain().await;
+        let mut guest = Client::connect(&server).await;
+        guest.send(&ClientFrame::Login { name: "zoe".to_string(), password: None }).await;
+        let answer = guest.next().await;
+        assert!(matches!(answer, Some(ServerFrame::LoginRejected { .. })), "{:?}", answer);
+        Client::log_in(&server, "zed").await;
+        Client::log_in_as(&server, "zoe", "correct horse", false).await;
+    });
+}
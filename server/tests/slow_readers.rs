@@ -0,0 +1,95 @@
+// Writes to a client that stops reading until its queue is full, checking that the overflow policy applies
+// to it and to the clients writing to it, while everyone else is still served
+
+mod common;
+
+use async_std::task;
+use common::{message, Client, Server};
+use protocol::{ClientFrame, ServerFrame};
+
+/// Big enough that a few hundred fill the socket buffers of a client that does not read
+const TEXT_LEN: usize = 16 * 1024;
+
+/// Batches of messages sent before giving up on filling the queue
+const MAX_BATCHES: u64 = 1000;
+
+fn server(overflow: &str) -> Server {
+    Server::start(&[
+        "--queue-capacity",
+        "256",
+        "--queue-overflow",
+        overflow,
+        "--messages-per-second",
+        "100000",
+        "--bytes-per-second",
+        "1000000000",
+    ])
+}
+
+/// Sends `count` long messages from `client` to bob, numbered after `first`
+async fn write_to_bob(client: &mut Client, first: u64, count: u64) {
+    for client_id in first..first + count {
+        client.send(&message(client_id, "bob", &"x".repeat(TEXT_LEN))).await;
+    }
+}
+
+#[test]
+fn writers_to_a_stalled_reader_are_held_back_with_the_block_policy() {
+    let server = server("block");
+    task::block_on(async {
+        let mut alice = Client::log_in(&server, "alice").await;
+        let mut bob = Client::log_in(&server, "bob").await;
+        let mut carol = Client::log_in(&server, "carol").await;
+        alice.drain().await;
+        bob.drain().await;
+        carol.drain().await;
+
+        // bob stops reading; alice writes a few messages at a time until the server stops reading her
+        let mut sent = 0;
+        loop {
+            assert!(sent < MAX_BATCHES * 4, "alice was never held back");
+            write_to_bob(&mut alice, sent, 4).await;
+            sent += 4;
+            alice.send(&ClientFrame::PeerListRequest).await;
+            if alice.wait_for(|frame| matches!(frame, ServerFrame::PeerList { .. })).await.is_none() {
+                break;
+            }
+        }
+
+        // The others are still served
+        carol.sync().await;
+
+        // Once bob catches up, alice is read again, and nothing was dropped on the way
+        let (received, answer) = futures::join!(
+            bob.drain(),
+            alice.wait_for(|frame| matches!(frame, ServerFrame::PeerList { .. }))
+        );
+        assert!(answer.is_some(), "alice is still held back");
+        let messages = received.iter().filter(|frame| matches!(frame, ServerFrame::Message { .. })).count();
+        assert_eq!(messages as u64, sent);
+    });
+}
+
+#[test]
+fn stalled_readers_are_disconnected_with_the_disconnect_policy() {
+    let server = server("disconnect");
+    task::block_on(async {
+        let mut alice = Client::log_in(&server, "alice").await;
+        let _bob = Client::log_in(&server, "bob").await;
+        alice.drain().await;
+
+        // alice is never held back, and goes on until bob is gone
+        let mut sent = 0;
+        loop {
+            assert!(sent < MAX_BATCHES * 4, "bob was never disconnected");
+            write_to_bob(&mut alice, sent, 4).await;
+            sent += 4;
+            alice.send(&ClientFrame::PeerListRequest).await;
+            match alice.wait_for(|frame| matches!(frame, ServerFrame::PeerList { .. })).await {
+                Some(ServerFrame::PeerList { names }) if names.iter().any(|name| name == "bob") => (),
+                Some(_) => break,
+                None => panic!("alice was held back"),
+            }
+        }
+    });
+}
@@ -0,0 +1,78 @@
+// Checks that typing notices reach the users and rooms they are meant for, and only clients that want them
+
+mod common;
+
+use async_std::task;
+use common::{Client, Server};
+use protocol::{features, ClientFrame, ServerFrame};
+
+fn typing(to: &[&str], typing: bool) -> ClientFrame {
+    ClientFrame::Typing { to: to.iter().map(|to| to.to_string()).collect(), typing }
+}
+
+#[test]
+fn typing_notices_are_forwarded_to_the_recipients() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::TYPING]).await;
+        let mut bob = Client::log_in_with(&server, "bob", &[features::TYPING]).await;
+        let mut carol = Client::log_in(&server, "carol").await;
+        alice.drain().await;
+        bob.drain().await;
+        carol.drain().await;
+
+        bob.send(&typing(&["alice", "carol"], true)).await;
+        let notice = alice.next().await;
+        assert_eq!(notice, Some(ServerFrame::Typing { from: "bob".to_string(), room: None, typing: true }));
+        bob.send(&typing(&["alice"], false)).await;
+        let notice = alice.next().await;
+        assert_eq!(notice, Some(ServerFrame::Typing { from: "bob".to_string(), room: None, typing: false }));
+        // carol did not ask for them
+        assert_eq!(carol.next().await, None);
+    });
+}
+
+#[test]
+fn typing_notices_follow_the_rules_of_messages() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::TYPING]).await;
+        let mut bob = Client::log_in_with(&server, "bob", &[features::TYPING]).await;
+        alice.drain().await;
+        bob.drain().await;
+
+        // Names are matched regardless of case, and nobody is told about typing to everyone
+        bob.send(&typing(&["*", "ALICE"], true)).await;
+        let notice = alice.next().await;
+        assert_eq!(notice, Some(ServerFrame::Typing { from: "bob".to_string(), room: None, typing: true }));
+        assert_eq!(alice.next().await, None);
+        assert_eq!(bob.next().await, None);
+    });
+}
+
+#[test]
+fn typing_in_a_room_reaches_its_members() {
+    let server = Server::start(&[]);
+    task::block_on(async {
+        let mut alice = Client::log_in_with(&server, "alice", &[features::TYPING]).await;
+        let mut bob = Client::log_in_with(&server, "bob", &[features::TYPING]).await;
+        let mut mallory = Client::log_in_with(&server, "mallory", &[features::TYPING]).await;
+        alice.join("#rust", true).await;
+        bob.join("#rust", false).await;
+        alice.drain().await;
+        bob.drain().await;
+
+        bob.send(&typing(&["#rust"], true)).await;
+        let notice = alice.next().await;
+        assert_eq!(
+            notice,
+            Some(ServerFrame::Typing { from: "bob".to_string(), room: Some("#rust".to_string()), typing: true })
+        );
+        assert_eq!(bob.next().await, None);
+
+        // Only members can type in a room
+        mallory.drain().await;
+        mallory.send(&typing(&["#rust"], true)).await;
+        assert_eq!(alice.next().await, None);
+    });
+}